@@ -0,0 +1,73 @@
+// Times the dense CRF refinement on the dev/test fundus image, for each test mask
+// (cropped to its bounding box like the frontend does).
+use std::time::Instant;
+
+use app_lib::refine_mask;
+
+const TEST_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../dev/test/");
+const ITERATIONS: usize = 10;
+const SPATIAL_WEIGHT: f32 = 0.25;
+const BILATERAL_WEIGHT: f32 = 2.0;
+
+fn mask_bounds(mask: &image::GrayImage) -> Option<(u32, u32, u32, u32)> {
+  let mut bounds: Option<(u32, u32, u32, u32)> = None;
+  for (x, y, pixel) in mask.enumerate_pixels() {
+    if pixel[0] == 0 {
+      continue;
+    }
+    bounds = Some(match bounds {
+      None => (x, y, x, y),
+      Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+    });
+  }
+  bounds
+}
+
+fn main() {
+  let image = image::open(format!("{}image_01.png", TEST_DIR)).expect("missing test image").to_rgb8();
+
+  for mask_name in ["exudates_mask.png", "vessels_mask.png"] {
+    let mask = image::open(format!("{}{}", TEST_DIR, mask_name)).expect("missing test mask");
+    let mask = image::imageops::resize(
+      &mask.to_luma8(),
+      image.width(),
+      image.height(),
+      image::imageops::FilterType::Nearest
+    );
+    let Some((x0, y0, x1, y1)) = mask_bounds(&mask) else {
+      println!("{}: empty mask, skipped", mask_name);
+      continue;
+    };
+    let (width, height) = (x1 - x0 + 1, y1 - y0 + 1);
+    let crop = image::imageops::crop_imm(&image, x0, y0, width, height).to_image();
+    let mask = image::imageops::crop_imm(&mask, x0, y0, width, height).to_image();
+    let colors: Vec<f32> = crop
+      .as_raw()
+      .iter()
+      .map(|&v| v as f32)
+      .collect();
+
+    let start = Instant::now();
+    let foreground = refine_mask(&colors, &mask, SPATIAL_WEIGHT, BILATERAL_WEIGHT, ITERATIONS);
+    let elapsed = start.elapsed();
+
+    let drawn = mask
+      .pixels()
+      .filter(|p| p[0] > 0)
+      .count();
+    let kept = mask
+      .pixels()
+      .zip(foreground.iter())
+      .filter(|(p, &q)| p[0] > 0 && q > 0.5)
+      .count();
+    println!(
+      "{} ({}x{}, {} iterations): {:?}, kept {:.1}% of the drawn pixels",
+      mask_name,
+      width,
+      height,
+      ITERATIONS,
+      elapsed,
+      (kept as f32) * 100.0 / (drawn.max(1) as f32)
+    );
+  }
+}
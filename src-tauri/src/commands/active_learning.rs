@@ -0,0 +1,79 @@
+use std::collections::HashSet;
+use std::sync::{ Arc, Mutex };
+
+use tauri::{ self, ipc::Response, State };
+
+use crate::dl::active_learning::{
+  score_images,
+  ActiveLearningQueue,
+  RankedImage,
+  UncertaintySource,
+};
+use crate::dl::model::ModelError;
+
+fn queue_to_response(queue: &ActiveLearningQueue, limit: Option<usize>) -> Result<Response, ModelError> {
+  let limit = limit.unwrap_or(queue.ranked.len()).min(queue.ranked.len());
+  let queue_json = serde_json
+    ::to_string(&queue.ranked[..limit])
+    .map_err(|e| ModelError::Inference(e.to_string()))?;
+  Ok(Response::new(queue_json))
+}
+
+// Runs every image through the model on a blocking thread, the queue stays readable meanwhile
+async fn score_in_background(
+  images: Vec<String>,
+  source: UncertaintySource,
+  app: tauri::AppHandle
+) -> Result<Vec<RankedImage>, ModelError> {
+  tauri::async_runtime
+    ::spawn_blocking(move || score_images(&images, &source, &app)).await
+    .map_err(|e| ModelError::Inference(e.to_string()))?
+}
+
+#[tauri::command]
+pub async fn rank_images(
+  images: Vec<String>,
+  source: UncertaintySource,
+  annotated: Vec<String>,
+  rerank_every: usize,
+  app: tauri::AppHandle,
+  queue: State<'_, Arc<Mutex<ActiveLearningQueue>>>
+) -> Result<Response, ModelError> {
+  let annotated: HashSet<String> = annotated.into_iter().collect();
+  let remaining: Vec<String> = images
+    .into_iter()
+    .filter(|path| !annotated.contains(path))
+    .collect();
+  let start = std::time::Instant::now();
+  let ranked = score_in_background(remaining, source.clone(), app).await?;
+  println!("Ranked {} images in {:?}", ranked.len(), start.elapsed());
+
+  let mut queue = queue.lock().unwrap();
+  queue.reset(ranked, source, annotated, rerank_every);
+  queue_to_response(&queue, None)
+}
+
+#[tauri::command]
+pub fn get_active_learning_queue(
+  limit: Option<usize>,
+  queue: State<Arc<Mutex<ActiveLearningQueue>>>
+) -> Result<Response, ModelError> {
+  let queue = queue.lock().unwrap();
+  queue_to_response(&queue, limit)
+}
+
+#[tauri::command]
+pub async fn mark_image_annotated(
+  path: String,
+  app: tauri::AppHandle,
+  queue: State<'_, Arc<Mutex<ActiveLearningQueue>>>
+) -> Result<Response, ModelError> {
+  let rerank = queue.lock().unwrap().mark_annotated(&path);
+  if let Some((remaining, source)) = rerank {
+    let ranked = score_in_background(remaining, source, app).await?;
+    queue.lock().unwrap().update_ranking(ranked);
+    println!("Re-ranked active learning queue");
+  }
+  let queue = queue.lock().unwrap();
+  queue_to_response(&queue, None)
+}
@@ -1,8 +1,186 @@
-use image::GenericImageView;
-use imageproc::distance_transform::{ self, Norm };
-use tauri::ipc::Response;
-use skeletonize::{ foreground, thin_image_edges, MarkingMethod };
+use std::sync::{ Arc, Mutex };
 
+use serde::{ Deserialize, Serialize };
+use tauri::{ ipc::Response, State };
+
+use crate::commands::segmentation::layer_colour;
+use crate::dl::feature_extract::FeaturesExtractor;
+use crate::dl::{ model, sam };
+use crate::tools::field_of_view::{ self, FieldOfViewCache };
+use crate::tools::color::rgb_to_lab;
+use crate::tools::dense_crf::{ boundary_prior, skeleton_prior, CrfSettings, DenseCrf };
+
+// Smallest kernel extent accepted, in pixels or colour units
+const MIN_THETA: f32 = 0.1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UnarySource {
+  // Distance to the skeleton of the drawn mask (binary), or to the class borders (multi-class).
+  DistanceTransform,
+  // MedSAM prediction for the box around each mask, on the cached image embedding.
+  // `origin` is the position of the crop in that image.
+  Sam {
+    origin: [usize; 2],
+  },
+  // One map per class, back to back, one byte per pixel (probability * 255).
+  Probabilities {
+    values: Vec<u8>,
+  },
+}
+
+/// Colour features of an RGB or RGBA buffer, 3 values per pixel.
+fn image_colors(
+  image: &[u8],
+  width: usize,
+  height: usize,
+  lab: bool
+) -> Result<Vec<f32>, String> {
+  let pixels = width * height;
+  let channels = if pixels > 0 && image.len() == pixels * 3 {
+    3
+  } else if pixels > 0 && image.len() == pixels * 4 {
+    4
+  } else {
+    return Err("Image buffer does not match the given dimensions".to_string());
+  };
+  Ok(
+    image
+      .chunks(channels)
+      .flat_map(|pixel| {
+        let rgb = [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32];
+        if lab { rgb_to_lab(rgb) } else { rgb }
+      })
+      .collect()
+  )
+}
+
+fn mask_color(mask: &image::RgbaImage) -> [u8; 4] {
+  layer_colour(mask.as_raw()).unwrap_or([0, 0, 0, 0])
+}
+
+fn mask_bbox(mask: &image::GrayImage) -> Option<[f32; 4]> {
+  let mut bbox: Option<[u32; 4]> = None;
+  for (x, y, pixel) in mask.enumerate_pixels() {
+    if pixel[0] == 0 {
+      continue;
+    }
+    bbox = Some(match bbox {
+      None => [x, y, x + 1, y + 1],
+      Some([x0, y0, x1, y1]) => [x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1)],
+    });
+  }
+  bbox.map(|b| b.map(|v| v as f32))
+}
+
+// Active field of view under the crop, which sits at `origin` or at the origin of the SAM unary.
+// Without an origin `image` is the whole image and must be the one the field of view is of.
+fn crop_field_of_view(
+  fov_cache: &State<Arc<Mutex<FieldOfViewCache>>>,
+  image: &[u8],
+  origin: Option<[usize; 2]>,
+  unary: Option<&UnarySource>,
+  width: usize,
+  height: usize
+) -> Option<Vec<bool>> {
+  let origin = origin.or(match unary {
+    Some(UnarySource::Sam { origin }) => Some(*origin),
+    _ => None,
+  });
+  let image_key = match origin {
+    Some(_) => None,
+    None => Some(field_of_view::active_image_key(fov_cache, image, width, height)?),
+  };
+  fov_cache.lock().unwrap().region(image_key, origin, width, height)
+}
+
+/// Foreground probability of each class mask, one map per class.
+fn class_priors(
+  masks: &[image::GrayImage],
+  unary: &UnarySource,
+  width: usize,
+  height: usize,
+  app: &tauri::AppHandle,
+  features_extractor: &State<Arc<Mutex<FeaturesExtractor>>>
+) -> Result<Vec<Vec<f32>>, String> {
+  match unary {
+    UnarySource::DistanceTransform => Ok(masks.iter().map(skeleton_prior).collect()),
+    UnarySource::Sam { origin } => {
+      let decoder = model::get_decoder(app).map_err(|e| e.to_string())?;
+      let features_extractor = features_extractor.lock().unwrap();
+      let region = [origin[0], origin[1], origin[0] + width, origin[1] + height];
+      masks
+        .iter()
+        .map(|mask| {
+          let Some(bbox) = mask_bbox(mask) else {
+            return Ok(vec![0.0; width * height]);
+          };
+          let bbox = [
+            bbox[0] + (origin[0] as f32),
+            bbox[1] + (origin[1] as f32),
+            bbox[2] + (origin[0] as f32),
+            bbox[3] + (origin[1] as f32),
+          ];
+          sam
+            ::decode_box_in_region(&decoder, &features_extractor, bbox, region)
+            .map(|probabilities| probabilities.into_raw_vec_and_offset().0)
+            .map_err(|e| e.to_string())
+        })
+        .collect()
+    }
+    UnarySource::Probabilities { values } => {
+      if values.len() != masks.len() * width * height {
+        return Err("Expected one probability map per mask".to_string());
+      }
+      Ok(
+        values
+          .chunks(width * height)
+          .map(|map|
+            map
+              .iter()
+              .map(|&v| (v as f32) / 255.0)
+              .collect()
+          )
+          .collect()
+      )
+    }
+  }
+}
+
+fn run_crf(
+  colors: &[f32],
+  probabilities: &[f32],
+  labels: usize,
+  width: usize,
+  height: usize,
+  spatial_weight: f32,
+  bilateral_weight: f32,
+  num_iterations: usize,
+  settings: &CrfSettings
+) -> Result<Vec<f32>, String> {
+  // Zero or negative extents would divide the lattice features by zero
+  let thetas = [settings.theta_alpha, settings.theta_beta, settings.theta_gamma];
+  if thetas.iter().any(|theta| !theta.is_finite() || *theta < MIN_THETA) {
+    return Err(format!("CRF kernel extents must be finite and at least {}", MIN_THETA));
+  }
+  let start = std::time::Instant::now();
+  let mut crf = DenseCrf::new(width, height, labels);
+  crf.set_unary_from_probabilities(probabilities);
+  crf.add_pairwise_gaussian(settings.theta_gamma, spatial_weight);
+  crf.add_pairwise_bilateral(
+    colors,
+    3,
+    settings.theta_alpha,
+    settings.theta_beta,
+    bilateral_weight
+  );
+  let q = crf.inference(num_iterations);
+  println!("CRF refinement ({} labels) took: {:?}", labels, start.elapsed());
+  Ok(q)
+}
+
+/// Refines a drawn mask with a dense CRF. The result stays inside the active field of view
+/// when one was detected; `origin` is the position of the crop in that image.
 #[tauri::command]
 pub fn crf_refine(
   image: Vec<u8>,
@@ -10,208 +188,152 @@ pub fn crf_refine(
   width: usize,
   height: usize,
   spatial_weight: f32,
-    bilateral_weight: f32,
-  num_iterations: usize
+  bilateral_weight: f32,
+  num_iterations: usize,
+  settings: Option<CrfSettings>,
+  unary: Option<UnarySource>,
+  origin: Option<[usize; 2]>,
+  app: tauri::AppHandle,
+  features_extractor: State<Arc<Mutex<FeaturesExtractor>>>,
+  fov_cache: State<Arc<Mutex<FieldOfViewCache>>>
 ) -> Result<Response, String> {
-  let mask = image::DynamicImage::ImageRgba8(
-    image::RgbaImage::from_raw(width as u32, height as u32, mask).unwrap()
-  );
-  let mut image = image::DynamicImage
-    ::ImageRgb8(image::RgbImage::from_raw(width as u32, height as u32, image).unwrap())
-    .to_rgba32f();
-
-  image.pixels_mut().for_each(|pixel| {
-    pixel[0] = pixel[0] / 255.0;
-    pixel[1] = pixel[1] / 255.0;
-    pixel[2] = pixel[2] / 255.0;
-  });
+  let settings = settings.unwrap_or_default();
+  let unary = unary.unwrap_or(UnarySource::DistanceTransform);
+  let fov = crop_field_of_view(&fov_cache, &image, origin, Some(&unary), width, height);
+  let mask = image::RgbaImage
+    ::from_raw(width as u32, height as u32, mask)
+    .ok_or("Mask buffer does not match the given dimensions".to_string())?;
+  let colors = image_colors(&image, width, height, settings.lab)?;
 
-  let color = mask
-    .pixels()
-    .collect::<Vec<_>>()
-    .iter()
-    .find_map(|(_, _, pixel)| {
-      if pixel[0] > 0 { Some([pixel[0], pixel[1], pixel[2], pixel[3]]) } else { None }
-    })
-    .unwrap_or([0, 0, 0, 0]);
-
-  let foregound_pixel = image::Rgba(color);
+  let foregound_pixel = image::Rgba(mask_color(&mask));
   let background_pixel = image::Rgba([0, 0, 0, 0]);
 
-  let mut gray_mask = mask.to_luma8();
-  // Binarise mask to thin edges
-
-  // Threshold the mask to get a binary image
-  for pixel in gray_mask.pixels_mut() {
-    if pixel[0] > 0 {
-      pixel[0] = 255;
-    }
-  }
-
-  let mut dynamic_mask = image::DynamicImage::ImageLuma8(gray_mask);
-  thin_image_edges::<foreground::White>(&mut dynamic_mask, MarkingMethod::Modified, None).unwrap();
-
-  let mut gray_buffer = dynamic_mask.to_luma8();
-  distance_transform::distance_transform_mut(&mut gray_buffer, Norm::L1);
-  dynamic_mask = image::DynamicImage::ImageLuma8(gray_buffer);
-
-  let mut maxvalue: f32 = 0.0;
-  // Convert input mask to floating probability [0.0, 1.0].
-
-  let mut q_prob: Vec<f32> = mask
-    .pixels()
-    .map(|p: (u32, u32, image::Rgba<u8>)| {
-      let pixel = p.2;
-      if pixel[0] > 0 {
-        let distance = dynamic_mask.get_pixel(p.0, p.1)[0] as f32;
-        maxvalue = maxvalue.max(distance);
-        distance
-      } else {
-        0.0
-      }
-    })
+  let gray_mask = image::DynamicImage::ImageRgba8(mask.clone()).to_luma8();
+  let priors = class_priors(&[gray_mask], &unary, width, height, &app, &features_extractor)?;
+  let probabilities: Vec<f32> = priors[0]
+    .iter()
+    .flat_map(|&p| [1.0 - p, p])
     .collect();
 
-  // Normalize distance transform
-  for i in 0..q_prob.len() {
-    q_prob[i] = f32::max(1.0 - q_prob[i] / maxvalue, 1e-7);
-  }
+  let q = run_crf(
+    &colors,
+    &probabilities,
+    2,
+    width,
+    height,
+    spatial_weight,
+    bilateral_weight,
+    num_iterations,
+    &settings
+  )?;
 
-  // Precompute unary potentials (negative log-likelihoods)
-  // unary_cost[i][label] = -ln P(label) for pixel i
-  // For binary labels, label = 0 or 1
-  let mut unary_cost_bg: Vec<f32> = Vec::with_capacity(width * height);
-  let mut unary_cost_fg: Vec<f32> = Vec::with_capacity(width * height);
-
-  // A simple scheme: if mask[i] == 1 => cost_bg is high, cost_fg is low; vice versa
-  for prob in q_prob.iter() {
-    unary_cost_fg.push(-prob.ln());
-    unary_cost_bg.push(-(1.0 - prob).ln());
-  }
+  // The refined mask stays within the drawn one and the field of view
+  let output_mask_image = image::RgbaImage::from_fn(width as u32, height as u32, |x, y| {
+    let i = (y as usize) * width + (x as usize);
+    if mask.get_pixel(x, y)[0] == 0 || fov.as_ref().is_some_and(|fov| !fov[i]) {
+      return background_pixel;
+    }
+    if q[i * 2 + 1] > 0.5 {
+      foregound_pixel
+    } else {
+      background_pixel
+    }
+  });
 
-  // Time the CRF refinement
+  Ok(Response::new(output_mask_image.into_vec()))
+}
 
-  let start = std::time::Instant::now();
+/// Joint refinement of several class masks. `masks` holds the RGBA masks back to back;
+/// with the distance transform unary, pixels within `boundary_band` pixels of a class border
+/// can join or leave that class. Returns the refined masks back to back, in the same order,
+/// cut to the active field of view like `crf_refine`.
+#[tauri::command]
+pub fn crf_refine_multiclass(
+  image: Vec<u8>,
+  masks: Vec<u8>,
+  width: usize,
+  height: usize,
+  spatial_weight: f32,
+  bilateral_weight: f32,
+  num_iterations: usize,
+  boundary_band: Option<f32>,
+  settings: Option<CrfSettings>,
+  unary: Option<UnarySource>,
+  origin: Option<[usize; 2]>,
+  app: tauri::AppHandle,
+  features_extractor: State<Arc<Mutex<FeaturesExtractor>>>,
+  fov_cache: State<Arc<Mutex<FieldOfViewCache>>>
+) -> Result<Response, String> {
+  let settings = settings.unwrap_or_default();
+  let fov = crop_field_of_view(&fov_cache, &image, origin, unary.as_ref(), width, height);
+  let mask_size = width * height * 4;
+  if mask_size == 0 || masks.len() % mask_size != 0 {
+    return Err("Mask buffers do not match the given dimensions".to_string());
+  }
+  let colors = image_colors(&image, width, height, settings.lab)?;
 
-  // Mean-field updates
-  for _iter in 0..num_iterations {
-    // Step 1: Compute pairwise message for each pixel
-    // For simplicity, we'll do a small neighborhood (4 neighbors) and a color-based factor.
-    // In practice, you’d do efficient filtering (e.g., permutohedral lattice).
-    let mut message_fg = vec![0.0f32; width * height];
-    let mut message_bg = vec![0.0f32; width * height];
-
-    for y in 0..height {
-      for x in 0..width {
-        if mask.get_pixel(x as u32, y as u32)[0] == 0 {
-          continue;
-        }
-        let i = y * width + x;
-        let pixel = image.get_pixel(x as u32, y as u32);
-        let r = pixel[0];
-        let g = pixel[1];
-        let b = pixel[2];
-
-        // Gather neighbors
-        let neighbors = neighbor_coords(x, y, width, height);
-        for &(nx, ny) in &neighbors {
-          if mask.get_pixel(nx as u32, ny as u32)[0] == 0 {
-            continue;
-          }
-          let j = ny * width + nx;
-          let npixel = image.get_pixel(nx as u32, ny as u32);
-          let nr = npixel[0];
-          let ng = npixel[1];
-          let nb = npixel[2];
-
-          // Color distance
-          let color_dist_sq = (r - nr).powi(2) + (g - ng).powi(2) + (b - nb).powi(2);
-
-          // Pairwise term — smaller if colors are similar
-          let color_similarity = (-color_dist_sq / 2.0).exp();
-
-          let spatial_dist_sq = (((x as i32) - (nx as i32)).pow(2) +
-            ((y as i32) - (ny as i32)).pow(2)) as f32;
-          let spatial_similarity = (-spatial_dist_sq / 2.0).exp();
-          let pairwise_factor =
-            spatial_weight * spatial_similarity + bilateral_weight * color_similarity;
-
-          // Accumulate message
-          // We treat q_prob[j] as the neighbor's probability of being FG
-          message_fg[i] += q_prob[j] * pairwise_factor;
-          message_bg[i] += (1.0 - q_prob[j]) * pairwise_factor;
-        }
-      }
+  let masks: Vec<image::RgbaImage> = masks
+    .chunks(mask_size)
+    .map(|mask| image::RgbaImage::from_raw(width as u32, height as u32, mask.to_vec()).unwrap())
+    .collect();
+  let class_colors: Vec<[u8; 4]> = masks.iter().map(mask_color).collect();
+  let gray_masks: Vec<image::GrayImage> = masks
+    .iter()
+    .map(|mask| image::DynamicImage::ImageRgba8(mask.clone()).to_luma8())
+    .collect();
+  let priors = match unary {
+    None | Some(UnarySource::DistanceTransform) => {
+      let band = boundary_band.unwrap_or(5.0);
+      gray_masks
+        .iter()
+        .map(|mask| boundary_prior(mask, band))
+        .collect()
     }
+    Some(unary) => class_priors(&gray_masks, &unary, width, height, &app, &features_extractor)?,
+  };
 
-    // Step 2: Integrate unary and pairwise into new Q
-    for y in 0..height {
-      for x in 0..width {
-        if mask.get_pixel(x as u32, y as u32)[0] == 0 {
-          continue;
-        }
-        let i = y * width + x;
-        // Negative log-likelihood from unary
-        let cost_fg = unary_cost_fg[i];
-        let cost_bg = unary_cost_bg[i];
-
-        // Pairwise cost: we interpret message_fg/bg as negative log-likelihood increments
-        // In practice, one might transform them or treat them differently,
-        // but here's a simplistic interpretation:
-        let pairwise_cost_fg = -message_fg[i];
-        let pairwise_cost_bg = -message_bg[i];
-
-        // Combined cost
-        let total_cost_fg = cost_fg + pairwise_cost_fg;
-        let total_cost_bg = cost_bg + pairwise_cost_bg;
-
-        // Convert costs into normalized probabilities:
-        // prob = exp(-cost), then normalize
-        let exp_fg = (-total_cost_fg).exp();
-        let exp_bg = (-total_cost_bg).exp();
-        let sum = exp_fg + exp_bg;
-
-        q_prob[i] = exp_fg / sum;
-      }
+  // Label 0 is background, then one label per class
+  let labels = masks.len() + 1;
+  let mut probabilities = vec![0f32; width * height * labels];
+  for (i, pixel) in probabilities.chunks_mut(labels).enumerate() {
+    let mut background = 1.0;
+    for (c, prior) in priors.iter().enumerate() {
+      let p = prior[i].clamp(1e-7, 1.0 - 1e-7);
+      pixel[c + 1] = p;
+      background *= 1.0 - p;
     }
+    pixel[0] = background;
+    let sum: f32 = pixel.iter().sum();
+    pixel.iter_mut().for_each(|p| {
+      *p /= sum;
+    });
   }
 
-  println!("CRF refinement took: {:?}", start.elapsed());
-
-  // 3. Convert refined mask back to blob
-  let output_mask_image: image::DynamicImage = image::DynamicImage::ImageRgba8(
-    image::ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
-      if mask.get_pixel(x, y)[0] == 0 {
-        return background_pixel;
-      }
-      let proba = q_prob[(y as usize) * (width as usize) + (x as usize)];
-      if proba > 0.5 {
-        foregound_pixel
-      } else {
-        background_pixel
-      }
-    })
-  );
-
-  Ok(Response::new(output_mask_image.to_rgba8().into_vec()))
-}
+  let q = run_crf(
+    &colors,
+    &probabilities,
+    labels,
+    width,
+    height,
+    spatial_weight,
+    bilateral_weight,
+    num_iterations,
+    &settings
+  )?;
 
-/// Returns the 4-neighbors (up, down, left, right) of (x, y) if they exist.
-fn neighbor_coords(x: usize, y: usize, width: usize, height: usize) -> Vec<(usize, usize)> {
-  let mut neighbors = Vec::new();
-  for i in -3..3 {
-    for j in -3..3 {
-      if i == 0 && j == 0 {
-        continue;
-      }
-      if (x as i32) + i < 0 || (x as i32) + i >= (width as i32) {
-        continue;
-      }
-      if (y as i32) + j < 0 || (y as i32) + j >= (height as i32) {
-        continue;
-      }
-      neighbors.push((((x as i32) + i) as usize, ((y as i32) + j) as usize));
+  let mut output = vec![0u8; mask_size * masks.len()];
+  for (i, marginals) in q.chunks(labels).enumerate() {
+    let (best, _) = marginals
+      .iter()
+      .enumerate()
+      .max_by(|a, b| a.1.total_cmp(b.1))
+      .unwrap();
+    let outside_fov = fov.as_ref().is_some_and(|fov| !fov[i]);
+    if best > 0 && !outside_fov {
+      let offset = (best - 1) * mask_size + i * 4;
+      output[offset..offset + 4].copy_from_slice(&class_colors[best - 1]);
     }
   }
-  neighbors
+
+  Ok(Response::new(output))
 }
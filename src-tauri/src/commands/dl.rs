@@ -1,12 +1,69 @@
 use std::sync::{ Arc, Mutex };
 
-use tauri::State;
+use tauri::{ Emitter, State };
 use tauri::{ self, ipc::Response };
 use ort::{self};
-use ort::value::Tensor;
+use base64::{ engine::general_purpose::STANDARD, Engine as _ };
+use ndarray::Array2;
+use serde::{ Deserialize, Serialize };
 
-use crate::dl::model::{ get_encoder, get_decoder };
+use crate::dl::model::{
+  get_encoder,
+  get_decoder,
+  get_classifier,
+  model_statuses,
+  session_settings,
+  update_session_settings,
+  ModelError,
+  SessionSettings,
+};
 use crate::dl::feature_extract::FeaturesExtractor;
+use crate::dl::sam;
+use crate::dl::classifier::{ self, ClassifierConfig };
+use crate::connection::types::{ MulticlassConfig, MultilabelConfig };
+use crate::tools::field_of_view::{ self, FieldOfViewCache };
+
+fn ensure_features(
+  features_extractor: &mut FeaturesExtractor,
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  app: &tauri::AppHandle
+) -> Result<(), ModelError> {
+  let image = features_extractor.prepare_image(image, width, height);
+  let encoder = get_encoder(app)?;
+
+  features_extractor
+    .extract_features(image, &encoder)
+    .map_err(|e| ModelError::Inference(e.to_string()))
+}
+
+// Key of the image a SAM command runs on, so a field of view detected on another image is
+// ignored. Extracting features moves the commands to this image and drops that field of view.
+// None when no field of view is active.
+fn field_of_view_key(
+  fov_cache: &State<Arc<Mutex<FieldOfViewCache>>>,
+  image: &[u8],
+  width: usize,
+  height: usize,
+  extract_features: bool
+) -> Option<u64> {
+  let key = field_of_view::active_image_key(fov_cache, image, width, height)?;
+  if extract_features {
+    fov_cache.lock().unwrap().retain_image(key);
+  }
+  Some(key)
+}
+
+// Zeroes the decoder output outside the field of view, `fov` covers the same grid
+fn restrict_to_field_of_view(probabilities: &mut Array2<f32>, fov: &[bool]) {
+  let width = probabilities.ncols();
+  for ((y, x), p) in probabilities.indexed_iter_mut() {
+    if !fov[y * width + x] {
+      *p = 0.0;
+    }
+  }
+}
 
 #[tauri::command]
 pub fn sam_segment(
@@ -19,27 +76,16 @@ pub fn sam_segment(
   max_depth: u32,
   min_size: u32,
   app: tauri::AppHandle,
-  features_extractor: State<Arc<Mutex<FeaturesExtractor>>>
-) -> Result<Response, String> {
-
+  features_extractor: State<Arc<Mutex<FeaturesExtractor>>>,
+  fov_cache: State<Arc<Mutex<FieldOfViewCache>>>
+) -> Result<Response, ModelError> {
+  let fov_key = field_of_view_key(&fov_cache, &image, width, height, extract_features);
   let mut features_extractor = features_extractor.lock().unwrap();
   if extract_features {
-    let image: ort::value::Value<ort::value::TensorValueType<f32>> = features_extractor.prepare_image(
-      image,
-      width as usize,
-      height as usize
-    );
-    let encoder: &ort::session::Session = get_encoder(&app).map_err(|e|
-      format!("Failed to load encoder model: {}", e)
-    )?;
-
-    let _ = features_extractor.extract_features(image, &encoder);
+    ensure_features(&mut features_extractor, image, width, height, &app)?;
   }
 
-  let bbox_and_colors: (
-    ndarray::ArrayBase<ndarray::OwnedRepr<f32>, ndarray::Dim<[usize; 3]>>,
-    [u8; 4],
-  ) = features_extractor.extract_bbox_and_color_from_mask(
+  let (bbox_array, color) = features_extractor.extract_bbox_and_color_from_mask(
     coarse_mask,
     width,
     height,
@@ -47,41 +93,28 @@ pub fn sam_segment(
     min_size
   );
 
-  let bbox_array: ndarray::ArrayBase<
-    ndarray::OwnedRepr<f32>,
-    ndarray::Dim<[usize; 3]>
-  > = bbox_and_colors.0;
-
   if bbox_array.dim().0 == 0 {
     return Ok(Response::new(Vec::new()));
   }
-  let bbox_tensor: ort::value::Value<ort::value::TensorValueType<f32>> = Tensor::from_array(
-    bbox_array.clone()
-  ).unwrap();
-  let color: [u8; 4] = bbox_and_colors.1;
 
   // Load model
-  let decoder: &ort::session::Session = get_decoder(&app).map_err(|e|
-    format!("Failed to load decoder model: {}", e)
+  let decoder = get_decoder(&app)?;
+  let mut candidates = sam::decode_boxes(
+    &decoder,
+    &features_extractor,
+    bbox_array,
+    width,
+    height,
+    false
   )?;
+  if let Some(fov) = fov_cache.lock().unwrap().region(fov_key, None, width, height) {
+    restrict_to_field_of_view(&mut candidates[0].probabilities, &fov);
+  }
+  let probabilities = &candidates[0].probabilities;
 
-  let mut decoder_binding = decoder.create_binding().unwrap();
-  decoder_binding.bind_input("features", features_extractor.get_features()).unwrap();
-  decoder_binding.bind_input("bbox", &bbox_tensor).unwrap();
-
-  decoder_binding.bind_output_to_device("mask", &decoder.allocator().memory_info()).unwrap();
-  decoder_binding.synchronize_inputs().unwrap();
-  println!("Running decoder inference");
-  let mut outputs = decoder_binding.run().map_err(|e| e.to_string())?;
-  let binding = outputs.remove("mask").unwrap();
-  let output = binding
-    .try_extract_tensor::<f32>().map_err(|e| e.to_string())?;
-  
-  // Convert the output tensor directly to a 2D view and create the image buffer in one pass
   let output_mask_image: image::DynamicImage = image::DynamicImage::ImageRgba8(
-    image::ImageBuffer::from_fn(1024, 1024, |x, y| {
-      let value = *output.get([y as usize, x as usize]).unwrap() > threshold;
-      if value {
+    image::ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+      if probabilities[[y as usize, x as usize]] > threshold {
         image::Rgba(color)
       } else {
         image::Rgba([0, 0, 0, 0])
@@ -89,11 +122,509 @@ pub fn sam_segment(
     })
   );
 
-  let output_mask_image = output_mask_image.resize_exact(
-    width as u32,
-    height as u32,
-    image::imageops::FilterType::Nearest
+  Ok(Response::new(output_mask_image.to_rgba8().into_vec()))
+}
+
+#[derive(Serialize)]
+struct SamCandidate {
+  score: Option<f32>,
+  // Base64 of one byte per pixel, probability * 255
+  probabilities: String,
+}
+
+#[derive(Serialize)]
+struct SamPrediction {
+  width: usize,
+  height: usize,
+  color: [u8; 4],
+  // False when multimask was asked for but the decoder does not export hypotheses
+  multimask: bool,
+  candidates: Vec<SamCandidate>,
+}
+
+/// Same as `sam_segment`, but returns the soft decoder output (and every hypothesis the
+/// decoder exports) so the threshold and candidate can be picked afterwards. `multimask` in
+/// the response tells whether the hypotheses were available.
+#[tauri::command]
+pub fn sam_predict(
+  image: Vec<u8>,
+  coarse_mask: Vec<u8>,
+  width: usize,
+  height: usize,
+  extract_features: bool,
+  max_depth: u32,
+  min_size: u32,
+  multimask: bool,
+  app: tauri::AppHandle,
+  features_extractor: State<Arc<Mutex<FeaturesExtractor>>>,
+  fov_cache: State<Arc<Mutex<FieldOfViewCache>>>
+) -> Result<Response, ModelError> {
+  let fov_key = field_of_view_key(&fov_cache, &image, width, height, extract_features);
+  let mut features_extractor = features_extractor.lock().unwrap();
+  if extract_features {
+    ensure_features(&mut features_extractor, image, width, height, &app)?;
+  }
+
+  let (bbox_array, color) = features_extractor.extract_bbox_and_color_from_mask(
+    coarse_mask,
+    width,
+    height,
+    max_depth,
+    min_size
   );
 
-  Ok(Response::new(output_mask_image.to_rgba8().into_vec()))
+  let decoder = get_decoder(&app)?;
+  let multimask = multimask && sam::supports_hypotheses(&decoder);
+  let mut candidates = Vec::new();
+  if bbox_array.dim().0 > 0 {
+    candidates = sam::decode_boxes(
+      &decoder,
+      &features_extractor,
+      bbox_array,
+      width,
+      height,
+      multimask
+    )?;
+  }
+  if let Some(fov) = fov_cache.lock().unwrap().region(fov_key, None, width, height) {
+    for candidate in candidates.iter_mut() {
+      restrict_to_field_of_view(&mut candidate.probabilities, &fov);
+    }
+  }
+
+  let prediction = SamPrediction {
+    width,
+    height,
+    color,
+    multimask,
+    candidates: candidates
+      .iter()
+      .map(|candidate| SamCandidate {
+        score: candidate.score,
+        probabilities: STANDARD.encode(sam::quantise_probabilities(&candidate.probabilities)),
+      })
+      .collect(),
+  };
+  let prediction_json = serde_json
+    ::to_string(&prediction)
+    .map_err(|e| ModelError::Inference(e.to_string()))?;
+  Ok(Response::new(prediction_json))
+}
+
+/// Decodes each quadtree box separately and returns one instance per box. Pixels claimed
+/// by several boxes go to the instance with the highest decoder score.
+#[tauri::command]
+pub fn sam_segment_instances(
+  image: Vec<u8>,
+  coarse_mask: Vec<u8>,
+  threshold: f32,
+  width: usize,
+  height: usize,
+  extract_features: bool,
+  max_depth: u32,
+  min_size: u32,
+  shades: Option<Vec<[u8; 4]>>,
+  app: tauri::AppHandle,
+  features_extractor: State<Arc<Mutex<FeaturesExtractor>>>,
+  fov_cache: State<Arc<Mutex<FieldOfViewCache>>>
+) -> Result<Response, ModelError> {
+  let fov_key = field_of_view_key(&fov_cache, &image, width, height, extract_features);
+  let mut features_extractor = features_extractor.lock().unwrap();
+  if extract_features {
+    ensure_features(&mut features_extractor, image, width, height, &app)?;
+  }
+
+  let (bbox_array, color) = features_extractor.extract_bbox_and_color_from_mask(
+    coarse_mask,
+    width,
+    height,
+    max_depth,
+    min_size
+  );
+
+  let num_boxes = bbox_array.dim().0;
+  if num_boxes > (u16::MAX as usize) {
+    return Err(
+      ModelError::Inference(
+        format!("{} boxes exceed the {} instances one mask can hold", num_boxes, u16::MAX)
+      )
+    );
+  }
+  let fov = fov_cache.lock().unwrap().region(fov_key, None, width, height);
+  let mut instance_map = sam::InstanceMap::new(width, height);
+  let mut scores = Vec::with_capacity(num_boxes);
+  if num_boxes > 0 {
+    let decoder = get_decoder(&app)?;
+    for i in 0..num_boxes {
+      let single_box = bbox_array.slice(ndarray::s![i..i + 1, .., ..]).to_owned();
+      let mut candidates = sam::decode_boxes(
+        &decoder,
+        &features_extractor,
+        single_box,
+        width,
+        height,
+        true
+      )?;
+      if let Some(fov) = &fov {
+        restrict_to_field_of_view(&mut candidates[0].probabilities, fov);
+      }
+      let best = &candidates[0];
+      let score = best.score.unwrap_or_else(||
+        sam::mean_foreground_probability(&best.probabilities, threshold)
+      );
+      instance_map.claim((i + 1) as u16, &best.probabilities, threshold, score);
+      scores.push(score);
+    }
+  }
+
+  // Instances are what each box kept after overlaps were resolved
+  let owners: Vec<u32> = instance_map.owners
+    .iter()
+    .map(|&owner| owner as u32)
+    .collect();
+  let result = sam::collect_instances(
+    &owners,
+    width,
+    height,
+    num_boxes,
+    Some(&scores),
+    color,
+    shades.as_deref()
+  );
+  let result_json = serde_json
+    ::to_string(&result)
+    .map_err(|e| ModelError::Inference(e.to_string()))?;
+  Ok(Response::new(result_json))
+}
+
+#[derive(Serialize)]
+struct SamRegion {
+  id: usize,
+  score: f32,
+  stability: f32,
+  // [xmin, ymin, xmax, ymax) in image coordinates
+  bbox: [usize; 4],
+  // Base64 of one byte per pixel of the bbox, 255 inside the region
+  mask: String,
+}
+
+/// Samples box prompts over the whole image and returns the candidate regions the
+/// annotator can accept one by one.
+#[tauri::command]
+pub fn sam_segment_everything(
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  extract_features: bool,
+  settings: Option<sam::AutoMaskSettings>,
+  app: tauri::AppHandle,
+  features_extractor: State<Arc<Mutex<FeaturesExtractor>>>,
+  fov_cache: State<Arc<Mutex<FieldOfViewCache>>>
+) -> Result<Response, ModelError> {
+  let fov_key = field_of_view_key(&fov_cache, &image, width, height, extract_features);
+  // The grid takes many decoder runs, the other SAM commands can use the extractor meanwhile
+  let embedding = {
+    let mut features_extractor = features_extractor.lock().unwrap();
+    if extract_features {
+      ensure_features(&mut features_extractor, image, width, height, &app)?;
+    }
+    features_extractor.detached()?
+  };
+  let settings = settings.unwrap_or_default();
+
+  let decoder = get_decoder(&app)?;
+  let start = std::time::Instant::now();
+  let mut masks = sam::generate_masks(&decoder, &embedding, width, height, &settings)?;
+  println!("Automatic mask generation took: {:?}", start.elapsed());
+
+  // Regions are cut to the field of view, those left empty are dropped
+  let fov = fov_key
+    .and_then(|key| fov_cache.lock().unwrap().active(Some(key)))
+    .filter(|fov| fov.width == width && fov.height == height);
+  if let Some(fov) = fov {
+    for mask in masks.iter_mut() {
+      let [x0, y0, x1, y1] = mask.bbox;
+      if let Some(crop) = fov.region([x0, y0], x1 - x0, y1 - y0) {
+        restrict_to_field_of_view(&mut mask.probabilities, &crop);
+      }
+    }
+    masks.retain(|mask| mask.probabilities.iter().any(|&p| p > settings.mask_threshold));
+  }
+
+  let regions: Vec<SamRegion> = masks
+    .iter()
+    .enumerate()
+    .map(|(id, mask)| SamRegion {
+      id,
+      score: mask.score,
+      stability: mask.stability,
+      bbox: mask.bbox,
+      mask: STANDARD.encode(
+        mask.probabilities
+          .iter()
+          .map(|&p| if p > settings.mask_threshold { 255u8 } else { 0u8 })
+          .collect::<Vec<u8>>()
+      ),
+    })
+    .collect();
+
+  let regions_json = serde_json
+    ::to_string(&regions)
+    .map_err(|e| ModelError::Inference(e.to_string()))?;
+  Ok(Response::new(regions_json))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PropagationDirection {
+  Forward,
+  Backward,
+  Both,
+}
+
+#[derive(Serialize, Clone)]
+struct PropagatedFrame {
+  index: usize,
+  path: String,
+  area: usize,
+  // Base64 RGBA PNG, empty once the structure has disappeared
+  mask: String,
+}
+
+// Segments one frame, prompted by the boxes of the mask found on its neighbour.
+fn propagate_to_frame(
+  features_extractor: &mut FeaturesExtractor,
+  encoder: &ort::session::Session,
+  decoder: &ort::session::Session,
+  frame_path: &str,
+  previous: &image::RgbaImage,
+  threshold: f32,
+  max_depth: u32,
+  min_size: u32
+) -> Result<(image::RgbaImage, usize), ModelError> {
+  let frame = image
+    ::open(frame_path)
+    .map_err(|e| ModelError::Inference(format!("Failed to open {}: {}", frame_path, e)))?
+    .to_rgba8();
+  let (width, height) = frame.dimensions();
+  let previous = if previous.dimensions() != (width, height) {
+    image::imageops::resize(previous, width, height, image::imageops::FilterType::Nearest)
+  } else {
+    previous.clone()
+  };
+
+  let tensor = features_extractor.prepare_image(frame.into_raw(), width as usize, height as usize);
+  features_extractor
+    .extract_features(tensor, encoder)
+    .map_err(|e| ModelError::Inference(e.to_string()))?;
+
+  let (bbox_array, color) = features_extractor.extract_bbox_and_color_from_mask(
+    previous.into_raw(),
+    width as usize,
+    height as usize,
+    max_depth,
+    min_size
+  );
+  if bbox_array.dim().0 == 0 {
+    return Ok((image::RgbaImage::new(width, height), 0));
+  }
+
+  let candidates = sam::decode_boxes(
+    decoder,
+    features_extractor,
+    bbox_array,
+    width as usize,
+    height as usize,
+    false
+  )?;
+  let probabilities = &candidates[0].probabilities;
+  let mut area = 0;
+  let mask = image::ImageBuffer::from_fn(width, height, |x, y| {
+    if probabilities[[y as usize, x as usize]] > threshold {
+      area += 1;
+      image::Rgba(color)
+    } else {
+      image::Rgba([0, 0, 0, 0])
+    }
+  });
+  Ok((mask, area))
+}
+
+/// Carries an accepted mask on frame `start_index` to its neighbours, frame after frame,
+/// until the mask vanishes or `max_frames` have been segmented in each direction.
+/// Each frame is also emitted as a `sam_propagation_progress` event as soon as it is ready.
+#[tauri::command]
+pub async fn sam_propagate(
+  frames: Vec<String>,
+  start_index: usize,
+  mask: Vec<u8>,
+  width: usize,
+  height: usize,
+  direction: PropagationDirection,
+  max_frames: usize,
+  threshold: f32,
+  max_depth: u32,
+  min_size: u32,
+  min_area: usize,
+  app: tauri::AppHandle
+) -> Result<Response, ModelError> {
+  if start_index >= frames.len() {
+    return Err(ModelError::Inference("Start frame is out of range".to_string()));
+  }
+  let accepted = image::RgbaImage
+    ::from_raw(width as u32, height as u32, mask)
+    .ok_or(ModelError::Inference("Mask does not match the given dimensions".to_string()))?;
+
+  // Encoding and decoding every frame would otherwise hold up the async runtime
+  let propagated = tauri::async_runtime
+    ::spawn_blocking(move || -> Result<Vec<PropagatedFrame>, ModelError> {
+      let encoder = get_encoder(&app)?;
+      let decoder = get_decoder(&app)?;
+      // Separate extractor so the embedding of the image being edited stays cached
+      let mut features_extractor = FeaturesExtractor::new();
+
+      let last = frames.len() - 1;
+      let mut sequences: Vec<Vec<usize>> = Vec::new();
+      if direction != PropagationDirection::Backward {
+        let end = last.min(start_index.saturating_add(max_frames));
+        sequences.push((start_index + 1..=end).collect());
+      }
+      if direction != PropagationDirection::Forward {
+        sequences.push((start_index.saturating_sub(max_frames)..start_index).rev().collect());
+      }
+
+      let mut propagated = Vec::new();
+      for sequence in sequences {
+        let mut previous = accepted.clone();
+        for index in sequence {
+          let (mask, area) = propagate_to_frame(
+            &mut features_extractor,
+            &encoder,
+            &decoder,
+            &frames[index],
+            &previous,
+            threshold,
+            max_depth,
+            min_size
+          )?;
+          let vanished = area < min_area.max(1);
+
+          let mut png = std::io::Cursor::new(Vec::new());
+          if !vanished {
+            image::DynamicImage
+              ::ImageRgba8(mask.clone())
+              .write_to(&mut png, image::ImageFormat::Png)
+              .map_err(|e| ModelError::Inference(e.to_string()))?;
+          }
+          let frame = PropagatedFrame {
+            index,
+            path: frames[index].clone(),
+            area,
+            mask: STANDARD.encode(png.into_inner()),
+          };
+          println!("Propagated mask to frame {} ({} pixels)", index, area);
+          let _ = app.emit("sam_propagation_progress", frame.clone());
+          propagated.push(frame);
+
+          if vanished {
+            break;
+          }
+          previous = mask;
+        }
+      }
+      propagated.sort_by_key(|frame| frame.index);
+      Ok(propagated)
+    }).await
+    .map_err(|e| ModelError::Inference(e.to_string()))??;
+
+  let propagated_json = serde_json
+    ::to_string(&propagated)
+    .map_err(|e| ModelError::Inference(e.to_string()))?;
+  Ok(Response::new(propagated_json))
+}
+
+/// Thresholds a quantised probability map from `sam_predict` into an RGBA mask.
+#[tauri::command]
+pub fn threshold_probability_map(
+  probabilities: Vec<u8>,
+  threshold: f32,
+  color: [u8; 4],
+  width: usize,
+  height: usize
+) -> Result<Response, String> {
+  if probabilities.len() != width * height {
+    return Err("Probability map does not match the given dimensions".to_string());
+  }
+  let level = (threshold.clamp(0.0, 1.0) * 255.0).round() as u8;
+  let mut output = vec![0u8; width * height * 4];
+  for (i, &p) in probabilities.iter().enumerate() {
+    if p > level {
+      output[4 * i..4 * i + 4].copy_from_slice(&color);
+    }
+  }
+  Ok(Response::new(output))
+}
+
+#[tauri::command]
+pub fn classify_multiclass(
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  classifier: ClassifierConfig,
+  task: MulticlassConfig
+) -> Result<Response, ModelError> {
+  let session = get_classifier(&classifier.model_path)?;
+  let prediction = classifier::predict_multiclass(
+    &session,
+    image,
+    width,
+    height,
+    &classifier,
+    task
+  )?;
+
+  let prediction_json = serde_json
+    ::to_string(&prediction)
+    .map_err(|e| ModelError::Inference(e.to_string()))?;
+  Ok(Response::new(prediction_json))
+}
+
+#[tauri::command]
+pub fn classify_multilabel(
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  classifier: ClassifierConfig,
+  task: MultilabelConfig
+) -> Result<Response, ModelError> {
+  let session = get_classifier(&classifier.model_path)?;
+  let prediction = classifier::predict_multilabel(
+    &session,
+    image,
+    width,
+    height,
+    &classifier,
+    task
+  )?;
+
+  let prediction_json = serde_json
+    ::to_string(&prediction)
+    .map_err(|e| ModelError::Inference(e.to_string()))?;
+  Ok(Response::new(prediction_json))
+}
+
+#[tauri::command]
+pub fn model_status() -> Result<Response, String> {
+  let status_json = serde_json::to_string(&model_statuses()).map_err(|e| e.to_string())?;
+  Ok(Response::new(status_json))
+}
+
+#[tauri::command]
+pub fn get_session_settings() -> SessionSettings {
+  session_settings()
+}
+
+#[tauri::command]
+pub fn set_session_settings(settings: SessionSettings) {
+  update_session_settings(settings);
 }
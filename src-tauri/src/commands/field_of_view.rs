@@ -0,0 +1,85 @@
+use std::sync::{ Arc, Mutex };
+
+use base64::{ engine::general_purpose::STANDARD, Engine as _ };
+use serde::Serialize;
+use tauri::{ self, ipc::Response, State };
+
+use crate::commands::segmentation::paint_layer;
+use crate::tools::field_of_view::{ self, FieldOfViewCache, FovShape };
+
+#[derive(Serialize)]
+struct FieldOfViewResult {
+  width: usize,
+  height: usize,
+  area: usize,
+  // [cx, cy, radius] of the fitted circle
+  circle: Option<[f32; 3]>,
+  // Base64 RGBA mask, in `color`
+  mask: String,
+}
+
+/// Detects the field of view of the image being edited and makes it the active one:
+/// `otsu_segmentation`, `crf_refine` and the SAM commands then keep their output inside it.
+/// It only applies to this image: commands given another whole image, or re-extracting SAM
+/// features for one, ignore it. Results are cached per image, so detecting again after
+/// switching back is free.
+#[tauri::command]
+pub async fn detect_field_of_view(
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  shape: Option<FovShape>,
+  level: Option<u8>,
+  color: Option<[u8; 4]>,
+  fov_cache: State<'_, Arc<Mutex<FieldOfViewCache>>>
+) -> Result<Response, String> {
+  let Some(image_key) = field_of_view::image_key(&image, width, height) else {
+    return Err("Image buffer does not match the given dimensions".to_string());
+  };
+  let shape = shape.unwrap_or(FovShape::Free);
+  let key = (image_key, level, shape);
+
+  // The cache is only locked around lookups, detection runs off the async runtime
+  let cached = fov_cache.lock().unwrap().get(key);
+  let fov = match cached {
+    Some(fov) => {
+      fov_cache.lock().unwrap().activate(image_key, fov.clone());
+      fov
+    }
+    None => {
+      let detected = tauri::async_runtime
+        ::spawn_blocking(move || {
+          let start = std::time::Instant::now();
+          let fov = field_of_view::detect_field_of_view(&image, width, height, level, shape);
+          println!("Field of view detection took: {:?}", start.elapsed());
+          fov
+        }).await
+        .map_err(|e| e.to_string())?;
+      let mut fov_cache = fov_cache.lock().unwrap();
+      let fov = fov_cache.insert(key, detected);
+      fov_cache.activate(image_key, fov.clone());
+      fov
+    }
+  };
+
+  let color = color.unwrap_or([255, 255, 255, 255]);
+  let output = paint_layer(fov.mask.iter().copied(), color);
+  let result = FieldOfViewResult {
+    width,
+    height,
+    area: fov.mask
+      .iter()
+      .filter(|&&inside| inside)
+      .count(),
+    circle: fov.circle,
+    mask: STANDARD.encode(output),
+  };
+  let result_json = serde_json::to_string(&result).map_err(|e| e.to_string())?;
+  Ok(Response::new(result_json))
+}
+
+/// Stops restricting the segmentation commands to the last detected field of view.
+#[tauri::command]
+pub fn clear_field_of_view(fov_cache: State<Arc<Mutex<FieldOfViewCache>>>) {
+  fov_cache.lock().unwrap().clear();
+}
@@ -1,6 +1,6 @@
 use std;
 
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::io::Cursor;
 use image::{ImageBuffer, Rgba};
 
@@ -47,13 +47,13 @@ pub fn load_image_as_base64(filepath: String) -> Result<Response, String> {
 
 
 fn generate_thumbnail(
-    image_path: &PathBuf,
-    thumbnail_path: &PathBuf,
+    image_path: &Path,
+    thumbnail_path: &Path,
     width: u32,
     height: u32,
 ) -> bool {
-    let image_path = image_path.clone();
-    let thumbnail_path = thumbnail_path.clone();
+    let image_path = image_path.to_path_buf();
+    let thumbnail_path = thumbnail_path.to_path_buf();
     
     std::thread::spawn(move || {
         let img = image::open(&image_path).unwrap();
@@ -105,9 +105,9 @@ pub fn convert_rgb_to_blob(rgb: &ArrayView3<u8>) -> Result<Vec<u8>, String> {
         width as u32, 
         height as u32, 
         |x, y| {
-            let r = rgb[[y as usize, x as usize, 0 as usize]];
-            let g = rgb[[y as usize, x as usize, 1 as usize]];
-            let b = rgb[[y as usize, x as usize, 2 as usize]];
+            let r = rgb[[y as usize, x as usize, 0]];
+            let g = rgb[[y as usize, x as usize, 1]];
+            let b = rgb[[y as usize, x as usize, 2]];
             // if 
             Rgba([r, g, b, 255])
         }
@@ -166,7 +166,7 @@ pub fn convert_image_to_rgb_u8_array(image: &DynamicImage) -> Array3<u8> {
     let pixels = rgb_image.into_raw();
 
     Array3::from_shape_vec(
-        (height as usize, width as usize, 3 as usize),
+        (height as usize, width as usize, 3),
         pixels,
     ).unwrap()
 }
\ No newline at end of file
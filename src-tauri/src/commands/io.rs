@@ -58,7 +58,7 @@ pub fn save_xml_file(filepath: String, xml_content: String) -> Result<(), String
     }
     
     // Open the file for writing
-    let mut file = File::create(&path)
+    let mut file = File::create(path)
         .map_err(|e| format!("Failed to create file: {}", e))?;
     
     // Write the XML content to the file
@@ -82,7 +82,7 @@ pub fn save_json_file(filepath: String, json_content: String) -> Result<(), Stri
     println!("Creating file: {:?}", path);
 
     // Open the file for writing
-    let mut file = File::create(&path)
+    let mut file = File::create(path)
         .map_err(|e| format!("Failed to create file: {}", e))?;
     
     // Write the JSON content to the file
@@ -1,5 +1,12 @@
+// Tauri commands take each frontend argument separately
+#![allow(clippy::too_many_arguments)]
+
 pub mod images;
 pub mod segmentation;
 pub mod io;
 pub mod dl;
-pub mod crf;
\ No newline at end of file
+pub mod crf;
+pub mod active_learning;
+pub mod vessels;
+pub mod polygons;
+pub mod field_of_view;
\ No newline at end of file
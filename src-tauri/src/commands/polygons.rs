@@ -0,0 +1,56 @@
+use serde::Serialize;
+use tauri::{ self, ipc::Response };
+
+use crate::commands::segmentation::{ layer_colour, paint_layer, rgba_layer };
+use crate::tools;
+use crate::tools::polygons::Polygon;
+
+#[derive(Serialize)]
+struct Polygons {
+  width: usize,
+  height: usize,
+  color: [u8; 4],
+  polygons: Vec<Polygon>,
+}
+
+/// Traces a class mask (RGBA) into polygons with holes, simplified with Douglas-Peucker at
+/// `tolerance` pixels (default 1, 0 keeps every boundary pixel and rasterises back exactly).
+#[tauri::command]
+pub async fn mask_to_polygons(
+  mask: Vec<u8>,
+  width: usize,
+  height: usize,
+  tolerance: Option<f32>
+) -> Result<Response, String> {
+  let inside = rgba_layer(&mask, width, height)?;
+  let color = layer_colour(&mask).unwrap_or([255, 255, 255, 255]);
+
+  let start = std::time::Instant::now();
+  let polygons = tools::polygons::mask_to_polygons(&inside, width, height, tolerance.unwrap_or(1.0));
+  println!("Traced {} polygons in {:?}", polygons.len(), start.elapsed());
+
+  let result = Polygons { width, height, color, polygons };
+  let result_json = serde_json::to_string(&result).map_err(|e| e.to_string())?;
+  Ok(Response::new(result_json))
+}
+
+/// Rasterises polygons (pixel coordinates, optional holes) into an RGBA mask in `color`.
+#[tauri::command]
+pub async fn polygons_to_mask(
+  polygons: Vec<Polygon>,
+  width: usize,
+  height: usize,
+  color: Option<[u8; 4]>
+) -> Result<Response, String> {
+  let finite = polygons
+    .iter()
+    .flat_map(|polygon| std::iter::once(&polygon.exterior).chain(polygon.holes.iter()))
+    .flatten()
+    .all(|vertex| vertex[0].is_finite() && vertex[1].is_finite());
+  if !finite {
+    return Err("Polygon vertices must be finite".to_string());
+  }
+  let inside = tools::polygons::polygons_to_mask(&polygons, width, height);
+  let color = color.unwrap_or([255, 255, 255, 255]);
+  Ok(Response::new(paint_layer(inside, color)))
+}
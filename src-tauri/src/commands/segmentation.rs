@@ -1,4 +1,4 @@
-use ndarray::{ s, Array2, Array3, Zip };
+use ndarray::{ s, Array2, Array3 };
 use super::images::{
   convert_image_to_luma_u8_array,
   convert_image_to_mask_array,
@@ -6,120 +6,166 @@ use super::images::{
   convert_rgb_to_blob,
   load_blob_to_image,
 };
-use tauri::{ self, ipc::Response };
-use imageproc::morphology::{ open, close, dilate, erode };
+use tauri::{ self, ipc::Response, State };
+use imageproc::morphology::{ dilate, erode };
 use imageproc::distance_transform::Norm;
+use imageproc::gradients::sobel_gradients;
 use image::{ GrayImage, ImageBuffer, Luma, GenericImageView };
 use imageproc::region_labelling::{ connected_components, Connectivity };
-use itertools::Itertools;
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use crate::tools;
+use crate::tools::grabcut::{ grabcut, Trimap };
+use crate::tools::scribbles::{ segment_from_seeds, SeedMethod };
+use crate::tools::watershed;
+use crate::tools::thresholding::{ self, ThresholdMode };
+use crate::tools::clustering::{ self, ClusterMethod };
+use crate::tools::morphology::{ apply_morphology, MaskNorm, MorphologyOp };
+use crate::tools::field_of_view::{ self, FieldOfViewCache };
+use crate::tools::color::{
+  macenko_stains,
+  optical_density,
+  rgb_to_hsv,
+  rgb_to_lab,
+  stain_concentrations,
+  unmixing_matrix,
+  StainMethod,
+  RUIFROK_HE,
+};
+use crate::dl::sam::{ collect_instances, instance_shade };
+use serde::Deserialize;
 use std::collections::HashSet;
+use std::sync::{ Arc, Mutex };
 use rayon::prelude::*; // for .into_par_iter()
 
-fn otsu_level(pixels: &Vec<u8>) -> u8 {
-  // Step 1: Compute histogram
-  let mut histogram = [0u32; 256];
-  for &pixel in pixels {
-    histogram[pixel as usize] += 1;
-  }
-
-  let total_pixels = pixels.len() as f64;
-
-  // Step 2: Compute probabilities
-  let mut probability = [0f64; 256];
-  for i in 0..256 {
-    probability[i] = (histogram[i] as f64) / total_pixels;
-  }
-
-  // Initialize variables
-  let mut max_between_class_variance = 0.0;
-  let mut optimal_threshold = 0u8;
-
-  let mut w0 = 0.0; // Weight for background class
-  let mut sum0 = 0.0; // Cumulative sum for background class
-  let mut total_mean = 0.0;
-
-  // Compute total mean
-  for i in 0..256 {
-    total_mean += (i as f64) * probability[i];
-  }
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Channel {
+  // Same grey conversion as before channels existed
+  Luma,
+  Red,
+  // Best vessel and lesion contrast in fundus images
+  Green,
+  Blue,
+  Hue,
+  Saturation,
+  Value,
+  // CIE Lab lightness, green-red and blue-yellow axes
+  Lightness,
+  LabA,
+  LabB,
+  // Stain concentrations of H&E histology
+  Hematoxylin {
+    #[serde(default)]
+    method: StainMethod,
+  },
+  Eosin {
+    #[serde(default)]
+    method: StainMethod,
+  },
+}
 
-  // Step 3: Iterate over possible thresholds
-  for t in 0..256 {
-    w0 += probability[t];
-    if w0 == 0.0 {
-      continue;
+/// Shared preprocessing of the thresholding and region tools: one 8-bit channel of the image.
+/// Stain concentrations are stretched so their 99th percentile over the image maps to 255.
+pub(crate) fn channel_array(image: &image::DynamicImage, channel: Channel) -> Array2<u8> {
+  let (width, height) = (image.width() as usize, image.height() as usize);
+  let rgb = image.to_rgb8();
+  let colors = rgb.pixels().map(|p| [p[0] as f32, p[1] as f32, p[2] as f32]);
+  let values: Vec<u8> = match channel {
+    Channel::Luma => {
+      return convert_image_to_luma_u8_array(image);
     }
-
-    let w1 = 1.0 - w0;
-    if w1 == 0.0 {
-      break;
+    Channel::Red | Channel::Green | Channel::Blue => {
+      let c = match channel {
+        Channel::Red => 0,
+        Channel::Green => 1,
+        _ => 2,
+      };
+      rgb
+        .pixels()
+        .map(|p| p[c])
+        .collect()
     }
-
-    sum0 += (t as f64) * probability[t];
-    let μ0 = sum0 / w0;
-    let μ1 = (total_mean - sum0) / w1;
-
-    // Between-class variance
-    let between_class_variance = w0 * w1 * (μ0 - μ1) * (μ0 - μ1);
-
-    // Update maximum variance and threshold
-    if between_class_variance > max_between_class_variance {
-      max_between_class_variance = between_class_variance;
-      optimal_threshold = t as u8;
+    Channel::Hue | Channel::Saturation | Channel::Value => {
+      colors
+        .map(|color| {
+          let [h, s, v] = rgb_to_hsv(color);
+          let value = match channel {
+            Channel::Hue => (h / 360.0) * 255.0,
+            Channel::Saturation => s * 255.0,
+            _ => v * 255.0,
+          };
+          value.round().clamp(0.0, 255.0) as u8
+        })
+        .collect()
     }
-  }
-
-  optimal_threshold
+    Channel::Lightness | Channel::LabA | Channel::LabB => {
+      colors
+        .map(|color| {
+          let [l, a, b] = rgb_to_lab(color);
+          let value = match channel {
+            Channel::Lightness => l * 2.55,
+            Channel::LabA => a + 128.0,
+            _ => b + 128.0,
+          };
+          value.round().clamp(0.0, 255.0) as u8
+        })
+        .collect()
+    }
+    Channel::Hematoxylin { method } | Channel::Eosin { method } => {
+      let densities: Vec<[f32; 3]> = colors.map(optical_density).collect();
+      let stains = match method {
+        StainMethod::Ruifrok => RUIFROK_HE,
+        StainMethod::Macenko =>
+          macenko_stains(&densities).unwrap_or_else(|| {
+            println!("Not enough stained pixels for Macenko, using Ruifrok's vectors");
+            RUIFROK_HE
+          }),
+      };
+      let unmixing = unmixing_matrix(stains);
+      let index = if matches!(channel, Channel::Hematoxylin { .. }) { 0 } else { 1 };
+      let concentrations: Vec<f32> = densities
+        .iter()
+        .map(|&od| stain_concentrations(od, &unmixing)[index].max(0.0))
+        .collect();
+      let mut sorted = concentrations.clone();
+      sorted.sort_by(|a, b| a.total_cmp(b));
+      let top = sorted
+        .get(((sorted.len() as f32) * 0.99) as usize)
+        .copied()
+        .unwrap_or(0.0)
+        .max(1e-6);
+      concentrations
+        .iter()
+        .map(|&c| ((c / top) * 255.0).round().clamp(0.0, 255.0) as u8)
+        .collect()
+    }
+  };
+  Array2::from_shape_vec((height, width), values).unwrap()
 }
 
 fn otsu_in_mask(
   image: &Array2<u8>,
   mask: &Array2<bool>,
-  inverse: bool
+  inverse: bool,
+  mode: ThresholdMode,
+  window_size: usize,
+  k: f32,
+  clip_limit: f32
 ) -> Result<Array2<bool>, String> {
   // Ensure the image and mask have the same dimensions
   if image.dim() != mask.dim() {
     return Err("Image and mask dimensions must match".to_string());
   }
 
-  // We will do adaptive thresholding with a window size of size window x window
-
-  // Step 1: compute the average pixel value in the window
-  // Extract the pixel values within the mask
-  let mut masked_pixels = Vec::new();
-  for (&pixel, &is_masked) in image.iter().zip(mask.iter()) {
-    if is_masked {
-      if inverse {
-        masked_pixels.push(255 - pixel);
-      } else {
-        masked_pixels.push(pixel);
-      }
-    }
-  }
-
-  if masked_pixels.is_empty() {
+  if !mask.iter().any(|&is_masked| is_masked) {
     return Err("Masked pixels are empty; cannot compute Otsu threshold".to_string());
   }
 
-  // Compute the Otsu threshold on the masked pixels
-  let threshold = otsu_level(&masked_pixels);
-
-  // Apply the threshold to the entire image to get a binary image
-
-  let thresholded_image = if inverse {
-    image.map(|&pixel| 255 - pixel > threshold)
-  } else {
-    image.map(|&pixel| pixel > threshold)
-  };
-
-  // Compute the logical AND between the thresholded image and the original mask
-  let refined_mask = Zip::from(&thresholded_image)
-    .and(mask)
-    .map_collect(|&thresholded, &original_mask| thresholded && original_mask);
+  // Thresholds look for bright structures, invert to segment dark ones
+  let image = if inverse { image.map(|&pixel| 255 - pixel) } else { image.clone() };
 
-  Ok(refined_mask)
+  // Only pixels within the mask are considered and kept
+  Ok(thresholding::threshold_in_mask(&image, mask, mode, window_size, k, clip_limit))
 }
 
 fn morpho_mask(
@@ -131,36 +177,17 @@ fn morpho_mask(
   let mask_image = mask.map(|&v| if v { 255u8 } else { 0u8 });
   let (height, width) = mask_image.dim();
   let (raw_vec, _) = mask_image.into_raw_vec_and_offset();
-  let mut morphed: GrayImage = GrayImage::from_raw(width as u32, height as u32, raw_vec).unwrap();
+  let morphed: GrayImage = GrayImage::from_raw(width as u32, height as u32, raw_vec).unwrap();
 
+  let mut operations = Vec::new();
   if opening {
-    morphed = open(&morphed, Norm::L1, kernel_size);
-    morphed = close(&morphed, Norm::L1, kernel_size);
+    operations.push(MorphologyOp::Open { norm: MaskNorm::L1, radius: kernel_size });
+    operations.push(MorphologyOp::Close { norm: MaskNorm::L1, radius: kernel_size });
   }
   if enforce_connectedness {
-    let background = Luma([0]);
-    let cc = connected_components(&morphed, Connectivity::Eight, background);
-
-    let kmers = cc.iter().copied().collect::<Vec<u32>>();
-    let nodes: HashMap<u32, usize> = kmers.iter().copied().counts();
-    // Find the largest connected component that is not 0
-    let largest_kmer = kmers
-      .iter()
-      .copied()
-      .filter(|&kmer| kmer != 0)
-      .max_by_key(|&kmer| nodes[&kmer]);
-
-    if let Some(largest_kmer) = largest_kmer {
-      morphed = GrayImage::from_fn(cc.width(), cc.height(), |x, y| {
-        if cc.get_pixel(x, y)[0] == largest_kmer && cc.get_pixel(x, y)[0] != 0 {
-          Luma([255])
-        } else {
-          Luma([0])
-        }
-      });
-    }
-    //
+    operations.push(MorphologyOp::KeepLargest { count: 1 });
   }
+  let morphed = apply_morphology(&morphed, &operations);
 
   // Convert morphed image back to Array2<bool>
   Array2::from_shape_fn((morphed.height() as usize, morphed.width() as usize), |(y, x)| {
@@ -168,6 +195,8 @@ fn morpho_mask(
   })
 }
 
+/// Thresholds the pixels inside `mask`, and inside the active field of view if one was
+/// detected. `origin` is the position of the crop in the image the field of view belongs to.
 #[tauri::command]
 pub async fn otsu_segmentation(
   image: Vec<u8>,
@@ -177,8 +206,21 @@ pub async fn otsu_segmentation(
   kernel_size: u8,
   connectedness: bool,
   width: usize,
-  height: usize
+  height: usize,
+  mode: Option<ThresholdMode>,
+  window_size: Option<usize>,
+  k: Option<f32>,
+  clip_limit: Option<f32>,
+  channel: Option<Channel>,
+  origin: Option<[usize; 2]>,
+  fov_cache: State<'_, Arc<Mutex<FieldOfViewCache>>>
 ) -> Result<Response, String> {
+  // A whole image has to be the one the field of view was detected on
+  let image_key = match origin {
+    Some(_) => None,
+    None => field_of_view::active_image_key(&fov_cache, &image, width, height),
+  };
+
   // 1. Load image and mask
 
   let image = image::DynamicImage::ImageRgba8(
@@ -188,19 +230,26 @@ pub async fn otsu_segmentation(
   let mask = image::DynamicImage::ImageRgba8(
     image::RgbaImage::from_raw(width as u32, height as u32, mask).unwrap()
   );
-  let color = mask
-    .pixels()
-    .collect::<Vec<_>>()
-    .iter()
-    .find_map(|(_, _, pixel)| {
-      if pixel[0] > 0 { Some([pixel[0], pixel[1], pixel[2], pixel[3]]) } else { None }
-    })
-    .unwrap_or([0, 0, 0, 0]);
+  let color = layer_colour(mask.as_bytes()).unwrap_or([0, 0, 0, 0]);
 
-  let image = convert_image_to_luma_u8_array(&image);
+  let image = channel_array(&image, channel.unwrap_or(Channel::Luma));
   let mask = convert_image_to_mask_array(&mask);
+  // The black border around the field of view would drag the threshold down
+  let fov = fov_cache.lock().unwrap().region(image_key, origin, width, height);
+  let mask = match fov {
+    Some(fov) => Array2::from_shape_fn((height, width), |(y, x)| mask[[y, x]] && fov[y * width + x]),
+    None => mask,
+  };
 
-  let mut refined_mask = otsu_in_mask(&image, &mask, inverse)?;
+  let mut refined_mask = otsu_in_mask(
+    &image,
+    &mask,
+    inverse,
+    mode.unwrap_or(ThresholdMode::Global),
+    window_size.unwrap_or(31),
+    k.unwrap_or(0.2),
+    clip_limit.unwrap_or(2.0)
+  )?;
 
   // 2. Perform morphological operation
 
@@ -221,6 +270,85 @@ pub async fn otsu_segmentation(
   Ok(Response::new(output_mask_image.to_rgba8().into_vec()))
 }
 
+/// Splits the pixels inside `mask` into `classes` intensity or colour clusters, numbered from
+/// darkest to brightest. Returns the union of the `keep` clusters in the mask colour, or every
+/// cluster as its own RGBA layer, back to back, when `keep` is not given.
+#[tauri::command]
+pub async fn cluster_segmentation(
+  image: Vec<u8>,
+  mask: Vec<u8>,
+  width: usize,
+  height: usize,
+  method: ClusterMethod,
+  classes: usize,
+  keep: Option<Vec<usize>>,
+  lab: Option<bool>
+) -> Result<Response, String> {
+  if image.len() != width * height * 4 {
+    return Err("Image buffer does not match the given dimensions".to_string());
+  }
+  let inside = rgba_layer(&mask, width, height)?;
+  let color = layer_colour(&mask).unwrap_or([255, 255, 255, 255]);
+
+  let indices: Vec<usize> = (0..width * height).filter(|&i| inside[i]).collect();
+  if indices.is_empty() {
+    return Err("Masked pixels are empty; cannot cluster".to_string());
+  }
+  let colors: Vec<[f32; 3]> = indices
+    .iter()
+    .map(|&i| [image[4 * i] as f32, image[4 * i + 1] as f32, image[4 * i + 2] as f32])
+    .collect();
+
+  let start = std::time::Instant::now();
+  let (labels, count) = clustering::cluster_colors(&colors, method, classes, lab.unwrap_or(false));
+  println!("Clustering into {} classes took: {:?}", count, start.elapsed());
+
+  let output = match keep {
+    Some(keep) => {
+      let mut output = vec![0u8; width * height * 4];
+      for (&i, label) in indices.iter().zip(labels.iter()) {
+        if keep.contains(label) {
+          output[4 * i..4 * i + 4].copy_from_slice(&color);
+        }
+      }
+      output
+    }
+    None => {
+      let mut output = vec![0u8; width * height * 4 * count];
+      for (&i, &label) in indices.iter().zip(labels.iter()) {
+        let offset = label * width * height * 4 + 4 * i;
+        output[offset..offset + 4].copy_from_slice(&instance_shade(color, label, count));
+      }
+      output
+    }
+  };
+  Ok(Response::new(output))
+}
+
+/// Cleans a class mask (RGBA) with a chain of morphology operations, applied in order.
+#[tauri::command]
+pub async fn mask_morphology(
+  mask: Vec<u8>,
+  width: usize,
+  height: usize,
+  operations: Vec<MorphologyOp>
+) -> Result<Response, String> {
+  if width * height == 0 {
+    return Err("Mask is empty".to_string());
+  }
+  let inside = rgba_layer(&mask, width, height)?;
+  let color = layer_colour(&mask).unwrap_or([255, 255, 255, 255]);
+  let mask_image = GrayImage::from_fn(width as u32, height as u32, |x, y| {
+    Luma([if inside[(y as usize) * width + (x as usize)] { 255 } else { 0 }])
+  });
+
+  let start = std::time::Instant::now();
+  let morphed = apply_morphology(&mask_image, &operations);
+  println!("{} morphology operations took: {:?}", operations.len(), start.elapsed());
+
+  Ok(Response::new(paint_layer(morphed.pixels().map(|pixel| pixel[0] > 0), color)))
+}
+
 #[tauri::command]
 pub async fn find_overlapping_region(
   label: Vec<u8>,
@@ -262,7 +390,7 @@ pub async fn find_overlapping_region(
     })
     .reduce(
       // Identity
-      || HashSet::new(),
+      HashSet::new,
       // Reduction
       |mut set_a, set_b| {
         // Combine the sets
@@ -275,7 +403,6 @@ pub async fn find_overlapping_region(
     );
 
   // Now we have a global `overlap_labels` HashSet and a combined bounding box.
-  let overlap_labels = overlap_labels; // HashSet<u32>
   // --- Second pass: For every pixel, check if its connected-component ID is in `overlap_labels` ---
   // Create a 2D array of bool to store the final overlap.
   // Create one big array of false
@@ -311,6 +438,56 @@ pub async fn find_overlapping_region(
   Ok(Response::new(output_mask_image.to_rgba8().into_vec()))
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BooleanOp {
+  Union,
+  Intersection,
+  // First mask minus every other mask
+  Difference,
+  // Pixels covered by an odd number of masks
+  Xor,
+}
+
+/// Combines two or more class masks (RGBA) with a boolean operation. The result is drawn in
+/// `color`, the target class colour, or in the colour of the first mask when none is given.
+#[tauri::command]
+pub async fn mask_boolean(
+  masks: Vec<Vec<u8>>,
+  width: usize,
+  height: usize,
+  operation: BooleanOp,
+  color: Option<[u8; 4]>
+) -> Result<Response, String> {
+  if masks.len() < 2 {
+    return Err("At least two masks are needed".to_string());
+  }
+  let layers = masks
+    .iter()
+    .map(|mask| rgba_layer(mask, width, height))
+    .collect::<Result<Vec<Vec<bool>>, String>>()?;
+  let color = color.unwrap_or_else(|| layer_colour(&masks[0]).unwrap_or([255, 255, 255, 255]));
+
+  let mut combined = Array2::from_elem((height, width), false);
+  combined
+    .axis_chunks_iter_mut(ndarray::Axis(0), 1)
+    .into_par_iter()
+    .enumerate()
+    .for_each(|(y, mut row)| {
+      for (x, elem) in row.iter_mut().enumerate() {
+        let i = y * width + x;
+        let mut covered = layers.iter().map(|layer| layer[i]);
+        *elem = match operation {
+          BooleanOp::Union => covered.any(|inside| inside),
+          BooleanOp::Intersection => covered.all(|inside| inside),
+          BooleanOp::Difference => layers[0][i] && !covered.skip(1).any(|inside| inside),
+          BooleanOp::Xor => covered.filter(|&inside| inside).count() % 2 == 1,
+        };
+      }
+    });
+
+  Ok(Response::new(paint_layer(combined.iter().copied(), color)))
+}
 
 #[tauri::command]
 pub async fn get_overlapping_region_with_mask(
@@ -328,10 +505,8 @@ pub async fn get_overlapping_region_with_mask(
     for i in 0..(width * height) {
         let label_r = label[4 * i + 3]; // 'R' channel if RGBA
         let mask_r = mask[4 * i + 3];
-        if mask_r >0 {
-          if first_nonzero_index.is_none() {
-                first_nonzero_index = Some(i);
-            }
+        if mask_r >0 && first_nonzero_index.is_none() {
+            first_nonzero_index = Some(i);
         }
         if label_r > 0 || mask_r > 0 {
             combined_mask[i] = true;
@@ -383,6 +558,138 @@ pub async fn get_overlapping_region_with_mask(
     Ok(Response::new(output))
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WandReference {
+  // Compare every pixel with the clicked one
+  Seed,
+  // Compare with the running mean of the region grown so far
+  RegionMean,
+}
+
+/// 4-connected flood fill from `seed` over pixels whose feature distance to the reference is
+/// at most `tolerance`, never entering `blocked` pixels. None when more than `max_area`
+/// pixels are reached.
+fn grow_region(
+  features: &[[f32; 3]],
+  blocked: Option<&[bool]>,
+  width: usize,
+  height: usize,
+  seed: (usize, usize),
+  tolerance: f32,
+  reference: WandReference,
+  max_area: Option<usize>
+) -> Option<Vec<bool>> {
+  let seed_idx = seed.1 * width + seed.0;
+  let mut region = vec![false; width * height];
+  let mut visited = vec![false; width * height];
+  visited[seed_idx] = true;
+  let mut queue = VecDeque::new();
+  queue.push_back(seed);
+
+  let mut sum = [0f64; 3];
+  let mut area = 0usize;
+  while let Some((x, y)) = queue.pop_front() {
+    let idx = y * width + x;
+    region[idx] = true;
+    area += 1;
+    if max_area.is_some_and(|max_area| area > max_area) {
+      return None;
+    }
+    for c in 0..3 {
+      sum[c] += features[idx][c] as f64;
+    }
+    let target = match reference {
+      WandReference::Seed => features[seed_idx],
+      WandReference::RegionMean => sum.map(|v| (v / (area as f64)) as f32),
+    };
+
+    for (nx, ny) in neighbors_4(x, y, width, height) {
+      let n_idx = ny * width + nx;
+      if visited[n_idx] || blocked.is_some_and(|blocked| blocked[n_idx]) {
+        continue;
+      }
+      let distance = (0..3)
+        .map(|c| (features[n_idx][c] - target[c]).powi(2))
+        .sum::<f32>()
+        .sqrt();
+      if distance <= tolerance {
+        visited[n_idx] = true;
+        queue.push_back((nx, ny));
+      }
+    }
+  }
+  Some(region)
+}
+
+/// Magic wand: grows a region from the clicked `seed` [x, y] over pixels within `tolerance`
+/// (0-255 units) of the seed or region mean, in RGB or in the selected channel.
+/// `edge_threshold` stops the growth at Sobel gradient magnitudes above it (0-1442), and
+/// `max_area` rejects regions that leak past that many pixels.
+#[tauri::command]
+pub async fn magic_wand(
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  seed: [usize; 2],
+  tolerance: f32,
+  reference: Option<WandReference>,
+  channel: Option<Channel>,
+  edge_threshold: Option<f32>,
+  max_area: Option<usize>,
+  color: Option<[u8; 4]>
+) -> Result<Response, String> {
+  if image.len() != width * height * 4 {
+    return Err("Image buffer does not match the given dimensions".to_string());
+  }
+  let [seed_x, seed_y] = seed;
+  if seed_x >= width || seed_y >= height {
+    return Err("Seed point is outside the image".to_string());
+  }
+  let image = image::DynamicImage::ImageRgba8(
+    image::RgbaImage::from_raw(width as u32, height as u32, image).unwrap()
+  );
+
+  let features: Vec<[f32; 3]> = match channel {
+    Some(channel) =>
+      channel_array(&image, channel)
+        .iter()
+        .map(|&v| [v as f32, 0.0, 0.0])
+        .collect(),
+    None =>
+      image
+        .to_rgb8()
+        .pixels()
+        .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+        .collect(),
+  };
+
+  let blocked = edge_threshold.map(|threshold| {
+    let gray = channel_array(&image, channel.unwrap_or(Channel::Luma));
+    let gray = GrayImage::from_raw(width as u32, height as u32, gray.into_raw_vec_and_offset().0).unwrap();
+    sobel_gradients(&gray)
+      .pixels()
+      .map(|p| (p[0] as f32) > threshold)
+      .collect::<Vec<bool>>()
+  });
+
+  let start = std::time::Instant::now();
+  let region = grow_region(
+    &features,
+    blocked.as_deref(),
+    width,
+    height,
+    (seed_x, seed_y),
+    tolerance,
+    reference.unwrap_or(WandReference::Seed),
+    max_area
+  ).ok_or_else(|| "Region grew past the maximum area; lower the tolerance".to_string())?;
+  println!("Magic wand took: {:?}", start.elapsed());
+
+  let color = color.unwrap_or([255, 255, 255, 255]);
+  Ok(Response::new(paint_layer(region.iter().copied(), color)))
+}
+
 // A simple helper to get 4-connected neighbors
 fn neighbors_4(
     x: usize,
@@ -512,3 +819,288 @@ pub fn get_quad_tree_bbox(
   // Return the bounding boxes as JSON string
   Ok(Response::new(bbox_json))
 }
+
+pub(crate) fn rgba_layer(layer: &[u8], width: usize, height: usize) -> Result<Vec<bool>, String> {
+  if layer.len() != width * height * 4 {
+    return Err("Layer buffer does not match the given dimensions".to_string());
+  }
+  Ok(
+    layer
+      .chunks(4)
+      .map(|pixel| pixel[3] > 0)
+      .collect()
+  )
+}
+
+/// Colour of an RGBA layer: its first drawn pixel, by the same alpha rule as `rgba_layer`.
+pub(crate) fn layer_colour(layer: &[u8]) -> Option<[u8; 4]> {
+  layer
+    .chunks_exact(4)
+    .find(|pixel| pixel[3] > 0)
+    .map(|pixel| [pixel[0], pixel[1], pixel[2], pixel[3]])
+}
+
+/// RGBA layer with the covered pixels in `color` and the others transparent.
+pub(crate) fn paint_layer(covered: impl IntoIterator<Item = bool>, color: [u8; 4]) -> Vec<u8> {
+  covered
+    .into_iter()
+    .flat_map(|inside| if inside { color } else { [0; 4] })
+    .collect()
+}
+
+/// GrabCut foreground extraction, initialised with a rectangle [xmin, ymin, xmax, ymax)
+/// and/or a rough mask. Scribbles (RGBA layers) mark definite foreground and background.
+#[tauri::command]
+pub async fn grabcut_segment(
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  rect: Option<[usize; 4]>,
+  mask: Option<Vec<u8>>,
+  foreground_scribbles: Option<Vec<u8>>,
+  background_scribbles: Option<Vec<u8>>,
+  iterations: Option<usize>,
+  color: Option<[u8; 4]>
+) -> Result<Response, String> {
+  if image.len() != width * height * 4 {
+    return Err("Image buffer does not match the given dimensions".to_string());
+  }
+  // The rectangle is clamped to the image and must keep some area
+  let rect = rect.map(|[xmin, ymin, xmax, ymax]| [xmin, ymin, xmax.min(width), ymax.min(height)]);
+  if let Some([xmin, ymin, xmax, ymax]) = rect {
+    if xmin >= xmax || ymin >= ymax {
+      return Err("GrabCut rectangle is empty or outside the image".to_string());
+    }
+  }
+  let colors: Vec<[f32; 3]> = image
+    .chunks(4)
+    .map(|pixel| [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32])
+    .collect();
+
+  let mut trimap = vec![Trimap::ProbableBackground; width * height];
+  let mut mask_color = None;
+  match (&rect, &mask) {
+    (None, None) => {
+      return Err("GrabCut needs a rectangle or a rough mask".to_string());
+    }
+    (_, Some(mask)) => {
+      let inside = rgba_layer(mask, width, height)?;
+      mask_color = layer_colour(mask);
+      for (label, &inside) in trimap.iter_mut().zip(inside.iter()) {
+        if inside {
+          *label = Trimap::ProbableForeground;
+        }
+      }
+    }
+    (Some(_), None) => {
+      trimap.fill(Trimap::ProbableForeground);
+    }
+  }
+  // Everything outside the rectangle is known background
+  if let Some([xmin, ymin, xmax, ymax]) = rect {
+    for y in 0..height {
+      for x in 0..width {
+        if x < xmin || x >= xmax || y < ymin || y >= ymax {
+          trimap[y * width + x] = Trimap::Background;
+        }
+      }
+    }
+  }
+  if let Some(scribbles) = &foreground_scribbles {
+    for (label, marked) in trimap.iter_mut().zip(rgba_layer(scribbles, width, height)?) {
+      if marked {
+        *label = Trimap::Foreground;
+      }
+    }
+  }
+  if let Some(scribbles) = &background_scribbles {
+    for (label, marked) in trimap.iter_mut().zip(rgba_layer(scribbles, width, height)?) {
+      if marked {
+        *label = Trimap::Background;
+      }
+    }
+  }
+
+  let start = std::time::Instant::now();
+  let foreground = grabcut(&colors, &mut trimap, width, height, iterations.unwrap_or(5));
+  println!("GrabCut took: {:?}", start.elapsed());
+
+  let color = color.or(mask_color).unwrap_or([255, 255, 255, 255]);
+  Ok(Response::new(paint_layer(foreground.iter().copied(), color)))
+}
+
+/// Fills the image (or the region of interest [xmin, ymin, xmax, ymax)) from scribbles of
+/// several classes at once. `scribbles` holds one RGBA layer per class, back to back, and
+/// optional `background_scribbles` mark pixels that belong to none of them.
+/// Returns one RGBA mask per class, back to back, coloured like its scribbles.
+#[tauri::command]
+pub async fn scribble_segment(
+  image: Vec<u8>,
+  scribbles: Vec<u8>,
+  width: usize,
+  height: usize,
+  method: SeedMethod,
+  background_scribbles: Option<Vec<u8>>,
+  roi: Option<[usize; 4]>,
+  beta: Option<f32>
+) -> Result<Response, String> {
+  let layer_size = width * height * 4;
+  if image.len() != layer_size {
+    return Err("Image buffer does not match the given dimensions".to_string());
+  }
+  if layer_size == 0 || scribbles.len() % layer_size != 0 {
+    return Err("Scribble layers do not match the given dimensions".to_string());
+  }
+  let classes = scribbles.len() / layer_size;
+  if classes == 0 {
+    return Err("No scribble layers given".to_string());
+  }
+  let [xmin, ymin, xmax, ymax] = roi.unwrap_or([0, 0, width, height]);
+  let (xmax, ymax) = (xmax.min(width), ymax.min(height));
+  if xmin >= xmax || ymin >= ymax {
+    return Err("Region of interest is empty".to_string());
+  }
+  let (roi_width, roi_height) = (xmax - xmin, ymax - ymin);
+
+  let class_colors: Vec<[u8; 4]> = scribbles
+    .chunks(layer_size)
+    .map(|layer| layer_colour(layer).unwrap_or([0, 0, 0, 0]))
+    .collect();
+
+  // Background is the last label when it was scribbled
+  let mut colors = Vec::with_capacity(roi_width * roi_height);
+  let mut seeds = Vec::with_capacity(roi_width * roi_height);
+  for y in ymin..ymax {
+    for x in xmin..xmax {
+      let i = y * width + x;
+      colors.push([image[i * 4] as f32, image[i * 4 + 1] as f32, image[i * 4 + 2] as f32]);
+      let mut seed = (0..classes).find(|&c| scribbles[c * layer_size + i * 4 + 3] > 0);
+      if let Some(background) = &background_scribbles {
+        if background.get(i * 4 + 3).is_some_and(|&a| a > 0) {
+          seed = Some(classes);
+        }
+      }
+      seeds.push(seed);
+    }
+  }
+  if seeds.iter().all(|seed| seed.is_none()) {
+    return Err("No scribbles inside the region of interest".to_string());
+  }
+  let labels = if background_scribbles.is_some() { classes + 1 } else { classes };
+
+  let start = std::time::Instant::now();
+  let labelling = segment_from_seeds(
+    &colors,
+    &seeds,
+    labels,
+    roi_width,
+    roi_height,
+    method,
+    beta.unwrap_or(1.0)
+  );
+  println!("Scribble segmentation ({:?}) took: {:?}", method, start.elapsed());
+
+  let mut output = vec![0u8; layer_size * classes];
+  for (k, &label) in labelling.iter().enumerate() {
+    if label >= classes {
+      continue;
+    }
+    let i = (ymin + k / roi_width) * width + xmin + (k % roi_width);
+    let offset = label * layer_size + i * 4;
+    output[offset..offset + 4].copy_from_slice(&class_colors[label]);
+  }
+  Ok(Response::new(output))
+}
+
+/// Splits a binary mask into instances with a distance-transform watershed. Markers are
+/// the user's clicks ([x, y]) when given, otherwise the peaks of the distance map at least
+/// `min_distance` pixels apart. Same output as `sam_segment_instances`.
+#[tauri::command]
+pub async fn watershed_instances(
+  mask: Vec<u8>,
+  width: usize,
+  height: usize,
+  min_distance: Option<usize>,
+  seeds: Option<Vec<[usize; 2]>>,
+  shades: Option<Vec<[u8; 4]>>
+) -> Result<Response, String> {
+  if mask.len() != width * height * 4 {
+    return Err("Mask buffer does not match the given dimensions".to_string());
+  }
+  let color = layer_colour(&mask).unwrap_or([255, 255, 255, 255]);
+  let inside = rgba_layer(&mask, width, height)?;
+
+  let distance = watershed::distance_map(&inside, width, height);
+  let (markers, marker_count) = match seeds.filter(|seeds| !seeds.is_empty()) {
+    Some(seeds) => {
+      let mut markers = vec![0u32; width * height];
+      let mut count = 0;
+      for [x, y] in seeds {
+        if x < width && y < height && inside[y * width + x] {
+          count += 1;
+          markers[y * width + x] = count;
+        }
+      }
+      (markers, count)
+    }
+    None => watershed::distance_peaks(&distance, width, height, min_distance.unwrap_or(7)),
+  };
+  let labels = watershed::watershed(&inside, &distance, &markers, marker_count, width, height);
+
+  let count = labels.iter().copied().max().unwrap_or(0) as usize;
+  let result = collect_instances(&labels, width, height, count, None, color, shades.as_deref());
+  println!("Watershed split the mask into {} instances", result.instances.len());
+
+  let result_json = serde_json::to_string(&result).map_err(|e| e.to_string())?;
+  Ok(Response::new(result_json))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A dark square on a background brightening to the right
+  fn square_on_ramp(width: usize, height: usize) -> Vec<[f32; 3]> {
+    (0..width * height)
+      .map(|i| {
+        let (x, y) = (i % width, i / width);
+        if (4..12).contains(&x) && (4..12).contains(&y) {
+          [20.0, 20.0, 20.0]
+        } else {
+          let v = 100.0 + (x as f32) * 4.0;
+          [v, v, v]
+        }
+      })
+      .collect()
+  }
+
+  #[test]
+  fn wand_fills_the_clicked_region() {
+    let features = square_on_ramp(32, 16);
+    let region = grow_region(&features, None, 32, 16, (6, 6), 10.0, WandReference::Seed, None).unwrap();
+    assert_eq!(region.iter().filter(|&&inside| inside).count(), 64);
+    assert!(region[8 * 32 + 8] && !region[8 * 32 + 14]);
+  }
+
+  #[test]
+  fn wand_reference_on_a_ramp() {
+    // Columns differ by 4√3 in RGB, so within 15 of a seed on the bright edge is 2 more columns
+    // while the running mean creeps down the ramp
+    let features = square_on_ramp(32, 16);
+    let seeded = grow_region(&features, None, 32, 16, (31, 14), 15.0, WandReference::Seed, None).unwrap();
+    let columns = |region: &[bool]| (0..32).filter(|&x| region[14 * 32 + x]).count();
+    assert_eq!(columns(&seeded), 3);
+    let mean = grow_region(&features, None, 32, 16, (31, 14), 15.0, WandReference::RegionMean, None).unwrap();
+    assert!(columns(&mean) > 3);
+  }
+
+  #[test]
+  fn wand_stops_at_edges_and_area() {
+    let features = vec![[50.0, 50.0, 50.0]; 16 * 16];
+    // A blocked column cuts the image in two
+    let blocked: Vec<bool> = (0..16 * 16).map(|i| i % 16 == 8).collect();
+    let region = grow_region(&features, Some(&blocked), 16, 16, (2, 2), 5.0, WandReference::Seed, None).unwrap();
+    assert_eq!(region.iter().filter(|&&inside| inside).count(), 8 * 16);
+    assert!(grow_region(&features, None, 16, 16, (2, 2), 5.0, WandReference::Seed, Some(100)).is_none());
+  }
+}
@@ -0,0 +1,181 @@
+use base64::{ engine::general_purpose::STANDARD, Engine as _ };
+use serde::Serialize;
+use tauri::{ self, ipc::Response };
+
+use crate::commands::segmentation::{ channel_array, layer_colour, paint_layer, rgba_layer, Channel };
+use crate::tools::thresholding::otsu_level;
+use crate::tools::vessel_graph::{ self, VesselGraph };
+use crate::tools::vesselness::{ vesselness, VesselFilter };
+
+const DEFAULT_SIGMAS: [f32; 4] = [1.0, 2.0, 4.0, 8.0];
+// Responses are scaled by this percentile rather than the maximum, which is usually
+// an outlier such as the edge of the fundus field of view
+const NORMALISATION_PERCENTILE: f32 = 0.99;
+
+fn percentile(values: impl Iterator<Item = f32>, q: f32) -> f32 {
+  let mut sorted: Vec<f32> = values.collect();
+  sorted.sort_by(|a, b| a.total_cmp(b));
+  sorted
+    .get((((sorted.len() as f32) * q) as usize).min(sorted.len().saturating_sub(1)))
+    .copied()
+    .unwrap_or(0.0)
+    .max(1e-12)
+}
+
+fn vesselness_map(
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  filter: VesselFilter,
+  sigmas: Option<Vec<f32>>,
+  dark_vessels: Option<bool>,
+  channel: Option<Channel>
+) -> Result<Vec<f32>, String> {
+  if image.len() != width * height * 4 {
+    return Err("Image buffer does not match the given dimensions".to_string());
+  }
+  let image = image::DynamicImage::ImageRgba8(
+    image::RgbaImage::from_raw(width as u32, height as u32, image).unwrap()
+  );
+  let values: Vec<f32> = channel_array(&image, channel.unwrap_or(Channel::Green))
+    .iter()
+    .map(|&v| v as f32)
+    .collect();
+  let sigmas = sigmas.unwrap_or(DEFAULT_SIGMAS.to_vec());
+  if sigmas.is_empty() || sigmas.iter().any(|sigma| !sigma.is_finite() || *sigma <= 0.0) {
+    return Err("Sigmas must be finite and positive".to_string());
+  }
+  // Kernels span 3 sigma each side, wider than the image they only cost time
+  let largest = width.max(height) as f32;
+  let sigmas: Vec<f32> = sigmas
+    .iter()
+    .map(|sigma| sigma.min(largest))
+    .collect();
+
+  let start = std::time::Instant::now();
+  let response = vesselness(&values, width, height, &sigmas, filter, dark_vessels.unwrap_or(true));
+  println!("{:?} vesselness took: {:?}", filter, start.elapsed());
+  Ok(response)
+}
+
+/// Multi-scale vesselness of the image, one byte per pixel with the 99th percentile of the
+/// response at 255. Defaults to dark vessels on the green channel over sigmas of 1 to 8 pixels.
+#[tauri::command]
+pub async fn vessel_enhancement(
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  filter: VesselFilter,
+  sigmas: Option<Vec<f32>>,
+  dark_vessels: Option<bool>,
+  channel: Option<Channel>
+) -> Result<Response, String> {
+  let response = vesselness_map(image, width, height, filter, sigmas, dark_vessels, channel)?;
+  let top = percentile(response.iter().copied(), NORMALISATION_PERCENTILE);
+  Ok(
+    Response::new(
+      response
+        .iter()
+        .map(|&v| ((v / top) * 255.0).round().min(255.0) as u8)
+        .collect::<Vec<u8>>()
+    )
+  )
+}
+
+/// Vessel brush: keeps the pixels of the drawn mask whose vesselness is above `threshold`
+/// (a fraction of the 99th percentile of the responses inside the mask), or above the Otsu
+/// level of the masked responses when no threshold is given. Returns an RGBA mask in the
+/// mask colour.
+#[tauri::command]
+pub async fn vessel_segmentation(
+  image: Vec<u8>,
+  mask: Vec<u8>,
+  width: usize,
+  height: usize,
+  filter: VesselFilter,
+  sigmas: Option<Vec<f32>>,
+  dark_vessels: Option<bool>,
+  channel: Option<Channel>,
+  threshold: Option<f32>
+) -> Result<Response, String> {
+  let inside = rgba_layer(&mask, width, height)?;
+  let color = layer_colour(&mask).unwrap_or([255, 255, 255, 255]);
+  let response = vesselness_map(image, width, height, filter, sigmas, dark_vessels, channel)?;
+
+  let top = percentile(
+    response
+      .iter()
+      .zip(inside.iter())
+      .filter(|(_, &inside)| inside)
+      .map(|(&v, _)| v),
+    NORMALISATION_PERCENTILE
+  );
+  let quantised: Vec<u8> = response
+    .iter()
+    .map(|&v| ((v / top) * 255.0).round().min(255.0) as u8)
+    .collect();
+  let level = match threshold {
+    Some(threshold) => (threshold.clamp(0.0, 1.0) * 255.0) as u8,
+    None => {
+      let masked: Vec<u8> = quantised
+        .iter()
+        .zip(inside.iter())
+        .filter(|(_, &inside)| inside)
+        .map(|(&v, _)| v)
+        .collect();
+      otsu_level(&masked)
+    }
+  };
+
+  let vessels = quantised
+    .iter()
+    .zip(inside.iter())
+    .map(|(&v, &inside)| inside && v > level);
+  Ok(Response::new(paint_layer(vessels, color)))
+}
+
+#[derive(Serialize)]
+struct SkeletonGraph {
+  width: usize,
+  height: usize,
+  // Base64 RGBA skeleton, in the mask colour
+  skeleton: String,
+  graph: VesselGraph,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  graphml: Option<String>,
+}
+
+/// Skeleton of a vessel or neurite mask and its graph: endpoints and junctions as nodes,
+/// branches as segments with their length, mean calibre and tortuosity. The graph is also
+/// returned as a GraphML document when `graphml` is set.
+#[tauri::command]
+pub async fn extract_vessel_graph(
+  mask: Vec<u8>,
+  width: usize,
+  height: usize,
+  graphml: Option<bool>
+) -> Result<Response, String> {
+  let inside = rgba_layer(&mask, width, height)?;
+  let color = layer_colour(&mask).unwrap_or([255, 255, 255, 255]);
+
+  let start = std::time::Instant::now();
+  let skeleton = vessel_graph::skeletonize(&inside, width, height).map_err(|e| e.to_string())?;
+  let graph = vessel_graph::vessel_graph(&skeleton, &inside, width, height);
+  println!(
+    "Vessel graph with {} nodes and {} segments took: {:?}",
+    graph.nodes.len(),
+    graph.segments.len(),
+    start.elapsed()
+  );
+
+  let output = paint_layer(skeleton.iter().copied(), color);
+  let result = SkeletonGraph {
+    width,
+    height,
+    skeleton: STANDARD.encode(output),
+    graphml: graphml.unwrap_or(false).then(|| graph.to_graphml()),
+    graph,
+  };
+  let result_json = serde_json::to_string(&result).map_err(|e| e.to_string())?;
+  Ok(Response::new(result_json))
+}
@@ -1,3 +1,4 @@
 pub mod coms;
+#[allow(clippy::module_inception)]
 pub mod connection;
 pub mod types;
\ No newline at end of file
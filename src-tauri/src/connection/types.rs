@@ -27,15 +27,15 @@ impl From<tauri::Error> for ComError {
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MulticlassConfig{
-    name: String,
-    classes: Vec<String>,
-    default: Option<String>,
+    pub name: String,
+    pub classes: Vec<String>,
+    pub default: Option<String>,
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MultilabelConfig{
-    name: String,
-    classes: Vec<String>,
-    default: Option<Vec<String>>,
+    pub name: String,
+    pub classes: Vec<String>,
+    pub default: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+
+use ndarray::Axis;
+use ort::session::Session;
+use serde::{ Deserialize, Serialize };
+
+use crate::dl::classifier::{ self, ClassifierConfig };
+use crate::dl::feature_extract::FeaturesExtractor;
+use crate::dl::model::{ self, ModelError };
+use crate::dl::sam;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UncertaintySource {
+  // Entropy of a classification model's prediction.
+  Classification {
+    classifier: ClassifierConfig,
+    multilabel: bool,
+  },
+  // Mean per-pixel entropy of a segmentation model's output (N, C, H, W).
+  Segmentation {
+    model: ClassifierConfig,
+  },
+  // Disagreement between the MedSAM hypotheses for a whole-image box.
+  Sam,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RankedImage {
+  pub path: String,
+  pub uncertainty: f32,
+}
+
+#[derive(Default)]
+pub struct ActiveLearningQueue {
+  source: Option<UncertaintySource>,
+  // Images still to annotate, most uncertain first.
+  pub ranked: Vec<RankedImage>,
+  annotated: HashSet<String>,
+  // Re-rank after this many saved annotations, 0 to never re-rank.
+  rerank_every: usize,
+  saved_since_rank: usize,
+}
+
+fn load_rgba(path: &str) -> Result<(Vec<u8>, usize, usize), ModelError> {
+  let image = image
+    ::open(path)
+    .map_err(|e| ModelError::Inference(format!("Failed to open {}: {}", path, e)))?
+    .to_rgba8();
+  let (width, height) = image.dimensions();
+  Ok((image.into_raw(), width as usize, height as usize))
+}
+
+fn segmentation_uncertainty(
+  session: &Session,
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  config: &ClassifierConfig
+) -> Result<f32, ModelError> {
+  let size = classifier::input_size(session, config);
+  let tensor = classifier::prepare_image(image, width, height, size, config)?;
+  let outputs = session.run(ort::inputs![tensor]?)?;
+  let logits = outputs[0].try_extract_tensor::<f32>()?;
+  if logits.ndim() != 4 {
+    return Err(ModelError::Inference("Segmentation output must be (N, C, H, W)".to_string()));
+  }
+
+  let logits = logits.index_axis(Axis(0), 0);
+  let num_classes = logits.shape()[0];
+  let pixels = logits.lanes(Axis(0));
+  let total: f32 = pixels
+    .into_iter()
+    .map(|lane| {
+      let values: Vec<f32> = lane.iter().copied().collect();
+      if num_classes == 1 {
+        classifier::binary_entropy(classifier::sigmoid(values[0]))
+      } else {
+        classifier::normalised_entropy(&classifier::softmax(&values))
+      }
+    })
+    .sum();
+  let count = logits.len() / num_classes.max(1);
+  Ok(total / (count.max(1) as f32))
+}
+
+fn sam_uncertainty(
+  encoder: &Session,
+  decoder: &Session,
+  features_extractor: &mut FeaturesExtractor,
+  image: Vec<u8>,
+  width: usize,
+  height: usize
+) -> Result<f32, ModelError> {
+  let tensor = features_extractor.prepare_image(image, width, height);
+  features_extractor
+    .extract_features(tensor, encoder)
+    .map_err(|e| ModelError::Inference(e.to_string()))?;
+  let letterbox = features_extractor.letterbox();
+  let bbox = [0.0, 0.0, letterbox.resized_width as f32, letterbox.resized_height as f32];
+  sam::hypothesis_disagreement(decoder, features_extractor, bbox)
+}
+
+/// Scores every image with the given source. Images that fail are logged and left out.
+pub fn score_images(
+  images: &[String],
+  source: &UncertaintySource,
+  app: &tauri::AppHandle
+) -> Result<Vec<RankedImage>, ModelError> {
+  let mut scores = Vec::with_capacity(images.len());
+  match source {
+    UncertaintySource::Classification { classifier: config, multilabel } => {
+      let session = model::get_classifier(&config.model_path)?;
+      for path in images {
+        let score = load_rgba(path).and_then(|(image, width, height)| {
+          classifier
+            ::classification_uncertainty(&session, image, width, height, config, *multilabel)
+            .map_err(ModelError::from)
+        });
+        push_score(&mut scores, path, score);
+      }
+    }
+    UncertaintySource::Segmentation { model: config } => {
+      let session = model::get_classifier(&config.model_path)?;
+      for path in images {
+        let score = load_rgba(path).and_then(|(image, width, height)| {
+          segmentation_uncertainty(&session, image, width, height, config)
+        });
+        push_score(&mut scores, path, score);
+      }
+    }
+    UncertaintySource::Sam => {
+      let encoder = model::get_encoder(app)?;
+      let decoder = model::get_decoder(app)?;
+      // Separate extractor so the embedding of the image being edited stays cached
+      let mut features_extractor = FeaturesExtractor::new();
+      for path in images {
+        let score = load_rgba(path).and_then(|(image, width, height)| {
+          sam_uncertainty(&encoder, &decoder, &mut features_extractor, image, width, height)
+        });
+        push_score(&mut scores, path, score);
+      }
+    }
+  }
+  scores.sort_by(|a, b| b.uncertainty.total_cmp(&a.uncertainty));
+  Ok(scores)
+}
+
+fn push_score(scores: &mut Vec<RankedImage>, path: &str, score: Result<f32, ModelError>) {
+  match score {
+    Ok(uncertainty) => scores.push(RankedImage { path: path.to_string(), uncertainty }),
+    Err(e) => eprintln!("Failed to score {}: {}", path, e),
+  }
+}
+
+impl ActiveLearningQueue {
+  pub fn new() -> Self {
+    ActiveLearningQueue::default()
+  }
+
+  /// Replaces the queue with a new ranking of the images that are not in `annotated`.
+  pub fn reset(
+    &mut self,
+    ranked: Vec<RankedImage>,
+    source: UncertaintySource,
+    annotated: HashSet<String>,
+    rerank_every: usize
+  ) {
+    self.ranked = ranked;
+    self.source = Some(source);
+    self.annotated = annotated;
+    self.rerank_every = rerank_every;
+    self.saved_since_rank = 0;
+  }
+
+  /// Removes a saved image from the queue. Once enough annotations were saved, returns the
+  /// images left and the source to re-rank them with; scoring happens outside the queue and
+  /// the result comes back through `update_ranking`.
+  pub fn mark_annotated(&mut self, path: &str) -> Option<(Vec<String>, UncertaintySource)> {
+    self.ranked.retain(|image| image.path != path);
+    if !self.annotated.insert(path.to_string()) {
+      return None;
+    }
+    self.saved_since_rank += 1;
+    if self.rerank_every == 0 || self.saved_since_rank < self.rerank_every {
+      return None;
+    }
+
+    let source = self.source.clone()?;
+    // The model may have been retrained on the new annotations in the meantime
+    match &source {
+      UncertaintySource::Classification { classifier, .. } => {
+        model::unload_classifier(&classifier.model_path);
+      }
+      UncertaintySource::Segmentation { model: config } => {
+        model::unload_classifier(&config.model_path);
+      }
+      UncertaintySource::Sam => {}
+    }
+    self.saved_since_rank = 0;
+    let remaining: Vec<String> = self.ranked
+      .iter()
+      .map(|image| image.path.clone())
+      .collect();
+    Some((remaining, source))
+  }
+
+  /// Swaps in re-ranked scores, leaving out images saved while they were computed.
+  pub fn update_ranking(&mut self, mut ranked: Vec<RankedImage>) {
+    ranked.retain(|image| !self.annotated.contains(&image.path));
+    self.ranked = ranked;
+  }
+}
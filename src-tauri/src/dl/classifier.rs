@@ -0,0 +1,220 @@
+use ndarray::Array4;
+use ort::{ session::Session, value::Tensor };
+use serde::{ Deserialize, Serialize };
+
+use crate::connection::types::{ MulticlassConfig, MultilabelConfig };
+
+fn default_mean() -> [f32; 3] {
+  [0.485, 0.456, 0.406]
+}
+
+fn default_std() -> [f32; 3] {
+  [0.229, 0.224, 0.225]
+}
+
+fn default_threshold() -> f32 {
+  0.5
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClassifierConfig {
+  pub model_path: String,
+  // Side of the square input; read from the model when it has a fixed shape.
+  pub input_size: Option<u32>,
+  #[serde(default = "default_mean")]
+  pub mean: [f32; 3],
+  #[serde(default = "default_std")]
+  pub std: [f32; 3],
+  // Sigmoid threshold above which a label is selected (multilabel only).
+  #[serde(default = "default_threshold")]
+  pub threshold: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClassificationPrediction<T> {
+  pub task: T,
+  // Model confidence for each entry of the task classes, kept out of the task config.
+  pub scores: Vec<f32>,
+  // Normalised entropy in [0, 1]; higher means the model is less sure.
+  pub uncertainty: f32,
+}
+
+pub fn prepare_image(
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  size: u32,
+  config: &ClassifierConfig
+) -> Result<Tensor<f32>, String> {
+  let image = image::RgbaImage
+    ::from_raw(width as u32, height as u32, image)
+    .ok_or("Image buffer does not match the given dimensions".to_string())?;
+  let image = image::imageops::resize(&image, size, size, image::imageops::FilterType::Triangle);
+
+  let mut image_array = Array4::<f32>::zeros([1, 3, size as usize, size as usize]);
+  for (x, y, pixel) in image.enumerate_pixels() {
+    for c in 0..3 {
+      image_array[[0, c, y as usize, x as usize]] =
+        ((pixel[c] as f32) / 255.0 - config.mean[c]) / config.std[c];
+    }
+  }
+  Tensor::from_array(image_array).map_err(|e| e.to_string())
+}
+
+/// Input side for a model: the configured one, else the model's fixed NCHW size, else 224.
+pub fn input_size(session: &Session, config: &ClassifierConfig) -> u32 {
+  let model_size = session.inputs
+    .first()
+    .and_then(|input| input.input_type.tensor_dimensions())
+    .and_then(|dims| dims.last().copied())
+    .filter(|&d| d > 0)
+    .map(|d| d as u32);
+  config.input_size.or(model_size).unwrap_or(224)
+}
+
+fn run_classifier(
+  session: &Session,
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  config: &ClassifierConfig
+) -> Result<Vec<f32>, String> {
+  let size = input_size(session, config);
+
+  let tensor = prepare_image(image, width, height, size, config)?;
+  println!("Running classifier inference at {}x{}", size, size);
+  let outputs = session
+    .run(ort::inputs![tensor].map_err(|e| e.to_string())?)
+    .map_err(|e| e.to_string())?;
+  let logits = outputs[0].try_extract_tensor::<f32>().map_err(|e| e.to_string())?;
+  Ok(logits.iter().copied().collect())
+}
+
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+  let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+  let exps: Vec<f32> = logits
+    .iter()
+    .map(|&l| (l - max).exp())
+    .collect();
+  let sum: f32 = exps.iter().sum();
+  exps
+    .iter()
+    .map(|&e| e / sum)
+    .collect()
+}
+
+pub fn sigmoid(logit: f32) -> f32 {
+  1.0 / (1.0 + (-logit).exp())
+}
+
+pub fn binary_entropy(p: f32) -> f32 {
+  let p = p.clamp(1e-7, 1.0 - 1e-7);
+  -(p * p.ln() + (1.0 - p) * (1.0 - p).ln()) / std::f32::consts::LN_2
+}
+
+/// Entropy of a categorical distribution, divided by its maximum so it lies in [0, 1].
+pub fn normalised_entropy(probabilities: &[f32]) -> f32 {
+  let entropy: f32 = probabilities
+    .iter()
+    .filter(|&&p| p > 0.0)
+    .map(|&p| -p * p.ln())
+    .sum();
+  let max_entropy = (probabilities.len() as f32).ln();
+  if max_entropy > 0.0 { entropy / max_entropy } else { 0.0 }
+}
+
+pub fn mean_binary_entropy(probabilities: &[f32]) -> f32 {
+  if probabilities.is_empty() {
+    return 0.0;
+  }
+  probabilities
+    .iter()
+    .map(|&p| binary_entropy(p))
+    .sum::<f32>() / (probabilities.len() as f32)
+}
+
+/// Uncertainty of a classifier on one image, without mapping to class names.
+pub fn classification_uncertainty(
+  session: &Session,
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  config: &ClassifierConfig,
+  multilabel: bool
+) -> Result<f32, String> {
+  let logits = run_classifier(session, image, width, height, config)?;
+  if multilabel {
+    let probabilities: Vec<f32> = logits
+      .iter()
+      .map(|&l| sigmoid(l))
+      .collect();
+    Ok(mean_binary_entropy(&probabilities))
+  } else {
+    Ok(normalised_entropy(&softmax(&logits)))
+  }
+}
+
+fn check_output_size(logits: &[f32], classes: &[String]) -> Result<(), String> {
+  if logits.len() != classes.len() {
+    return Err(
+      format!(
+        "Model returned {} outputs but the task has {} classes",
+        logits.len(),
+        classes.len()
+      )
+    );
+  }
+  Ok(())
+}
+
+pub fn predict_multiclass(
+  session: &Session,
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  config: &ClassifierConfig,
+  mut task: MulticlassConfig
+) -> Result<ClassificationPrediction<MulticlassConfig>, String> {
+  let logits = run_classifier(session, image, width, height, config)?;
+  check_output_size(&logits, &task.classes)?;
+
+  let probabilities = softmax(&logits);
+  let (best, _) = probabilities
+    .iter()
+    .enumerate()
+    .max_by(|a, b| a.1.total_cmp(b.1))
+    .ok_or("Task has no classes".to_string())?;
+
+  let uncertainty = normalised_entropy(&probabilities);
+
+  task.default = Some(task.classes[best].clone());
+  Ok(ClassificationPrediction { task, scores: probabilities, uncertainty })
+}
+
+pub fn predict_multilabel(
+  session: &Session,
+  image: Vec<u8>,
+  width: usize,
+  height: usize,
+  config: &ClassifierConfig,
+  mut task: MultilabelConfig
+) -> Result<ClassificationPrediction<MultilabelConfig>, String> {
+  let logits = run_classifier(session, image, width, height, config)?;
+  check_output_size(&logits, &task.classes)?;
+
+  let probabilities: Vec<f32> = logits
+    .iter()
+    .map(|&l| sigmoid(l))
+    .collect();
+  let selected: Vec<String> = task.classes
+    .iter()
+    .zip(probabilities.iter())
+    .filter(|&(_, &p)| p > config.threshold)
+    .map(|(class, _)| class.clone())
+    .collect();
+
+  let uncertainty = mean_binary_entropy(&probabilities);
+
+  task.default = Some(selected);
+  Ok(ClassificationPrediction { task, scores: probabilities, uncertainty })
+}
@@ -1,15 +1,48 @@
-use image::GenericImageView;
-use ndarray::{ Array4, Array3 };
+use ndarray::{ Array2, Array4, Array3, ArrayViewD };
 use ort::{
   memory::Allocator, value::{Tensor, Value}
 
 };
 
+use crate::dl::model::ModelError;
 use crate::tools;
 
+/// Longest-side resize into a square canvas, padded on the bottom and right.
+#[derive(Debug, Clone, Copy)]
+pub struct Letterbox {
+  // Size of the source image
+  pub width: u32,
+  pub height: u32,
+  pub scale: f32,
+  pub resized_width: u32,
+  pub resized_height: u32,
+}
+
+impl Letterbox {
+  pub fn new(width: u32, height: u32, target: u32) -> Self {
+    let scale = (target as f32) / (width.max(height).max(1) as f32);
+    Letterbox {
+      width,
+      height,
+      scale,
+      resized_width: (((width as f32) * scale).round() as u32).clamp(1, target),
+      resized_height: (((height as f32) * scale).round() as u32).clamp(1, target),
+    }
+  }
+}
+
+/// Encoder input and the letterbox it was built with, which only becomes the extractor's
+/// once the encoder succeeded on it.
+pub struct PreparedImage {
+  tensor: Tensor<f32>,
+  letterbox: Letterbox,
+}
+
 pub struct FeaturesExtractor {
   expected_size: u32,
   pub features: Value,
+  // Transform used for the image the cached features come from.
+  letterbox: Letterbox,
 }
 
 impl FeaturesExtractor {
@@ -18,16 +51,29 @@ impl FeaturesExtractor {
     FeaturesExtractor {
       expected_size: 1024,
       features: Tensor::<f32>::new(&Allocator::default(), [1, 256, 64, 64]).unwrap().into_dyn(),
+      letterbox: Letterbox::new(1024, 1024, 1024),
     }
   }
-  pub fn prepare_image(&self, input_blob: Vec<u8>, width: usize, height: usize) -> Tensor<f32> {
+  pub fn prepare_image(&self, input_blob: Vec<u8>, width: usize, height: usize) -> PreparedImage {
     let image = self.load_blob_to_image(input_blob, width, height);
-    println!("Image size: {:?}", image.dimensions());
-    let image = image.resize_exact(
-      self.expected_size,
-      self.expected_size,
-      image::imageops::FilterType::Nearest
-    );
+    let letterbox = Letterbox::new(width as u32, height as u32, self.expected_size);
+    println!("Image size: {:?}, letterboxed to {:?}", (width, height), letterbox);
+    let image = image
+      .resize_exact(
+        letterbox.resized_width,
+        letterbox.resized_height,
+        image::imageops::FilterType::Triangle
+      )
+      .to_rgb8();
+
+    // MedSAM was trained on images min-max normalised to [0, 1]
+    let (min, max) = image
+      .as_raw()
+      .iter()
+      .fold((u8::MAX, u8::MIN), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+    let range = (max.saturating_sub(min) as f32).max(1e-8);
+
+    // The padded area stays at zero
     let mut image_array = Array4::<f32>::zeros([
       1,
       3,
@@ -35,13 +81,80 @@ impl FeaturesExtractor {
       self.expected_size as usize,
     ]);
 
-    for (x, y, pixel) in image.pixels() {
+    for (x, y, pixel) in image.enumerate_pixels() {
       for c in 0..3 {
-        image_array[[0, c, y as usize, x as usize]] = (pixel[c] as f32) / 255.0;
+        image_array[[0, c, y as usize, x as usize]] = ((pixel[c] - min) as f32) / range;
       }
     }
 
-    Tensor::from_array(image_array).unwrap()
+    PreparedImage { tensor: Tensor::from_array(image_array).unwrap(), letterbox }
+  }
+
+  /// Maps the decoder output (expected_size x expected_size, letterboxed) back onto the
+  /// original image grid with bilinear sampling.
+  pub fn restore_mask(
+    &self,
+    output: &ArrayViewD<f32>,
+    width: usize,
+    height: usize
+  ) -> Result<Array2<f32>, ModelError> {
+    self.restore_mask_crop(output, [0, 0, width, height])
+  }
+
+  /// Same as `restore_mask` for the image region [xmin, ymin, xmax, ymax) only, which has
+  /// to lie inside the image the features come from.
+  pub fn restore_mask_crop(
+    &self,
+    output: &ArrayViewD<f32>,
+    crop: [usize; 4]
+  ) -> Result<Array2<f32>, ModelError> {
+    let [xmin, ymin, xmax, ymax] = crop;
+    let (width, height) = (self.letterbox.width as usize, self.letterbox.height as usize);
+    if xmin > xmax || ymin > ymax || xmax > width || ymax > height {
+      let message = format!(
+        "Region {:?} does not fit the {}x{} image the features come from",
+        crop,
+        width,
+        height
+      );
+      return Err(ModelError::Inference(message));
+    }
+    let shape = output.shape();
+    let out_h = shape[shape.len() - 2];
+    let out_w = shape[shape.len() - 1];
+    let values: Vec<f32> = output.iter().copied().take(out_h * out_w).collect();
+
+    // The decoder mask may be at a lower resolution than the encoder input
+    let ratio_x = (out_w as f32) / (self.expected_size as f32);
+    let ratio_y = (out_h as f32) / (self.expected_size as f32);
+    let max_x = ((self.letterbox.resized_width as f32) * ratio_x - 1.0).max(0.0);
+    let max_y = ((self.letterbox.resized_height as f32) * ratio_y - 1.0).max(0.0);
+    let scale_x = self.letterbox.scale * ratio_x;
+    let scale_y = self.letterbox.scale * ratio_y;
+
+    Ok(Array2::from_shape_fn((ymax - ymin, xmax - xmin), |(y, x)| {
+      let (x, y) = (x + xmin, y + ymin);
+      let u = (((x as f32) + 0.5) * scale_x - 0.5).clamp(0.0, max_x);
+      let v = (((y as f32) + 0.5) * scale_y - 0.5).clamp(0.0, max_y);
+      let x0 = u.floor() as usize;
+      let y0 = v.floor() as usize;
+      let x1 = (x0 + 1).min(max_x as usize);
+      let y1 = (y0 + 1).min(max_y as usize);
+      let fx = u - (x0 as f32);
+      let fy = v - (y0 as f32);
+
+      let top = values[y0 * out_w + x0] * (1.0 - fx) + values[y0 * out_w + x1] * fx;
+      let bottom = values[y1 * out_w + x0] * (1.0 - fx) + values[y1 * out_w + x1] * fx;
+      top * (1.0 - fy) + bottom * fy
+    }))
+  }
+
+  pub fn letterbox(&self) -> Letterbox {
+    self.letterbox
+  }
+
+  pub fn expected_size(&self) -> u32 {
+    self.expected_size
   }
 
   fn load_blob_to_image(&self, blob: Vec<u8>, width: usize, height: usize) -> image::DynamicImage {
@@ -51,24 +164,36 @@ impl FeaturesExtractor {
     )
   }
 
+  /// Runs the encoder, the features and letterbox are only replaced when it succeeds.
   pub fn extract_features(
     &mut self,
-    image: Tensor<f32>,
+    image: PreparedImage,
     session: &ort::session::Session
   ) -> Result<(), Box<dyn std::error::Error>> {
     println!("Running encoder inference");
-    let mut io_binding = session.create_binding().unwrap();
-    io_binding.bind_input("image", &image)?;
+    let mut io_binding = session.create_binding()?;
+    io_binding.bind_input("image", &image.tensor)?;
     io_binding.bind_output_to_device("features", &session.allocator().memory_info())?;
 
-    self.features = io_binding.run().unwrap().remove("features").unwrap();
+    self.features = io_binding.run()?.remove("features").ok_or("Encoder did not return features")?;
+    self.letterbox = image.letterbox;
     Ok(())
-    
   }
   pub fn get_features(&self) -> &Value {
     &self.features
   }
 
+  /// Copy of the cached embedding and its letterbox, so long decodes do not have to hold
+  /// the shared extractor.
+  pub fn detached(&self) -> Result<FeaturesExtractor, ModelError> {
+    let features = self.features.try_extract_tensor::<f32>()?.to_owned();
+    Ok(FeaturesExtractor {
+      expected_size: self.expected_size,
+      features: Tensor::from_array(features)?.into_dyn(),
+      letterbox: self.letterbox,
+    })
+  }
+
   pub fn extract_bbox_and_color_from_mask(
     &self,
     mask: Vec<u8>,
@@ -80,25 +205,27 @@ impl FeaturesExtractor {
     let mask: image::DynamicImage = image::DynamicImage::ImageRgba8(
       image::RgbaImage::from_raw(width as u32, height as u32, mask).unwrap()
     );
+    // Same letterbox as the encoder input, at a quarter of the resolution
+    let coarse = Letterbox::new(width as u32, height as u32, 256);
     let mask: image::DynamicImage = mask.resize_exact(
-      256,
-      256,
+      coarse.resized_width,
+      coarse.resized_height,
       image::imageops::FilterType::Nearest
     );
-    let mask_pixels: Vec<_> = mask.pixels().collect();
-    // Find the first pixel with a non-zero value and use it as the color
+    let mask_pixels = mask.to_rgba8();
+    // Find the first painted pixel and use it as the color, classes may have no red at all
     let color = mask_pixels
-      .iter()
-      .find_map(|(_, _, pixel)| {
-        if pixel[0] > 0 { Some([pixel[0], pixel[1], pixel[2], pixel[3]]) } else { None }
+      .pixels()
+      .find_map(|pixel| {
+        if pixel[3] > 0 { Some([pixel[0], pixel[1], pixel[2], pixel[3]]) } else { None }
       })
       .unwrap_or([0, 0, 0, 0]);
 
     let graymask: image::ImageBuffer<image::Luma<u8>, Vec<u8>> = mask.to_luma8();
 
     let mut boxes = tools::split_and_merge::quadtree_bounding_boxes(&graymask, max_depth, min_size);
-    // Resize coordinates from 256x256 to expected_size x expected_size
-    
+    // Resize coordinates from the 256 letterbox to the expected_size letterbox (same padding)
+
     for bbox in boxes.iter_mut() {
       bbox[0] = (bbox[0] as f32 / 256.0 * self.expected_size as f32) as u32;
       bbox[1] = (bbox[1] as f32 / 256.0 * self.expected_size as f32) as u32;
@@ -1,2 +1,5 @@
 pub mod feature_extract;
-pub mod model;
\ No newline at end of file
+pub mod model;
+pub mod classifier;
+pub mod sam;
+pub mod active_learning;
\ No newline at end of file
@@ -1,74 +1,394 @@
 use lazy_static::lazy_static;
 
-use std::sync::OnceLock;
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::Instant;
+use parking_lot::Mutex;
 use ort::{
   execution_providers::{
-    CUDAExecutionProvider, CoreMLExecutionProvider, DirectMLExecutionProvider, ExecutionProvider, TensorRTExecutionProvider
+    CPUExecutionProvider,
+    CUDAExecutionProvider,
+    CoreMLExecutionProvider,
+    DirectMLExecutionProvider,
+    ExecutionProvider,
+    ExecutionProviderDispatch,
+    TensorRTExecutionProvider,
   },
   session::{ builder::GraphOptimizationLevel, Session },
 };
+use serde::{ ser::SerializeStruct, Deserialize, Serialize, Serializer };
 use tauri::{ path::BaseDirectory, Manager };
+use thiserror::Error;
+
+const ENCODER_KEY: &str = "medsam_encoder";
+const DECODER_KEY: &str = "medsam_decoder";
+
+#[derive(Error, Debug)]
+pub enum ModelError {
+  #[error("Model file not found: {0}")]
+  NotFound(String),
+  #[error("Failed to resolve model resource: {0}")]
+  Resource(String),
+  #[error("Failed to load model on any execution provider: {0}")]
+  Load(String),
+  #[error("ONNX Runtime error: {0}")]
+  Ort(#[from] ort::Error),
+  #[error("Inference error: {0}")]
+  Inference(String),
+}
+
+impl ModelError {
+  fn kind(&self) -> &'static str {
+    match self {
+      ModelError::NotFound(_) => "not_found",
+      ModelError::Resource(_) => "resource",
+      ModelError::Load(_) => "load",
+      ModelError::Ort(_) => "ort",
+      ModelError::Inference(_) => "inference",
+    }
+  }
+}
+
+impl From<String> for ModelError {
+  fn from(error: String) -> Self {
+    ModelError::Inference(error)
+  }
+}
+
+// Sent to the frontend as `{ kind, message }` so it can tell a missing model from a failed run.
+impl Serialize for ModelError {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut state = serializer.serialize_struct("ModelError", 2)?;
+    state.serialize_field("kind", self.kind())?;
+    state.serialize_field("message", &self.to_string())?;
+    state.end()
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+  TensorRT,
+  Cuda,
+  DirectML,
+  CoreML,
+  Cpu,
+}
+
+impl Provider {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Provider::TensorRT => "TensorRT",
+      Provider::Cuda => "CUDA",
+      Provider::DirectML => "DirectML",
+      Provider::CoreML => "CoreML",
+      Provider::Cpu => "CPU",
+    }
+  }
+
+  fn is_available(&self) -> bool {
+    let available = match self {
+      Provider::TensorRT => TensorRTExecutionProvider::default().is_available(),
+      Provider::Cuda => CUDAExecutionProvider::default().is_available(),
+      Provider::DirectML => DirectMLExecutionProvider::default().is_available(),
+      Provider::CoreML => CoreMLExecutionProvider::default().is_available(),
+      Provider::Cpu => Ok(true),
+    };
+    available.unwrap_or(false)
+  }
+
+  fn dispatch(&self) -> ExecutionProviderDispatch {
+    let dispatch = match self {
+      Provider::TensorRT => TensorRTExecutionProvider::default().build(),
+      Provider::Cuda => CUDAExecutionProvider::default().build(),
+      Provider::DirectML => DirectMLExecutionProvider::default().build(),
+      Provider::CoreML => CoreMLExecutionProvider::default().build(),
+      Provider::Cpu => CPUExecutionProvider::default().build(),
+    };
+    // Fail loudly so we can move on to the next provider ourselves.
+    dispatch.error_on_failure()
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OptimizationLevel {
+  Disable,
+  Basic,
+  Extended,
+  All,
+}
+
+impl From<OptimizationLevel> for GraphOptimizationLevel {
+  fn from(level: OptimizationLevel) -> Self {
+    match level {
+      OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+      OptimizationLevel::Basic => GraphOptimizationLevel::Level1,
+      OptimizationLevel::Extended => GraphOptimizationLevel::Level2,
+      OptimizationLevel::All => GraphOptimizationLevel::Level3,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionSettings {
+  // Tried in order; CPU is appended if missing so a session can always be built.
+  pub providers: Vec<Provider>,
+  // When unset, each model keeps its own default thread count.
+  pub intra_threads: Option<usize>,
+  // Only used by ONNX Runtime when greater than one (enables parallel execution).
+  pub inter_threads: Option<usize>,
+  pub optimization_level: OptimizationLevel,
+  // Directory where ONNX Runtime writes one JSON trace per model.
+  pub profiling_dir: Option<String>,
+}
+
+impl Default for SessionSettings {
+  fn default() -> Self {
+    SessionSettings {
+      providers: vec![
+        Provider::Cuda,
+        Provider::TensorRT,
+        Provider::DirectML,
+        Provider::CoreML,
+        Provider::Cpu
+      ],
+      intra_threads: None,
+      inter_threads: None,
+      optimization_level: OptimizationLevel::All,
+      profiling_dir: None,
+    }
+  }
+}
+
+impl SessionSettings {
+  fn provider_order(&self) -> Vec<Provider> {
+    let mut providers: Vec<Provider> = Vec::new();
+    for &provider in &self.providers {
+      if !providers.contains(&provider) {
+        providers.push(provider);
+      }
+    }
+    if !providers.contains(&Provider::Cpu) {
+      providers.push(Provider::Cpu);
+    }
+    providers
+  }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ModelStatus {
+  pub model: String,
+  pub path: String,
+  pub loaded: bool,
+  // A session is being built, which can take seconds with provider fallbacks.
+  pub loading: bool,
+  pub provider: Option<String>,
+  pub load_time_ms: Option<f64>,
+  // Why each provider before the active one was skipped.
+  pub fallbacks: Vec<String>,
+  pub error: Option<String>,
+}
+
+struct ModelSlot {
+  session: Option<Arc<Session>>,
+  status: ModelStatus,
+  // Held while the session is built, so concurrent requests wait for one load.
+  loader: Arc<Mutex<()>>,
+}
+
+impl ModelSlot {
+  fn new(key: &str, path: &Path) -> Self {
+    ModelSlot {
+      session: None,
+      status: ModelStatus {
+        model: key.to_string(),
+        path: path.display().to_string(),
+        ..Default::default()
+      },
+      loader: Arc::new(Mutex::new(())),
+    }
+  }
+}
 
 lazy_static! {
-  static ref MODEL_SESSION_ENCODER: OnceLock<Session> = OnceLock::new();
-  static ref MODEL_SESSION_DECODER: OnceLock<Session> = OnceLock::new();
-}
-
-pub fn get_encoder(app: &tauri::AppHandle) -> Result<&'static Session, ort::Error> {
-  Ok(
-    MODEL_SESSION_ENCODER.get_or_init(|| {
-      let resource_path = app
-        .path()
-        .resolve("resources/medsam_encoder.onnx", BaseDirectory::Resource)
-        .unwrap();
-      println!("Cuda execution provider is available: {:?}", CUDAExecutionProvider::default().is_available());
-      Session::builder()
-        .unwrap()
-        .with_optimization_level(GraphOptimizationLevel::Level3)
-        .unwrap()
-        .with_intra_threads(6)
-        .unwrap()
-        .with_execution_providers([
-          // Prefer TensorRT over CUDA.
-          CUDAExecutionProvider::default().build(),
-
-          TensorRTExecutionProvider::default().build(),
-          // Use DirectML on Windows if NVIDIA EPs are not available
-          DirectMLExecutionProvider::default().build(),
-          // Or use ANE on Apple platforms
-          CoreMLExecutionProvider::default().build(),
-        ]).unwrap()
-        .commit_from_file(resource_path)
-        .unwrap()
-    })
-  )
+  // MedSAM encoder/decoder and user-supplied classifiers (keyed by their path on disk).
+  static ref MODEL_SESSIONS: Mutex<HashMap<String, ModelSlot>> = Mutex::new(HashMap::new());
+  static ref SESSION_SETTINGS: Mutex<SessionSettings> = Mutex::new(SessionSettings::default());
+}
+
+fn build_session(
+  key: &str,
+  path: &Path,
+  settings: &SessionSettings,
+  default_intra_threads: usize,
+  provider: Provider
+) -> Result<Session, ort::Error> {
+  let mut builder = Session::builder()?
+    .with_optimization_level(settings.optimization_level.into())?
+    .with_intra_threads(settings.intra_threads.unwrap_or(default_intra_threads))?;
+
+  if let Some(inter_threads) = settings.inter_threads.filter(|&n| n > 1) {
+    builder = builder.with_parallel_execution(true)?.with_inter_threads(inter_threads)?;
+  }
+  if let Some(profiling_dir) = &settings.profiling_dir {
+    std::fs::create_dir_all(profiling_dir).map_err(ort::Error::wrap)?;
+    // Classifiers are keyed by path, only keep the file name for the trace prefix.
+    let prefix = Path::new(key)
+      .file_stem()
+      .map(|stem| stem.to_string_lossy().to_string())
+      .unwrap_or_else(|| key.to_string());
+    builder = builder.with_profiling(Path::new(profiling_dir).join(prefix))?;
+  }
+
+  builder.with_execution_providers([provider.dispatch()])?.commit_from_file(path)
 }
 
-pub fn get_decoder(app: &tauri::AppHandle) -> Result<&'static Session, ort::Error> {
-  Ok(
-    MODEL_SESSION_DECODER.get_or_init(|| {
-      let resource_path = app
-        .path()
-        .resolve("resources/medsam_decoder.onnx", BaseDirectory::Resource)
-        .unwrap();
-      Session::builder()
-        .unwrap()
-        .with_optimization_level(GraphOptimizationLevel::Level3)
-        .unwrap()
-        .with_intra_threads(4)
-        .unwrap()
-        .with_execution_providers([
-          // Prefer TensorRT over CUDA.
-          CUDAExecutionProvider::default().build(),
-
-          TensorRTExecutionProvider::default().build(),
-          // Use DirectML on Windows if NVIDIA EPs are not available
-          DirectMLExecutionProvider::default().build(),
-          // Or use ANE on Apple platforms
-          CoreMLExecutionProvider::default().build(),
-        ]).unwrap()
-        .commit_from_file(resource_path)
-        .unwrap()
-    })
+fn load_session(
+  key: &str,
+  path: &Path,
+  default_intra_threads: usize,
+  status: &mut ModelStatus
+) -> Result<Session, ModelError> {
+  if !path.exists() {
+    return Err(ModelError::NotFound(path.display().to_string()));
+  }
+
+  let settings = SESSION_SETTINGS.lock().clone();
+  let mut last_error = None;
+  for provider in settings.provider_order() {
+    if !provider.is_available() {
+      status.fallbacks.push(format!("{}: not available in this build", provider.name()));
+      continue;
+    }
+    let start = Instant::now();
+    match build_session(key, path, &settings, default_intra_threads, provider) {
+      Ok(session) => {
+        let elapsed = start.elapsed();
+        println!("Loaded {} with {} in {:?}", key, provider.name(), elapsed);
+        status.provider = Some(provider.name().to_string());
+        status.load_time_ms = Some(elapsed.as_secs_f64() * 1000.0);
+        return Ok(session);
+      }
+      Err(e) => {
+        eprintln!("Failed to load {} with {}: {}", key, provider.name(), e);
+        status.fallbacks.push(format!("{}: {}", provider.name(), e));
+        last_error = Some(e);
+      }
+    }
+  }
+
+  Err(
+    ModelError::Load(
+      last_error.map(|e| e.to_string()).unwrap_or_else(|| "no execution provider".to_string())
+    )
   )
 }
+
+// The session map is only locked around lookups, so status and settings requests are not
+// stuck behind a load.
+fn get_or_load(
+  key: &str,
+  path: &Path,
+  default_intra_threads: usize
+) -> Result<Arc<Session>, ModelError> {
+  let loader = {
+    let mut sessions = MODEL_SESSIONS.lock();
+    let slot = sessions.entry(key.to_string()).or_insert_with(|| ModelSlot::new(key, path));
+    if let Some(session) = slot.session.clone() {
+      return Ok(session);
+    }
+    slot.loader.clone()
+  };
+
+  let _loading = loader.lock();
+  {
+    // Another request may have loaded it while this one waited
+    let mut sessions = MODEL_SESSIONS.lock();
+    let slot = sessions.entry(key.to_string()).or_insert_with(|| ModelSlot::new(key, path));
+    if let Some(session) = slot.session.clone() {
+      return Ok(session);
+    }
+    slot.status.loading = true;
+  }
+
+  let mut status = ModelStatus {
+    model: key.to_string(),
+    path: path.display().to_string(),
+    ..Default::default()
+  };
+  let result = load_session(key, path, default_intra_threads, &mut status).map(Arc::new);
+  match &result {
+    Ok(_) => {
+      status.loaded = true;
+    }
+    Err(e) => {
+      status.error = Some(e.to_string());
+    }
+  }
+  let mut sessions = MODEL_SESSIONS.lock();
+  let slot = sessions.entry(key.to_string()).or_insert_with(|| ModelSlot::new(key, path));
+  slot.session = result.as_ref().ok().cloned();
+  slot.status = status;
+  result
+}
+
+fn resolve_resource(app: &tauri::AppHandle, resource: &str) -> Result<PathBuf, ModelError> {
+  app
+    .path()
+    .resolve(resource, BaseDirectory::Resource)
+    .map_err(|e| ModelError::Resource(e.to_string()))
+}
+
+pub fn get_encoder(app: &tauri::AppHandle) -> Result<Arc<Session>, ModelError> {
+  let resource_path = resolve_resource(app, "resources/medsam_encoder.onnx")?;
+  get_or_load(ENCODER_KEY, &resource_path, 6)
+}
+
+pub fn get_decoder(app: &tauri::AppHandle) -> Result<Arc<Session>, ModelError> {
+  let resource_path = resolve_resource(app, "resources/medsam_decoder.onnx")?;
+  get_or_load(DECODER_KEY, &resource_path, 4)
+}
+
+pub fn get_classifier(model_path: &str) -> Result<Arc<Session>, ModelError> {
+  get_or_load(model_path, Path::new(model_path), 4)
+}
+
+/// Drops a classifier session so a retrained file at the same path is picked up.
+/// Its status entry stays, marked as unloaded.
+pub fn unload_classifier(model_path: &str) {
+  if let Some(slot) = MODEL_SESSIONS.lock().get_mut(model_path) {
+    slot.session = None;
+    slot.status.loaded = false;
+  }
+}
+
+pub fn model_statuses() -> Vec<ModelStatus> {
+  let sessions = MODEL_SESSIONS.lock();
+  let mut statuses: Vec<ModelStatus> = sessions
+    .values()
+    .map(|slot| slot.status.clone())
+    .collect();
+  statuses.sort_by(|a, b| a.model.cmp(&b.model));
+  statuses
+}
+
+pub fn session_settings() -> SessionSettings {
+  SESSION_SETTINGS.lock().clone()
+}
+
+// Sessions are rebuilt lazily with the new settings the next time a model is requested.
+// The status entries stay, marked as unloaded, so the last provider and errors are still reported.
+pub fn update_session_settings(settings: SessionSettings) {
+  *SESSION_SETTINGS.lock() = settings;
+  let mut sessions = MODEL_SESSIONS.lock();
+  let mut dropped = 0;
+  for slot in sessions.values_mut() {
+    if slot.session.take().is_some() {
+      dropped += 1;
+    }
+    slot.status.loaded = false;
+  }
+  println!("Session settings changed, dropping {} loaded model(s)", dropped);
+}
@@ -0,0 +1,586 @@
+use base64::{ engine::general_purpose::STANDARD, Engine as _ };
+use ndarray::{ s, Array2, Array3, ArrayD, ArrayView2, Axis, Zip };
+use ort::{ session::Session, value::Tensor };
+use serde::{ Deserialize, Serialize };
+
+use crate::dl::classifier::{ mean_binary_entropy, sigmoid };
+use crate::dl::feature_extract::{ FeaturesExtractor, Letterbox };
+use crate::dl::model::ModelError;
+
+// Merged mask, always exported by the decoder.
+const MASK_OUTPUT: &str = "mask";
+// Optional multi-hypothesis outputs: masks [N, K, H, W] and predicted IoU [N, K].
+const MASKS_OUTPUT: &str = "masks";
+const IOU_OUTPUT: &str = "iou_predictions";
+
+pub struct MaskCandidate {
+  // Decoder output mapped back onto the image grid.
+  pub probabilities: Array2<f32>,
+  // Predicted IoU, only known when the decoder exports its hypotheses.
+  pub score: Option<f32>,
+}
+
+/// Whether the decoder exports its mask hypotheses and their predicted IoU, the bundled
+/// MedSAM decoder only exports the merged mask.
+pub fn supports_hypotheses(decoder: &Session) -> bool {
+  let has_output = |name: &str| decoder.outputs.iter().any(|output| output.name == name);
+  has_output(MASKS_OUTPUT) && has_output(IOU_OUTPUT)
+}
+
+struct DecoderOutput {
+  // [N, K, H, W] with hypotheses, otherwise the merged mask (last two axes are H, W).
+  masks: ArrayD<f32>,
+  // [N, K], only with hypotheses.
+  ious: Option<ArrayD<f32>>,
+}
+
+// Decoders exported without their final sigmoid return logits, which no probability map
+// has outside [0, 1]
+fn into_probabilities(mut masks: ArrayD<f32>) -> ArrayD<f32> {
+  if masks.iter().any(|&v| !(0.0..=1.0).contains(&v)) {
+    masks.mapv_inplace(sigmoid);
+  }
+  masks
+}
+
+fn run_decoder(
+  decoder: &Session,
+  features_extractor: &FeaturesExtractor,
+  bbox_array: Array3<f32>,
+  hypotheses: bool
+) -> Result<DecoderOutput, ModelError> {
+  let bbox_tensor = Tensor::from_array(bbox_array)?;
+
+  let mut decoder_binding = decoder.create_binding()?;
+  decoder_binding.bind_input("features", features_extractor.get_features())?;
+  decoder_binding.bind_input("bbox", &bbox_tensor)?;
+  if hypotheses {
+    decoder_binding.bind_output_to_device(MASKS_OUTPUT, &decoder.allocator().memory_info())?;
+    decoder_binding.bind_output_to_device(IOU_OUTPUT, &decoder.allocator().memory_info())?;
+  } else {
+    decoder_binding.bind_output_to_device(MASK_OUTPUT, &decoder.allocator().memory_info())?;
+  }
+  decoder_binding.synchronize_inputs()?;
+  let mut outputs = decoder_binding.run()?;
+
+  if !hypotheses {
+    let binding = outputs
+      .remove(MASK_OUTPUT)
+      .ok_or(ModelError::Inference("Decoder did not return a mask".to_string()))?;
+    let masks = into_probabilities(binding.try_extract_tensor::<f32>()?.to_owned());
+    return Ok(DecoderOutput { masks, ious: None });
+  }
+
+  let masks_binding = outputs
+    .remove(MASKS_OUTPUT)
+    .ok_or(ModelError::Inference("Decoder did not return its masks".to_string()))?;
+  let iou_binding = outputs
+    .remove(IOU_OUTPUT)
+    .ok_or(ModelError::Inference("Decoder did not return IoU predictions".to_string()))?;
+  Ok(DecoderOutput {
+    masks: into_probabilities(masks_binding.try_extract_tensor::<f32>()?.to_owned()),
+    ious: Some(iou_binding.try_extract_tensor::<f32>()?.to_owned()),
+  })
+}
+
+/// Runs the decoder on the cached image embedding for a (N, 1, 4) array of boxes.
+/// With `multimask` and a decoder exporting its hypotheses (see `supports_hypotheses`),
+/// returns one candidate per hypothesis (merged over the boxes), otherwise a single candidate.
+pub fn decode_boxes(
+  decoder: &Session,
+  features_extractor: &FeaturesExtractor,
+  bbox_array: Array3<f32>,
+  width: usize,
+  height: usize,
+  multimask: bool
+) -> Result<Vec<MaskCandidate>, ModelError> {
+  let hypotheses = multimask && supports_hypotheses(decoder);
+  println!("Running decoder inference");
+  let output = run_decoder(decoder, features_extractor, bbox_array, hypotheses)?;
+
+  let ious = match output.ious {
+    Some(ious) => ious,
+    None => {
+      return Ok(
+        vec![MaskCandidate {
+          probabilities: features_extractor.restore_mask(&output.masks.view(), width, height)?,
+          score: None,
+        }]
+      );
+    }
+  };
+
+  let num_boxes = output.masks.shape()[0];
+  let num_hypotheses = output.masks.shape()[1];
+  let mut candidates = Vec::with_capacity(num_hypotheses);
+  for k in 0..num_hypotheses {
+    // Union of the boxes for this hypothesis
+    let merged = output.masks
+      .index_axis(Axis(1), k)
+      .fold_axis(Axis(0), f32::NEG_INFINITY, |&acc, &v| acc.max(v));
+    let score =
+      (0..num_boxes).map(|n| ious[[n, k]]).sum::<f32>() / (num_boxes.max(1) as f32);
+    candidates.push(MaskCandidate {
+      probabilities: features_extractor.restore_mask(&merged.view(), width, height)?,
+      score: Some(score),
+    });
+  }
+  candidates.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+  Ok(candidates)
+}
+
+/// Decoder probabilities for one box given in image pixels [xmin, ymin, xmax, ymax],
+/// restored for the image region [xmin, ymin, xmax, ymax) only.
+pub fn decode_box_in_region(
+  decoder: &Session,
+  features_extractor: &FeaturesExtractor,
+  bbox: [f32; 4],
+  region: [usize; 4]
+) -> Result<Array2<f32>, ModelError> {
+  let scale = features_extractor.letterbox().scale;
+  let bbox_array = Array3::from_shape_fn((1, 1, 4), |(_, _, c)| bbox[c] * scale);
+  let output = run_decoder(decoder, features_extractor, bbox_array, false)?;
+  features_extractor.restore_mask_crop(&output.masks.view(), region)
+}
+
+/// How much the decoder hesitates on a box: 1 - mean pairwise IoU of its hypotheses, or
+/// the mean binary entropy of its mask when it does not export hypotheses.
+pub fn hypothesis_disagreement(
+  decoder: &Session,
+  features_extractor: &FeaturesExtractor,
+  bbox: [f32; 4]
+) -> Result<f32, ModelError> {
+  let bbox_array = Array3::from_shape_fn((1, 1, 4), |(_, _, c)| bbox[c]);
+  let hypotheses = supports_hypotheses(decoder);
+  let output = run_decoder(decoder, features_extractor, bbox_array, hypotheses)?;
+
+  if output.ious.is_none() {
+    let probabilities: Vec<f32> = output.masks.iter().copied().collect();
+    return Ok(mean_binary_entropy(&probabilities));
+  }
+
+  let num_hypotheses = output.masks.shape()[1];
+  let binary: Vec<Vec<bool>> = (0..num_hypotheses)
+    .map(|k| {
+      output.masks
+        .slice(s![0, k, .., ..])
+        .iter()
+        .map(|&p| p > 0.5)
+        .collect()
+    })
+    .collect();
+
+  let mut total = 0.0f32;
+  let mut pairs = 0usize;
+  for a in 0..num_hypotheses {
+    for b in a + 1..num_hypotheses {
+      let (inter, union) = binary[a]
+        .iter()
+        .zip(binary[b].iter())
+        .fold((0usize, 0usize), |(i, u), (&pa, &pb)| (i + ((pa && pb) as usize), u + ((pa || pb) as usize)));
+      total += if union > 0 { (inter as f32) / (union as f32) } else { 1.0 };
+      pairs += 1;
+    }
+  }
+  Ok(if pairs > 0 { 1.0 - total / (pairs as f32) } else { 0.0 })
+}
+
+fn default_box_scales() -> Vec<f32> {
+  vec![0.05, 0.1, 0.2]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AutoMaskSettings {
+  // Prompts are centred on a points_per_side x points_per_side grid.
+  pub points_per_side: usize,
+  // Side of the prompt boxes, as a fraction of the longest image side.
+  #[serde(default = "default_box_scales")]
+  pub box_scales: Vec<f32>,
+  pub batch_size: usize,
+  pub mask_threshold: f32,
+  pub pred_iou_threshold: f32,
+  pub stability_threshold: f32,
+  pub stability_offset: f32,
+  pub nms_threshold: f32,
+  // In image pixels.
+  pub min_area: usize,
+}
+
+impl Default for AutoMaskSettings {
+  fn default() -> Self {
+    AutoMaskSettings {
+      points_per_side: 16,
+      box_scales: default_box_scales(),
+      batch_size: 64,
+      mask_threshold: 0.5,
+      pred_iou_threshold: 0.8,
+      stability_threshold: 0.9,
+      stability_offset: 0.05,
+      nms_threshold: 0.7,
+      min_area: 0,
+    }
+  }
+}
+
+pub struct AutoMask {
+  pub score: f32,
+  pub stability: f32,
+  // [xmin, ymin, xmax, ymax) in image coordinates
+  pub bbox: [usize; 4],
+  // Probabilities inside `bbox` only
+  pub probabilities: Array2<f32>,
+}
+
+// Candidate at decoder resolution, kept until non-maximum suppression.
+struct LowResMask {
+  score: f32,
+  stability: f32,
+  bbox: [usize; 4],
+  // Whole decoder output, quantised to keep memory down
+  mask: Array2<u8>,
+}
+
+fn prompt_grid(letterbox: Letterbox, settings: &AutoMaskSettings) -> Vec<[f32; 4]> {
+  let w = letterbox.resized_width as f32;
+  let h = letterbox.resized_height as f32;
+  let n = settings.points_per_side.max(1);
+  let mut prompts = Vec::new();
+  for j in 0..n {
+    for i in 0..n {
+      let cx = ((i as f32) + 0.5) / (n as f32) * w;
+      let cy = ((j as f32) + 0.5) / (n as f32) * h;
+      for &scale in &settings.box_scales {
+        let half = scale * w.max(h) / 2.0;
+        prompts.push([
+          (cx - half).max(0.0),
+          (cy - half).max(0.0),
+          (cx + half).min(w),
+          (cy + half).min(h),
+        ]);
+      }
+    }
+  }
+  prompts
+}
+
+fn box_iou(a: &[usize; 4], b: &[usize; 4]) -> f32 {
+  let ix = a[2].min(b[2]).saturating_sub(a[0].max(b[0]));
+  let iy = a[3].min(b[3]).saturating_sub(a[1].max(b[1]));
+  let inter = (ix * iy) as f32;
+  let area_a = ((a[2] - a[0]) * (a[3] - a[1])) as f32;
+  let area_b = ((b[2] - b[0]) * (b[3] - b[1])) as f32;
+  let union = area_a + area_b - inter;
+  if union > 0.0 { inter / union } else { 0.0 }
+}
+
+/// Greedy non-maximum suppression on boxes, highest score first. Returns kept indices.
+pub fn non_max_suppression(boxes: &[[usize; 4]], scores: &[f32], threshold: f32) -> Vec<usize> {
+  let mut order: Vec<usize> = (0..boxes.len()).collect();
+  order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+  let mut kept: Vec<usize> = Vec::new();
+  for i in order {
+    if kept.iter().all(|&k| box_iou(&boxes[i], &boxes[k]) <= threshold) {
+      kept.push(i);
+    }
+  }
+  kept
+}
+
+// Scores one decoder mask restricted to the letterboxed image area.
+fn low_res_candidate(
+  mask: ArrayView2<f32>,
+  valid: (usize, usize),
+  score: Option<f32>,
+  settings: &AutoMaskSettings
+) -> Option<LowResMask> {
+  let cropped = mask.slice(s![..valid.1, ..valid.0]);
+  let high = settings.mask_threshold + settings.stability_offset;
+  let low = settings.mask_threshold - settings.stability_offset;
+
+  let mut area = 0usize;
+  let mut area_high = 0usize;
+  let mut area_low = 0usize;
+  let mut sum = 0.0f32;
+  let mut bbox = [usize::MAX, usize::MAX, 0, 0];
+  for ((y, x), &p) in cropped.indexed_iter() {
+    if p > low {
+      area_low += 1;
+    }
+    if p > high {
+      area_high += 1;
+    }
+    if p > settings.mask_threshold {
+      area += 1;
+      sum += p;
+      bbox = [bbox[0].min(x), bbox[1].min(y), bbox[2].max(x + 1), bbox[3].max(y + 1)];
+    }
+  }
+  if area == 0 {
+    return None;
+  }
+
+  // The high-threshold mask is inside the low one, so their IoU is a ratio of areas
+  let stability = (area_high as f32) / (area_low as f32);
+  let score = score.unwrap_or(sum / (area as f32));
+  if score < settings.pred_iou_threshold || stability < settings.stability_threshold {
+    return None;
+  }
+
+  Some(LowResMask {
+    score,
+    stability,
+    bbox,
+    mask: mask.map(|&p| (p.clamp(0.0, 1.0) * 255.0).round() as u8),
+  })
+}
+
+/// "Segment everything": prompts the decoder with a grid of boxes over the whole image and
+/// keeps the confident, stable and non-overlapping masks.
+pub fn generate_masks(
+  decoder: &Session,
+  features_extractor: &FeaturesExtractor,
+  width: usize,
+  height: usize,
+  settings: &AutoMaskSettings
+) -> Result<Vec<AutoMask>, ModelError> {
+  let hypotheses = supports_hypotheses(decoder);
+  let mut batch_size = settings.batch_size.max(1);
+  let letterbox = features_extractor.letterbox();
+  let prompts = prompt_grid(letterbox, settings);
+  println!("Automatic masks: {} prompts in batches of {}", prompts.len(), batch_size);
+
+  let mut candidates: Vec<LowResMask> = Vec::new();
+  let mut ratio = 1.0f32;
+  let mut start = 0;
+  while start < prompts.len() {
+    let batch = &prompts[start..(start + batch_size).min(prompts.len())];
+    let bbox_array = Array3::from_shape_fn((batch.len(), 1, 4), |(n, _, c)| batch[n][c]);
+    let output = run_decoder(decoder, features_extractor, bbox_array, hypotheses)?;
+
+    let shape = output.masks.shape().to_vec();
+    // Some decoders merge the boxes of a batch into one mask, those get one prompt at a time
+    if batch.len() > 1 && (shape.len() < 3 || shape[0] != batch.len()) {
+      println!("Automatic masks: the decoder merges its boxes, decoding them one by one");
+      batch_size = 1;
+      continue;
+    }
+    start += batch.len();
+
+    let (out_h, out_w) = (shape[shape.len() - 2], shape[shape.len() - 1]);
+    ratio = (out_w as f32) / (features_extractor.expected_size() as f32);
+    let valid = (
+      (((letterbox.resized_width as f32) * ratio).round() as usize).clamp(1, out_w),
+      (((letterbox.resized_height as f32) * ratio).round() as usize).clamp(1, out_h),
+    );
+
+    match &output.ious {
+      Some(ious) => {
+        for n in 0..shape[0] {
+          for k in 0..shape[1] {
+            let mask = output.masks.slice(s![n, k, .., ..]);
+            if let Some(c) = low_res_candidate(mask, valid, Some(ious[[n, k]]), settings) {
+              candidates.push(c);
+            }
+          }
+        }
+      }
+      None => {
+        for n in 0..batch.len() {
+          let mask = if batch.len() == 1 {
+            output.masks.view()
+          } else {
+            output.masks.index_axis(Axis(0), n)
+          };
+          let mask = mask
+            .into_shape_with_order((out_h, out_w))
+            .map_err(|e| ModelError::Inference(e.to_string()))?;
+          if let Some(c) = low_res_candidate(mask, valid, None, settings) {
+            candidates.push(c);
+          }
+        }
+      }
+    }
+  }
+
+  let boxes: Vec<[usize; 4]> = candidates
+    .iter()
+    .map(|c| c.bbox)
+    .collect();
+  let scores: Vec<f32> = candidates
+    .iter()
+    .map(|c| c.score)
+    .collect();
+  let kept = non_max_suppression(&boxes, &scores, settings.nms_threshold);
+  println!("Automatic masks: {} candidates, {} after NMS", candidates.len(), kept.len());
+
+  // Decoder pixels to image pixels
+  let to_image = 1.0 / (ratio * letterbox.scale);
+  let mut masks = Vec::with_capacity(kept.len());
+  for i in kept {
+    let candidate = &candidates[i];
+    let bbox = [
+      ((candidate.bbox[0] as f32) * to_image).floor() as usize,
+      ((candidate.bbox[1] as f32) * to_image).floor() as usize,
+      (((candidate.bbox[2] as f32) * to_image).ceil() as usize).min(width),
+      (((candidate.bbox[3] as f32) * to_image).ceil() as usize).min(height),
+    ];
+    if bbox[2] <= bbox[0] || bbox[3] <= bbox[1] {
+      continue;
+    }
+    let soft = candidate.mask.map(|&v| (v as f32) / 255.0).into_dyn();
+    let probabilities = features_extractor.restore_mask_crop(&soft.view(), bbox)?;
+    let area = probabilities
+      .iter()
+      .filter(|&&p| p > settings.mask_threshold)
+      .count();
+    if area == 0 || area < settings.min_area {
+      continue;
+    }
+    masks.push(AutoMask {
+      score: candidate.score,
+      stability: candidate.stability,
+      bbox,
+      probabilities,
+    });
+  }
+  Ok(masks)
+}
+
+/// Quantises a probability map (decoder output after `into_probabilities`) to one byte per pixel.
+pub fn quantise_probabilities(probabilities: &Array2<f32>) -> Vec<u8> {
+  probabilities
+    .iter()
+    .map(|&p| (p.clamp(0.0, 1.0) * 255.0).round() as u8)
+    .collect()
+}
+
+/// Per-pixel owner of overlapping instance masks; the highest scoring instance wins.
+pub struct InstanceMap {
+  // 0 is background, instances start at 1.
+  pub owners: Array2<u16>,
+  scores: Array2<f32>,
+}
+
+impl InstanceMap {
+  pub fn new(width: usize, height: usize) -> Self {
+    InstanceMap {
+      owners: Array2::zeros((height, width)),
+      scores: Array2::from_elem((height, width), f32::NEG_INFINITY),
+    }
+  }
+
+  pub fn claim(&mut self, id: u16, probabilities: &Array2<f32>, threshold: f32, score: f32) {
+    Zip::from(&mut self.owners)
+      .and(&mut self.scores)
+      .and(probabilities)
+      .for_each(|owner, best, &p| {
+        if p > threshold && score > *best {
+          *owner = id;
+          *best = score;
+        }
+      });
+  }
+}
+
+/// Confidence used when the decoder does not predict IoU: mean probability inside the mask.
+pub fn mean_foreground_probability(probabilities: &Array2<f32>, threshold: f32) -> f32 {
+  let (sum, count) = probabilities
+    .iter()
+    .filter(|&&p| p > threshold)
+    .fold((0.0f32, 0usize), |(sum, count), &p| (sum + p, count + 1));
+  if count == 0 { 0.0 } else { sum / (count as f32) }
+}
+
+/// Same shading as the frontend `generate_shades`: the i-th of n shades of a class colour.
+pub fn instance_shade(color: [u8; 4], index: usize, count: usize) -> [u8; 4] {
+  let factor = 1.0 - (index as f32) / (count.max(1) as f32);
+  [
+    ((color[0] as f32) * factor) as u8,
+    ((color[1] as f32) * factor) as u8,
+    ((color[2] as f32) * factor) as u8,
+    color[3],
+  ]
+}
+
+#[derive(Serialize)]
+pub struct Instance {
+  pub id: u32,
+  // Decoder confidence, only for SAM instances
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub score: Option<f32>,
+  pub color: [u8; 4],
+  pub area: usize,
+  // [xmin, ymin, xmax, ymax] in image coordinates, inclusive
+  pub bbox: [usize; 4],
+}
+
+#[derive(Serialize)]
+pub struct Instances {
+  pub width: usize,
+  pub height: usize,
+  pub instances: Vec<Instance>,
+  // Base64 RGBA mask, each instance painted with its own shade
+  pub mask: String,
+}
+
+/// Instances of a label map where 0 is background and 1..=`count` are instances. Empty
+/// labels are dropped, the rest renumbered 1..=n and painted with the given `shades`, or
+/// with shades of `color`. `scores` holds one score per label, starting at label 1.
+pub fn collect_instances(
+  labels: &[u32],
+  width: usize,
+  height: usize,
+  count: usize,
+  scores: Option<&[f32]>,
+  color: [u8; 4],
+  shades: Option<&[[u8; 4]]>
+) -> Instances {
+  let mut areas = vec![0usize; count + 1];
+  let mut bboxes = vec![[usize::MAX, usize::MAX, 0, 0]; count + 1];
+  for (i, &label) in labels.iter().enumerate() {
+    if label == 0 {
+      continue;
+    }
+    let (x, y) = (i % width, i / width);
+    let label = label as usize;
+    areas[label] += 1;
+    let bbox = &mut bboxes[label];
+    bbox[0] = bbox[0].min(x);
+    bbox[1] = bbox[1].min(y);
+    bbox[2] = bbox[2].max(x);
+    bbox[3] = bbox[3].max(y);
+  }
+
+  // Renumber the non-empty instances 1..=n and give each its shade
+  let mut new_ids = vec![0u32; count + 1];
+  let mut instances = Vec::new();
+  let kept = (1..=count).filter(|&id| areas[id] > 0).count();
+  for id in 1..=count {
+    if areas[id] == 0 {
+      continue;
+    }
+    let index = instances.len();
+    let shade = shades
+      .and_then(|shades| shades.get(index).copied())
+      .unwrap_or_else(|| instance_shade(color, index, kept));
+    new_ids[id] = (index + 1) as u32;
+    instances.push(Instance {
+      id: (index + 1) as u32,
+      score: scores.and_then(|scores| scores.get(id - 1).copied()),
+      color: shade,
+      area: areas[id],
+      bbox: bboxes[id],
+    });
+  }
+
+  let mut mask = vec![0u8; width * height * 4];
+  for (i, &label) in labels.iter().enumerate() {
+    let id = new_ids[label as usize];
+    if id > 0 {
+      mask[4 * i..4 * i + 4].copy_from_slice(&instances[(id - 1) as usize].color);
+    }
+  }
+
+  Instances { width, height, instances, mask: STANDARD.encode(mask) }
+}
@@ -5,6 +5,10 @@ mod tools;
 mod commands;
 mod connection;
 
+// For benches/crf.rs
+#[doc(hidden)]
+pub use tools::dense_crf::refine_mask;
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
   tauri::Builder
@@ -12,6 +16,8 @@ pub fn run() {
     .plugin(tauri_plugin_fs::init())
     .plugin(tauri_plugin_dialog::init())
     .manage(Arc::new(Mutex::new(dl::feature_extract::FeaturesExtractor::new())))
+    .manage(Arc::new(Mutex::new(dl::active_learning::ActiveLearningQueue::new())))
+    .manage(Arc::new(Mutex::new(tools::field_of_view::FieldOfViewCache::new())))
 
     .setup(|app| {
       connection::coms::setup_zmq_receiver(app.handle().clone())?;
@@ -23,13 +29,41 @@ pub fn run() {
         commands::images::load_image_as_base64,
         commands::images::process_image_blob,
         commands::segmentation::otsu_segmentation,
+        commands::segmentation::cluster_segmentation,
+        commands::segmentation::mask_morphology,
         commands::segmentation::edge_detection,
         commands::segmentation::find_overlapping_region,
+        commands::segmentation::mask_boolean,
         commands::segmentation::get_overlapping_region_with_mask,
+        commands::segmentation::magic_wand,
+        commands::segmentation::grabcut_segment,
+        commands::segmentation::scribble_segment,
+        commands::segmentation::watershed_instances,
+        commands::vessels::vessel_enhancement,
+        commands::vessels::vessel_segmentation,
+        commands::vessels::extract_vessel_graph,
+        commands::polygons::mask_to_polygons,
+        commands::polygons::polygons_to_mask,
+        commands::field_of_view::detect_field_of_view,
+        commands::field_of_view::clear_field_of_view,
         connection::connection::event_processed,
         commands::crf::crf_refine,
+        commands::crf::crf_refine_multiclass,
         commands::segmentation::get_quad_tree_bbox,
         commands::dl::sam_segment,
+        commands::dl::sam_predict,
+        commands::dl::sam_segment_instances,
+        commands::dl::sam_segment_everything,
+        commands::dl::sam_propagate,
+        commands::dl::threshold_probability_map,
+        commands::dl::classify_multiclass,
+        commands::dl::classify_multilabel,
+        commands::dl::model_status,
+        commands::dl::get_session_settings,
+        commands::dl::set_session_settings,
+        commands::active_learning::rank_images,
+        commands::active_learning::get_active_learning_queue,
+        commands::active_learning::mark_image_annotated,
         commands::io::save_json_file,
         commands::io::load_json_file,
         commands::io::save_xml_file,
@@ -0,0 +1,91 @@
+use serde::{ Deserialize, Serialize };
+
+use crate::tools::color::rgb_to_lab;
+use crate::tools::gmm::{ kmeans, Gmm };
+use crate::tools::thresholding::{ histogram, multi_otsu };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterMethod {
+  // Multi-level Otsu thresholds on luminance
+  MultiOtsu,
+  // Lloyd's k-means on colour
+  KMeans,
+  // Gaussian mixture on colour, each pixel goes to its most likely component
+  Gmm,
+}
+
+// Same weights as image's luma conversion
+fn luminance(rgb: &[f32; 3]) -> f32 {
+  0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2]
+}
+
+/// Groups `colors` (RGB, 0-255) into at most `classes` clusters and returns the cluster of each
+/// colour with the cluster count. Clusters are numbered from darkest to brightest mean
+/// luminance, and `lab` runs k-means and the GMM in CIE Lab instead of RGB.
+pub fn cluster_colors(
+  colors: &[[f32; 3]],
+  method: ClusterMethod,
+  classes: usize,
+  lab: bool
+) -> (Vec<usize>, usize) {
+  let classes = classes.max(1);
+  let features: Vec<[f32; 3]> = if lab {
+    colors
+      .iter()
+      .map(|&rgb| rgb_to_lab(rgb))
+      .collect()
+  } else {
+    colors.to_vec()
+  };
+  let labels: Vec<usize> = match method {
+    ClusterMethod::MultiOtsu => {
+      let luma: Vec<u8> = colors
+        .iter()
+        .map(|rgb| luminance(rgb).round().clamp(0.0, 255.0) as u8)
+        .collect();
+      let thresholds = multi_otsu(&histogram(luma.iter().copied()), classes);
+      luma
+        .iter()
+        .map(|&v| thresholds.iter().filter(|&&t| v > t).count())
+        .collect()
+    }
+    ClusterMethod::KMeans => kmeans(&features, classes, 20),
+    ClusterMethod::Gmm => {
+      let mut gmm = Gmm::fit(&features, classes);
+      for _ in 0..5 {
+        gmm = gmm.refine(&features);
+      }
+      features
+        .iter()
+        .map(|x| gmm.component(x))
+        .collect()
+    }
+  };
+
+  // Renumber the non-empty clusters by mean luminance
+  let count = labels.iter().copied().max().map_or(0, |max| max + 1);
+  let mut sums = vec![0f64; count];
+  let mut sizes = vec![0usize; count];
+  for (rgb, &label) in colors.iter().zip(labels.iter()) {
+    sums[label] += luminance(rgb) as f64;
+    sizes[label] += 1;
+  }
+  let mut order: Vec<usize> = (0..count).filter(|&c| sizes[c] > 0).collect();
+  order.sort_by(|&a, &b| {
+    let ma = sums[a] / (sizes[a] as f64);
+    let mb = sums[b] / (sizes[b] as f64);
+    ma.total_cmp(&mb)
+  });
+  let mut rank = vec![0usize; count];
+  for (r, &c) in order.iter().enumerate() {
+    rank[c] = r;
+  }
+  (
+    labels
+      .iter()
+      .map(|&label| rank[label])
+      .collect(),
+    order.len(),
+  )
+}
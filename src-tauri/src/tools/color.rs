@@ -0,0 +1,241 @@
+use serde::{ Deserialize, Serialize };
+
+// D65 reference white
+const WHITE: [f32; 3] = [0.95047, 1.0, 1.08883];
+
+fn srgb_to_linear(v: f32) -> f32 {
+  if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+fn lab_f(t: f32) -> f32 {
+  if t > 0.008856 { t.cbrt() } else { 7.787 * t + 16.0 / 116.0 }
+}
+
+/// sRGB (0-255) to CIE Lab: L in [0, 100], a and b roughly in [-128, 127].
+pub fn rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+  let [r, g, b] = rgb.map(|v| srgb_to_linear(v / 255.0));
+  let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / WHITE[0];
+  let y = (0.2126 * r + 0.7152 * g + 0.0722 * b) / WHITE[1];
+  let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / WHITE[2];
+  let (fx, fy, fz) = (lab_f(x), lab_f(y), lab_f(z));
+  [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// sRGB (0-255) to HSV: hue in degrees [0, 360), saturation and value in [0, 1].
+pub fn rgb_to_hsv(rgb: [f32; 3]) -> [f32; 3] {
+  let [r, g, b] = rgb.map(|v| v / 255.0);
+  let max = r.max(g).max(b);
+  let min = r.min(g).min(b);
+  let delta = max - min;
+  let hue = if delta == 0.0 {
+    0.0
+  } else if max == r {
+    60.0 * ((g - b) / delta).rem_euclid(6.0)
+  } else if max == g {
+    60.0 * ((b - r) / delta + 2.0)
+  } else {
+    60.0 * ((r - g) / delta + 4.0)
+  };
+  let saturation = if max == 0.0 { 0.0 } else { delta / max };
+  [hue, saturation, max]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StainMethod {
+  // Fixed H&E optical densities measured by Ruifrok & Johnston
+  #[default]
+  Ruifrok,
+  // Stain vectors estimated from the image itself (Macenko et al. 2009)
+  Macenko,
+}
+
+// Hematoxylin and eosin optical density vectors, from Ruifrok & Johnston (2001)
+pub const RUIFROK_HE: [[f32; 3]; 2] = [
+  [0.65, 0.7, 0.29],
+  [0.07, 0.99, 0.11],
+];
+
+// Optical density below which a pixel counts as background glass for Macenko
+const TRANSPARENT_OD: f32 = 0.15;
+// Percentile of the stain angles taken as the extreme stain directions
+const MACENKO_PERCENTILE: f32 = 0.01;
+
+/// Beer-Lambert optical density of an RGB (0-255) colour.
+pub fn optical_density(rgb: [f32; 3]) -> [f32; 3] {
+  rgb.map(|v| -((v + 1.0) / 256.0).log10())
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+  let norm = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt().max(1e-12);
+  v.map(|c| c / norm)
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+  [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+// Eigenvectors of a symmetric 3x3 matrix (as columns), sorted by decreasing eigenvalue,
+// with cyclic Jacobi rotations.
+fn symmetric_eigenvectors(matrix: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+  let mut a = matrix;
+  let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+  for _ in 0..50 {
+    let off = a[0][1].abs() + a[0][2].abs() + a[1][2].abs();
+    if off < 1e-12 {
+      break;
+    }
+    for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+      if a[p][q].abs() < 1e-15 {
+        continue;
+      }
+      let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+      let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+      let (c, s) = (1.0 / (t * t + 1.0).sqrt(), t / (t * t + 1.0).sqrt());
+      for row in a.iter_mut() {
+        let (akp, akq) = (row[p], row[q]);
+        row[p] = c * akp - s * akq;
+        row[q] = s * akp + c * akq;
+      }
+      let (ap, aq) = (a[p], a[q]);
+      a[p] = std::array::from_fn(|k| c * ap[k] - s * aq[k]);
+      a[q] = std::array::from_fn(|k| s * ap[k] + c * aq[k]);
+      for row in v.iter_mut() {
+        let (vkp, vkq) = (row[p], row[q]);
+        row[p] = c * vkp - s * vkq;
+        row[q] = s * vkp + c * vkq;
+      }
+    }
+  }
+  let mut order = [0usize, 1, 2];
+  order.sort_by(|&i, &j| a[j][j].total_cmp(&a[i][i]));
+  let mut sorted = [[0f64; 3]; 3];
+  for (column, &i) in order.iter().enumerate() {
+    for k in 0..3 {
+      sorted[k][column] = v[k][i];
+    }
+  }
+  sorted
+}
+
+/// Macenko's H&E estimate from the optical densities of the stained pixels: the plane of
+/// the two main eigenvectors, and the extreme angles of the densities within it.
+/// Returns [hematoxylin, eosin], or None when there is too little tissue.
+pub fn macenko_stains(densities: &[[f32; 3]]) -> Option<[[f32; 3]; 2]> {
+  let tissue: Vec<[f32; 3]> = densities
+    .iter()
+    .filter(|od| od.iter().all(|&v| v > TRANSPARENT_OD))
+    .copied()
+    .collect();
+  if tissue.len() < 100 {
+    return None;
+  }
+
+  let n = tissue.len() as f64;
+  let mut mean = [0f64; 3];
+  for od in &tissue {
+    for c in 0..3 {
+      mean[c] += (od[c] as f64) / n;
+    }
+  }
+  let mut covariance = [[0f64; 3]; 3];
+  for od in &tissue {
+    for i in 0..3 {
+      for j in 0..3 {
+        covariance[i][j] += ((od[i] as f64) - mean[i]) * ((od[j] as f64) - mean[j]) / n;
+      }
+    }
+  }
+  let vectors = symmetric_eigenvectors(covariance);
+  // Densities are positive, orient the plane axes the same way
+  let axis = |column: usize| -> [f32; 3] {
+    let v = [vectors[0][column], vectors[1][column], vectors[2][column]].map(|v| v as f32);
+    if v.iter().sum::<f32>() < 0.0 { v.map(|c| -c) } else { v }
+  };
+  let (first, second) = (axis(0), axis(1));
+  let dot = |a: &[f32; 3], b: &[f32; 3]| a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+
+  let mut angles: Vec<f32> = tissue
+    .iter()
+    .map(|od| dot(od, &second).atan2(dot(od, &first)))
+    .collect();
+  angles.sort_by(|a, b| a.total_cmp(b));
+  let low = angles[((angles.len() as f32) * MACENKO_PERCENTILE) as usize];
+  let high = angles[(((angles.len() as f32) * (1.0 - MACENKO_PERCENTILE)) as usize).min(angles.len() - 1)];
+  let direction = |angle: f32| -> [f32; 3] {
+    normalize([0, 1, 2].map(|c| angle.cos() * first[c] + angle.sin() * second[c]))
+  };
+  let (a, b) = (direction(low), direction(high));
+  // Hematoxylin absorbs more red than eosin
+  Some(if a[0] > b[0] { [a, b] } else { [b, a] })
+}
+
+/// Matrix turning optical densities into [stain 1, stain 2, residual] concentrations.
+pub fn unmixing_matrix(stains: [[f32; 3]; 2]) -> [[f32; 3]; 3] {
+  let (h, e) = (normalize(stains[0]), normalize(stains[1]));
+  let residual = normalize(cross(h, e));
+  // Concentrations c solve od = c0 h + c1 e + c2 r, so invert the matrix of stain columns
+  let m = [
+    [h[0], e[0], residual[0]],
+    [h[1], e[1], residual[1]],
+    [h[2], e[2], residual[2]],
+  ];
+  let det =
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) -
+    m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) +
+    m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+  let det = if det.abs() < 1e-12 { 1e-12 } else { det };
+  [
+    [
+      (m[1][1] * m[2][2] - m[1][2] * m[2][1]) / det,
+      (m[0][2] * m[2][1] - m[0][1] * m[2][2]) / det,
+      (m[0][1] * m[1][2] - m[0][2] * m[1][1]) / det,
+    ],
+    [
+      (m[1][2] * m[2][0] - m[1][0] * m[2][2]) / det,
+      (m[0][0] * m[2][2] - m[0][2] * m[2][0]) / det,
+      (m[0][2] * m[1][0] - m[0][0] * m[1][2]) / det,
+    ],
+    [
+      (m[1][0] * m[2][1] - m[1][1] * m[2][0]) / det,
+      (m[0][1] * m[2][0] - m[0][0] * m[2][1]) / det,
+      (m[0][0] * m[1][1] - m[0][1] * m[1][0]) / det,
+    ],
+  ]
+}
+
+/// Stain concentrations of one optical density with an unmixing matrix.
+pub fn stain_concentrations(od: [f32; 3], unmixing: &[[f32; 3]; 3]) -> [f32; 3] {
+  [0, 1, 2].map(|i| unmixing[i][0] * od[0] + unmixing[i][1] * od[1] + unmixing[i][2] * od[2])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn eigenvectors_of_a_symmetric_matrix() {
+    let matrix = [[4.0, 1.0, 0.5], [1.0, 3.0, 0.2], [0.5, 0.2, 1.0]];
+    let vectors = symmetric_eigenvectors(matrix);
+    let mut previous = f64::INFINITY;
+    for v in (0..3).map(|column| [vectors[0][column], vectors[1][column], vectors[2][column]]) {
+      let product: Vec<f64> = (0..3).map(|r| (0..3).map(|k| matrix[r][k] * v[k]).sum()).collect();
+      let eigenvalue: f64 = (0..3).map(|k| product[k] * v[k]).sum();
+      for k in 0..3 {
+        assert!((product[k] - eigenvalue * v[k]).abs() < 1e-9);
+      }
+      assert!((v.iter().map(|x| x * x).sum::<f64>() - 1.0).abs() < 1e-9);
+      // Largest eigenvalue first
+      assert!(eigenvalue <= previous);
+      previous = eigenvalue;
+    }
+  }
+
+  #[test]
+  fn lab_of_white_and_black() {
+    let white = rgb_to_lab([255.0, 255.0, 255.0]);
+    assert!((white[0] - 100.0).abs() < 0.1 && white[1].abs() < 0.1 && white[2].abs() < 0.1);
+    let black = rgb_to_lab([0.0, 0.0, 0.0]);
+    assert!(black.iter().all(|v| v.abs() < 0.1));
+  }
+}
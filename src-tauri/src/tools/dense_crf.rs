@@ -0,0 +1,294 @@
+use imageproc::distance_transform::{ self, Norm };
+use rayon::prelude::*;
+use serde::{ Deserialize, Serialize };
+use skeletonize::{ foreground, thin_image_edges, MarkingMethod };
+
+use crate::tools::permutohedral::PermutohedralLattice;
+
+/// Kernel bandwidths, named as in Krähenbühl & Koltun.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CrfSettings {
+  // Spatial extent of the appearance kernel, in pixels.
+  pub theta_alpha: f32,
+  // Colour extent of the appearance kernel, in RGB (0-255) or Lab units.
+  pub theta_beta: f32,
+  // Spatial extent of the smoothness kernel, in pixels.
+  pub theta_gamma: f32,
+  // Compare colours in CIE Lab instead of RGB.
+  pub lab: bool,
+}
+
+// Smaller appearance extent than the paper's 80 px, as masks are usually drawn on crops.
+impl Default for CrfSettings {
+  fn default() -> Self {
+    CrfSettings {
+      theta_alpha: 20.0,
+      theta_beta: 13.0,
+      theta_gamma: 3.0,
+      lab: false,
+    }
+  }
+}
+
+struct PairwiseKernel {
+  lattice: PermutohedralLattice,
+  // Symmetric normalisation, 1 / sqrt(sum of the kernel weights) per pixel
+  norm: Vec<f32>,
+  weight: f32,
+}
+
+/// Fully connected CRF with Potts compatibility and Gaussian edge potentials
+/// (Krähenbühl & Koltun 2011). Message passing runs in linear time on a permutohedral lattice.
+pub struct DenseCrf {
+  width: usize,
+  height: usize,
+  labels: usize,
+  // -ln P(label), `labels` values per pixel
+  unary: Vec<f32>,
+  kernels: Vec<PairwiseKernel>,
+}
+
+impl DenseCrf {
+  pub fn new(width: usize, height: usize, labels: usize) -> Self {
+    DenseCrf {
+      width,
+      height,
+      labels,
+      unary: vec![0.0; width * height * labels],
+      kernels: Vec::new(),
+    }
+  }
+
+  /// Sets the unary energies from per-pixel label probabilities (`labels` values per pixel).
+  pub fn set_unary_from_probabilities(&mut self, probabilities: &[f32]) {
+    self.unary = probabilities
+      .par_iter()
+      .map(|&p| -p.clamp(1e-7, 1.0).ln())
+      .collect();
+  }
+
+  /// Smoothness kernel on pixel positions only.
+  pub fn add_pairwise_gaussian(&mut self, sxy: f32, weight: f32) {
+    let mut features = Vec::with_capacity(self.width * self.height * 2);
+    for y in 0..self.height {
+      for x in 0..self.width {
+        features.push((x as f32) / sxy);
+        features.push((y as f32) / sxy);
+      }
+    }
+    self.add_kernel(&features, 2, weight);
+  }
+
+  /// Appearance kernel on pixel positions and colours (`channels` values per pixel).
+  pub fn add_pairwise_bilateral(
+    &mut self,
+    colors: &[f32],
+    channels: usize,
+    sxy: f32,
+    scolor: f32,
+    weight: f32
+  ) {
+    let d = 2 + channels;
+    let mut features = vec![0f32; self.width * self.height * d];
+    let width = self.width;
+    features
+      .par_chunks_mut(d)
+      .enumerate()
+      .for_each(|(i, feature)| {
+        feature[0] = ((i % width) as f32) / sxy;
+        feature[1] = ((i / width) as f32) / sxy;
+        for c in 0..channels {
+          feature[2 + c] = colors[i * channels + c] / scolor;
+        }
+      });
+    self.add_kernel(&features, d, weight);
+  }
+
+  fn add_kernel(&mut self, features: &[f32], d: usize, weight: f32) {
+    let lattice = PermutohedralLattice::new(features, d);
+    let ones = vec![1.0; self.width * self.height];
+    let norm = lattice
+      .filter(&ones, 1)
+      .into_iter()
+      .map(|sum| 1.0 / (sum + 1e-20).sqrt())
+      .collect();
+    self.kernels.push(PairwiseKernel { lattice, norm, weight });
+  }
+
+  /// Mean-field inference, returns the label marginals (`labels` values per pixel).
+  pub fn inference(&self, num_iterations: usize) -> Vec<f32> {
+    let labels = self.labels;
+    let mut q: Vec<f32> = self.unary
+      .iter()
+      .map(|&u| -u)
+      .collect();
+    softmax_rows(&mut q, labels);
+
+    for _ in 0..num_iterations {
+      let mut energy: Vec<f32> = self.unary
+        .iter()
+        .map(|&u| -u)
+        .collect();
+      for kernel in &self.kernels {
+        let mut input = q.clone();
+        input
+          .par_chunks_mut(labels)
+          .zip(kernel.norm.par_iter())
+          .for_each(|(values, &norm)| values.iter_mut().for_each(|v| *v *= norm));
+        let message = kernel.lattice.filter(&input, labels);
+        // Potts model: agreeing neighbours lower the energy of the same label
+        energy
+          .par_chunks_mut(labels)
+          .zip(message.par_chunks(labels))
+          .zip(kernel.norm.par_iter())
+          .for_each(|((energy, message), &norm)| {
+            for l in 0..labels {
+              energy[l] += kernel.weight * norm * message[l];
+            }
+          });
+      }
+      softmax_rows(&mut energy, labels);
+      q = energy;
+    }
+    q
+  }
+}
+
+/// Foreground marginal of each pixel of a brush mask, refined the way `crf_refine` does with
+/// the default kernels and the skeleton prior. `colors` holds RGB values (0-255) per pixel.
+pub fn refine_mask(
+  colors: &[f32],
+  mask: &image::GrayImage,
+  spatial_weight: f32,
+  bilateral_weight: f32,
+  num_iterations: usize
+) -> Vec<f32> {
+  let settings = CrfSettings::default();
+  let probabilities: Vec<f32> = skeleton_prior(mask)
+    .iter()
+    .flat_map(|&p| [1.0 - p, p])
+    .collect();
+  let mut crf = DenseCrf::new(mask.width() as usize, mask.height() as usize, 2);
+  crf.set_unary_from_probabilities(&probabilities);
+  crf.add_pairwise_gaussian(settings.theta_gamma, spatial_weight);
+  crf.add_pairwise_bilateral(colors, 3, settings.theta_alpha, settings.theta_beta, bilateral_weight);
+  crf
+    .inference(num_iterations)
+    .chunks(2)
+    .map(|q| q[1])
+    .collect()
+}
+
+/// Foreground prior of a brush mask: 1 on its skeleton, decreasing towards its border.
+/// Pixels outside the mask get a near-zero probability.
+pub fn skeleton_prior(mask: &image::GrayImage) -> Vec<f32> {
+  let mut binary = mask.clone();
+  for pixel in binary.pixels_mut() {
+    if pixel[0] > 0 {
+      pixel[0] = 255;
+    }
+  }
+  let mut dynamic_mask = image::DynamicImage::ImageLuma8(binary);
+  thin_image_edges::<foreground::White>(&mut dynamic_mask, MarkingMethod::Modified, None).unwrap();
+  let mut distance = dynamic_mask.to_luma8();
+  distance_transform::distance_transform_mut(&mut distance, Norm::L1);
+
+  let maxvalue = mask
+    .pixels()
+    .zip(distance.pixels())
+    .filter(|(m, _)| m[0] > 0)
+    .map(|(_, d)| d[0] as f32)
+    .fold(0.0, f32::max)
+    .max(1.0);
+  mask
+    .pixels()
+    .zip(distance.pixels())
+    .map(|(m, d)| {
+      if m[0] > 0 { f32::max(1.0 - (d[0] as f32) / maxvalue, 1e-7) } else { 1e-7 }
+    })
+    .collect()
+}
+
+/// Soft prior of a class mask: 0.5 on its border, ramping to ~1 inside and ~0 outside
+/// over `band` pixels, so pixels near the border can either join or leave the class.
+pub fn boundary_prior(mask: &image::GrayImage, band: f32) -> Vec<f32> {
+  let inverted = image::GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+    image::Luma([if mask.get_pixel(x, y)[0] > 0 { 0 } else { 255 }])
+  });
+  // Distance to the mask from outside, and to the background from inside
+  let outside = distance_transform::euclidean_squared_distance_transform(mask);
+  let inside = distance_transform::euclidean_squared_distance_transform(&inverted);
+  outside
+    .pixels()
+    .zip(inside.pixels())
+    .map(|(d_out, d_in)| {
+      let signed = (d_in[0].sqrt() - d_out[0].sqrt()) as f32;
+      (0.5 + signed / (2.0 * (band + 1.0))).clamp(1e-7, 1.0 - 1e-7)
+    })
+    .collect()
+}
+
+fn softmax_rows(values: &mut [f32], labels: usize) {
+  values.par_chunks_mut(labels).for_each(|row| {
+    let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for v in row.iter_mut() {
+      *v = (*v - max).exp();
+      sum += *v;
+    }
+    for v in row.iter_mut() {
+      *v /= sum;
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const WIDTH: usize = 30;
+  const HEIGHT: usize = 20;
+
+  // Three vertical colour bands
+  fn band(x: usize) -> usize {
+    x / 10
+  }
+
+  #[test]
+  fn joint_classes_follow_colour_edges() {
+    let palette = [[200.0, 40.0, 40.0], [40.0, 200.0, 40.0], [40.0, 40.0, 200.0]];
+    let colors: Vec<f32> = (0..WIDTH * HEIGHT).flat_map(|i| palette[band(i % WIDTH)]).collect();
+    // Weak unaries that are wrong on every seventh pixel
+    let mut probabilities = vec![0.0; WIDTH * HEIGHT * 3];
+    for i in 0..WIDTH * HEIGHT {
+      let label = if i % 7 == 0 { (band(i % WIDTH) + 1) % 3 } else { band(i % WIDTH) };
+      for l in 0..3 {
+        probabilities[i * 3 + l] = if l == label { 0.6 } else { 0.2 };
+      }
+    }
+
+    let mut crf = DenseCrf::new(WIDTH, HEIGHT, 3);
+    crf.set_unary_from_probabilities(&probabilities);
+    crf.add_pairwise_gaussian(3.0, 3.0);
+    crf.add_pairwise_bilateral(&colors, 3, 20.0, 13.0, 10.0);
+    let marginals = crf.inference(5);
+
+    for (i, q) in marginals.chunks(3).enumerate() {
+      assert!((q.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+      let best = (0..3).max_by(|&a, &b| q[a].total_cmp(&q[b])).unwrap();
+      assert_eq!(best, band(i % WIDTH), "pixel {} {:?}", i, q);
+    }
+  }
+
+  #[test]
+  fn boundary_prior_ramps_across_the_border() {
+    let mask = image::GrayImage::from_fn(40, 1, |x, _| image::Luma([if x < 20 { 255 } else { 0 }]));
+    let prior = boundary_prior(&mask, 4.0);
+    // Half way on the border, sure inside and outside past the band
+    assert!(prior[19] > 0.5 && prior[20] < 0.5);
+    assert!((prior[19] + prior[20] - 1.0).abs() < 0.15);
+    assert!(prior.windows(2).all(|pair| pair[0] >= pair[1]));
+    assert!(prior[5] > 0.99 && prior[35] < 0.01);
+  }
+}
@@ -0,0 +1,293 @@
+use std::collections::VecDeque;
+use std::hash::{ Hash, Hasher };
+use std::sync::{ Arc, Mutex };
+
+use image::{ GrayImage, Luma };
+use serde::{ Deserialize, Serialize };
+
+use crate::tools::morphology::{ apply_morphology, MaskNorm, MorphologyOp };
+
+// Brightest channel above which a pixel belongs to the field of view
+const DEFAULT_LEVEL: u8 = 20;
+// Fields of view kept around, so switching back to an image does not detect it again
+const CACHE_SIZE: usize = 16;
+// RGBA pixels copied at a time when hashing an image
+const HASH_BLOCK: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum FovShape {
+  // Largest bright component as is, e.g. dermoscopy vignetting or endoscopy octagons
+  Free,
+  // Circle fitted to the component border, for fundus images
+  Circle,
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldOfView {
+  pub width: usize,
+  pub height: usize,
+  pub mask: Vec<bool>,
+  // [cx, cy, radius] when fitted
+  pub circle: Option<[f32; 3]>,
+}
+
+impl FieldOfView {
+  /// Field of view under the `width` x `height` region starting at `origin`, or None when the
+  /// region does not fit in the image it was detected on.
+  pub fn region(&self, origin: [usize; 2], width: usize, height: usize) -> Option<Vec<bool>> {
+    let fits = |start: usize, size: usize, limit: usize| {
+      start.checked_add(size).is_some_and(|end| end <= limit)
+    };
+    if !fits(origin[0], width, self.width) || !fits(origin[1], height, self.height) {
+      return None;
+    }
+    let mut region = Vec::with_capacity(width * height);
+    for y in origin[1]..origin[1] + height {
+      let row = y * self.width + origin[0];
+      region.extend_from_slice(&self.mask[row..row + width]);
+    }
+    Some(region)
+  }
+}
+
+/// Key of the pixels of an RGB or RGBA image, alpha is ignored. None when the buffer does
+/// not match the dimensions.
+pub fn image_key(image: &[u8], width: usize, height: usize) -> Option<u64> {
+  let pixels = width * height;
+  if pixels == 0 || (image.len() != pixels * 3 && image.len() != pixels * 4) {
+    return None;
+  }
+  let mut hasher = std::collections::hash_map::DefaultHasher::new();
+  (width, height).hash(&mut hasher);
+  if image.len() == pixels * 3 {
+    hasher.write(image);
+  } else {
+    // The hasher is fed a block of RGB at a time, rather than a copy of the whole image
+    let mut rgb = [0u8; 3 * HASH_BLOCK];
+    for block in image.chunks(4 * HASH_BLOCK) {
+      let count = block.len() / 4;
+      for (dst, pixel) in rgb.chunks_exact_mut(3).zip(block.chunks_exact(4)) {
+        dst.copy_from_slice(&pixel[..3]);
+      }
+      hasher.write(&rgb[..3 * count]);
+    }
+  }
+  Some(hasher.finish())
+}
+
+/// Key of the whole image for `FieldOfViewCache::region`. Only hashed when a field of view
+/// is active, None otherwise.
+pub fn active_image_key(
+  cache: &Mutex<FieldOfViewCache>,
+  image: &[u8],
+  width: usize,
+  height: usize
+) -> Option<u64> {
+  if !cache.lock().unwrap().is_active() {
+    return None;
+  }
+  image_key(image, width, height)
+}
+
+// Kasa least-squares circle through the points, solving x² + y² + Dx + Ey + F = 0
+fn fit_circle(points: &[[f32; 2]]) -> Option<[f32; 3]> {
+  if points.len() < 3 {
+    return None;
+  }
+  // Centred coordinates keep the normal equations well conditioned
+  let n = points.len() as f64;
+  let mx = points.iter().map(|p| p[0] as f64).sum::<f64>() / n;
+  let my = points.iter().map(|p| p[1] as f64).sum::<f64>() / n;
+  let mut a = [[0f64; 3]; 3];
+  let mut b = [0f64; 3];
+  for p in points {
+    let (x, y) = ((p[0] as f64) - mx, (p[1] as f64) - my);
+    let row = [x, y, 1.0];
+    let z = -(x * x + y * y);
+    for i in 0..3 {
+      for j in 0..3 {
+        a[i][j] += row[i] * row[j];
+      }
+      b[i] += row[i] * z;
+    }
+  }
+
+  // Gaussian elimination with partial pivoting
+  for col in 0..3 {
+    let pivot = (col..3).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+    if a[pivot][col].abs() < 1e-9 {
+      return None;
+    }
+    a.swap(col, pivot);
+    b.swap(col, pivot);
+    let pivot_row = a[col];
+    for row in col + 1..3 {
+      let factor = a[row][col] / pivot_row[col];
+      for (value, pivot) in a[row].iter_mut().zip(pivot_row.iter()).skip(col) {
+        *value -= factor * pivot;
+      }
+      b[row] -= factor * b[col];
+    }
+  }
+  let mut solution = [0f64; 3];
+  for row in (0..3).rev() {
+    let rest: f64 = (row + 1..3).map(|k| a[row][k] * solution[k]).sum();
+    solution[row] = (b[row] - rest) / a[row][row];
+  }
+
+  let [d, e, f] = solution;
+  let (cx, cy) = (-d / 2.0, -e / 2.0);
+  let r2 = cx * cx + cy * cy - f;
+  if r2 <= 0.0 {
+    return None;
+  }
+  Some([(cx + mx) as f32, (cy + my) as f32, r2.sqrt() as f32])
+}
+
+/// Detects the field of view of an RGB or RGBA image: pixels whose brightest channel is above
+/// `level`, opened to cut text overlays loose, reduced to the largest component and with its
+/// holes filled. With `FovShape::Circle` the result is the circle fitted to the component
+/// border, leaving out the image edges where fundus images are often cropped.
+/// Falls back to the whole image when nothing is bright enough.
+pub fn detect_field_of_view(
+  image: &[u8],
+  width: usize,
+  height: usize,
+  level: Option<u8>,
+  shape: FovShape
+) -> FieldOfView {
+  let channels = (image.len() / (width * height).max(1)).max(1);
+  let level = level.unwrap_or(DEFAULT_LEVEL);
+  let bright = GrayImage::from_fn(width as u32, height as u32, |x, y| {
+    let i = ((y as usize) * width + (x as usize)) * channels;
+    let brightest = image[i..i + channels.min(3)].iter().copied().max().unwrap_or(0);
+    Luma([if brightest > level { 255 } else { 0 }])
+  });
+  let component = apply_morphology(
+    &bright,
+    &[
+      MorphologyOp::Open { norm: MaskNorm::L1, radius: 2 },
+      MorphologyOp::KeepLargest { count: 1 },
+      MorphologyOp::FillHoles,
+    ]
+  );
+  let mask: Vec<bool> = component
+    .pixels()
+    .map(|p| p[0] > 0)
+    .collect();
+  if !mask.iter().any(|&inside| inside) {
+    return FieldOfView { width, height, mask: vec![true; width * height], circle: None };
+  }
+  if shape == FovShape::Free {
+    return FieldOfView { width, height, mask, circle: None };
+  }
+
+  let inside = |x: usize, y: usize| mask[y * width + x];
+  let mut border = Vec::new();
+  for y in 1..height.saturating_sub(1) {
+    for x in 1..width.saturating_sub(1) {
+      if inside(x, y) && (!inside(x - 1, y) || !inside(x + 1, y) || !inside(x, y - 1) || !inside(x, y + 1)) {
+        border.push([x as f32, y as f32]);
+      }
+    }
+  }
+  match fit_circle(&border) {
+    Some(circle) => {
+      // Border pixels sit half a pixel inside the edge on average
+      let [cx, cy, r] = [circle[0], circle[1], circle[2] + 0.5];
+      let disk = (0..width * height)
+        .map(|i| (((i % width) as f32) - cx).hypot(((i / width) as f32) - cy) <= r)
+        .collect();
+      FieldOfView { width, height, mask: disk, circle: Some([cx, cy, r]) }
+    }
+    None => FieldOfView { width, height, mask, circle: None },
+  }
+}
+
+// Image key and detection settings
+type EntryKey = (u64, Option<u8>, FovShape);
+
+/// Recently detected fields of view, and the one of the image being edited that the
+/// segmentation commands restrict themselves to.
+pub struct FieldOfViewCache {
+  entries: VecDeque<(EntryKey, Arc<FieldOfView>)>,
+  // Key of the image the active field of view was detected on
+  active: Option<(u64, Arc<FieldOfView>)>,
+}
+
+impl Default for FieldOfViewCache {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl FieldOfViewCache {
+  pub fn new() -> Self {
+    FieldOfViewCache { entries: VecDeque::new(), active: None }
+  }
+
+  pub fn get(&self, key: EntryKey) -> Option<Arc<FieldOfView>> {
+    self.entries
+      .iter()
+      .find(|(k, _)| *k == key)
+      .map(|(_, fov)| fov.clone())
+  }
+
+  pub fn insert(&mut self, key: EntryKey, fov: FieldOfView) -> Arc<FieldOfView> {
+    let fov = Arc::new(fov);
+    if self.entries.len() >= CACHE_SIZE {
+      self.entries.pop_front();
+    }
+    self.entries.push_back((key, fov.clone()));
+    fov
+  }
+
+  pub fn activate(&mut self, image: u64, fov: Arc<FieldOfView>) {
+    self.active = Some((image, fov));
+  }
+
+  pub fn clear(&mut self) {
+    self.active = None;
+  }
+
+  pub fn is_active(&self) -> bool {
+    self.active.is_some()
+  }
+
+  /// Drops the active field of view unless it was detected on `image`, for when the
+  /// commands move on to another image.
+  pub fn retain_image(&mut self, image: u64) {
+    if self.active.as_ref().is_some_and(|(key, _)| *key != image) {
+      self.active = None;
+    }
+  }
+
+  /// Active field of view, None when `image` is given and it was detected on another one.
+  pub fn active(&self, image: Option<u64>) -> Option<Arc<FieldOfView>> {
+    let (key, fov) = self.active.as_ref()?;
+    if image.is_some_and(|image| image != *key) {
+      return None;
+    }
+    Some(fov.clone())
+  }
+
+  /// Active field of view under a crop at `origin`, or under the whole image `image` when no
+  /// origin is given. None when no field of view is active for it or the region does not match.
+  pub fn region(
+    &self,
+    image: Option<u64>,
+    origin: Option<[usize; 2]>,
+    width: usize,
+    height: usize
+  ) -> Option<Vec<bool>> {
+    let fov = self.active(image)?;
+    match origin {
+      Some(origin) => fov.region(origin, width, height),
+      None if image.is_some() && fov.width == width && fov.height == height => {
+        Some(fov.mask.clone())
+      }
+      None => None,
+    }
+  }
+}
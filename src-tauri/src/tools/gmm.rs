@@ -0,0 +1,183 @@
+// Added to the covariance diagonal so flat colour regions stay invertible
+const COVARIANCE_EPSILON: f64 = 0.01;
+
+/// Lloyd's k-means on colour samples, initialised with evenly spaced samples of the
+/// sorted luminance so the result is deterministic. Returns the label of each sample.
+pub fn kmeans(samples: &[[f32; 3]], k: usize, iterations: usize) -> Vec<usize> {
+  let k = k.min(samples.len()).max(1);
+  if samples.is_empty() {
+    return Vec::new();
+  }
+  let mut order: Vec<usize> = (0..samples.len()).collect();
+  order.sort_by(|&a, &b| {
+    let la: f32 = samples[a].iter().sum();
+    let lb: f32 = samples[b].iter().sum();
+    la.total_cmp(&lb)
+  });
+  let mut centers: Vec<[f32; 3]> = (0..k)
+    .map(|i| samples[order[(i * 2 + 1) * samples.len() / (2 * k)]])
+    .collect();
+
+  let mut labels = vec![0usize; samples.len()];
+  for _ in 0..iterations {
+    let mut changed = false;
+    for (sample, label) in samples.iter().zip(labels.iter_mut()) {
+      let (best, _) = centers
+        .iter()
+        .enumerate()
+        .map(|(c, center)| (c, squared_distance(sample, center)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap();
+      changed |= *label != best;
+      *label = best;
+    }
+    let mut sums = vec![[0f64; 3]; k];
+    let mut counts = vec![0usize; k];
+    for (sample, &label) in samples.iter().zip(labels.iter()) {
+      for c in 0..3 {
+        sums[label][c] += sample[c] as f64;
+      }
+      counts[label] += 1;
+    }
+    for (center, (sum, &count)) in centers.iter_mut().zip(sums.iter().zip(counts.iter())) {
+      if count > 0 {
+        *center = sum.map(|v| (v / (count as f64)) as f32);
+      }
+    }
+    if !changed {
+      break;
+    }
+  }
+  labels
+}
+
+fn squared_distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+  (0..3).map(|c| (a[c] - b[c]).powi(2)).sum()
+}
+
+#[derive(Debug, Clone)]
+pub struct Gaussian {
+  pub mean: [f64; 3],
+  inverse: [[f64; 3]; 3],
+  // Normalisation constant, weight / sqrt((2 pi)^3 det)
+  norm: f64,
+}
+
+/// Gaussian mixture over colours (0-255 units), as used by GrabCut.
+#[derive(Debug, Clone)]
+pub struct Gmm {
+  pub components: Vec<Gaussian>,
+}
+
+impl Gmm {
+  /// Initialises `k` components with k-means, then fits them.
+  pub fn fit(samples: &[[f32; 3]], k: usize) -> Gmm {
+    let labels = kmeans(samples, k, 10);
+    Gmm::learn(samples, &labels, k)
+  }
+
+  /// Maximum-likelihood components from hard assignments. Empty components are dropped.
+  pub fn learn(samples: &[[f32; 3]], labels: &[usize], k: usize) -> Gmm {
+    let mut sums = vec![[0f64; 3]; k];
+    let mut products = vec![[[0f64; 3]; 3]; k];
+    let mut counts = vec![0usize; k];
+    for (sample, &label) in samples.iter().zip(labels.iter()) {
+      let x = sample.map(|v| v as f64);
+      for i in 0..3 {
+        sums[label][i] += x[i];
+        for j in 0..3 {
+          products[label][i][j] += x[i] * x[j];
+        }
+      }
+      counts[label] += 1;
+    }
+
+    let total = samples.len().max(1) as f64;
+    let components = (0..k)
+      .filter(|&c| counts[c] > 0)
+      .map(|c| {
+        let n = counts[c] as f64;
+        let mean = sums[c].map(|v| v / n);
+        let mut covariance = [[0f64; 3]; 3];
+        for i in 0..3 {
+          for j in 0..3 {
+            covariance[i][j] = products[c][i][j] / n - mean[i] * mean[j];
+          }
+          covariance[i][i] += COVARIANCE_EPSILON;
+        }
+        Gaussian::new(n / total, mean, covariance)
+      })
+      .collect();
+    Gmm { components }
+  }
+
+  /// Mixture density at `x`.
+  pub fn likelihood(&self, x: &[f32; 3]) -> f64 {
+    self.components
+      .iter()
+      .map(|g| g.density(x))
+      .sum()
+  }
+
+  /// Index of the component that explains `x` best.
+  pub fn component(&self, x: &[f32; 3]) -> usize {
+    self.components
+      .iter()
+      .enumerate()
+      .map(|(c, g)| (c, g.density(x)))
+      .max_by(|a, b| a.1.total_cmp(&b.1))
+      .map(|(c, _)| c)
+      .unwrap_or(0)
+  }
+
+  /// One hard-EM step: reassign every sample to its best component and refit.
+  pub fn refine(&self, samples: &[[f32; 3]]) -> Gmm {
+    let labels: Vec<usize> = samples
+      .iter()
+      .map(|x| self.component(x))
+      .collect();
+    Gmm::learn(samples, &labels, self.components.len().max(1))
+  }
+}
+
+impl Gaussian {
+  fn new(weight: f64, mean: [f64; 3], covariance: [[f64; 3]; 3]) -> Self {
+    let c = &covariance;
+    let det =
+      c[0][0] * (c[1][1] * c[2][2] - c[1][2] * c[2][1]) -
+      c[0][1] * (c[1][0] * c[2][2] - c[1][2] * c[2][0]) +
+      c[0][2] * (c[1][0] * c[2][1] - c[1][1] * c[2][0]);
+    let det = det.max(1e-12);
+    let inverse = [
+      [
+        (c[1][1] * c[2][2] - c[1][2] * c[2][1]) / det,
+        (c[0][2] * c[2][1] - c[0][1] * c[2][2]) / det,
+        (c[0][1] * c[1][2] - c[0][2] * c[1][1]) / det,
+      ],
+      [
+        (c[1][2] * c[2][0] - c[1][0] * c[2][2]) / det,
+        (c[0][0] * c[2][2] - c[0][2] * c[2][0]) / det,
+        (c[0][2] * c[1][0] - c[0][0] * c[1][2]) / det,
+      ],
+      [
+        (c[1][0] * c[2][1] - c[1][1] * c[2][0]) / det,
+        (c[0][1] * c[2][0] - c[0][0] * c[2][1]) / det,
+        (c[0][0] * c[1][1] - c[0][1] * c[1][0]) / det,
+      ],
+    ];
+    let norm = weight / ((2.0 * std::f64::consts::PI).powi(3) * det).sqrt();
+    Gaussian { mean, inverse, norm }
+  }
+
+  /// Weighted density of the component at `x`.
+  pub fn density(&self, x: &[f32; 3]) -> f64 {
+    let d = [0, 1, 2].map(|i| (x[i] as f64) - self.mean[i]);
+    let mut mahalanobis = 0.0;
+    for i in 0..3 {
+      for j in 0..3 {
+        mahalanobis += d[i] * self.inverse[i][j] * d[j];
+      }
+    }
+    self.norm * (-0.5 * mahalanobis).exp()
+  }
+}
@@ -0,0 +1,155 @@
+use crate::tools::gmm::Gmm;
+use crate::tools::graph_cut::FlowGraph;
+
+const COMPONENTS: usize = 5;
+// Smoothness weight from the GrabCut paper
+const GAMMA: f32 = 50.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trimap {
+  Background,
+  ProbableBackground,
+  ProbableForeground,
+  Foreground,
+}
+
+impl Trimap {
+  pub fn is_foreground(&self) -> bool {
+    matches!(self, Trimap::Foreground | Trimap::ProbableForeground)
+  }
+}
+
+fn squared_difference(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+  (0..3).map(|c| (a[c] - b[c]).powi(2)).sum()
+}
+
+// Right, bottom-left, bottom and bottom-right neighbours, so each pair is visited once
+const FORWARD: [(isize, isize); 4] = [(1, 0), (-1, 1), (0, 1), (1, 1)];
+
+fn forward_neighbours(
+  x: usize,
+  y: usize,
+  width: usize,
+  height: usize
+) -> impl Iterator<Item = (usize, f32)> {
+  FORWARD.into_iter().filter_map(move |(dx, dy)| {
+    let nx = (x as isize) + dx;
+    let ny = (y as isize) + dy;
+    if nx >= 0 && (nx as usize) < width && (ny as usize) < height {
+      let distance = ((dx * dx + dy * dy) as f32).sqrt();
+      Some(((ny as usize) * width + (nx as usize), distance))
+    } else {
+      None
+    }
+  })
+}
+
+/// Pairwise weights between each pixel and its forward neighbours, GAMMA / dist * exp(-beta |zi - zj|²)
+/// with beta set from the mean contrast of the image.
+fn smoothness_edges(colors: &[[f32; 3]], width: usize, height: usize) -> Vec<(usize, usize, f32)> {
+  let mut total = 0.0f64;
+  let mut count = 0usize;
+  for y in 0..height {
+    for x in 0..width {
+      let i = y * width + x;
+      for (j, _) in forward_neighbours(x, y, width, height) {
+        total += squared_difference(&colors[i], &colors[j]) as f64;
+        count += 1;
+      }
+    }
+  }
+  let mean = (total / (count.max(1) as f64)) as f32;
+  let beta = if mean > 0.0 { 1.0 / (2.0 * mean) } else { 0.0 };
+
+  let mut edges = Vec::with_capacity(count);
+  for y in 0..height {
+    for x in 0..width {
+      let i = y * width + x;
+      for (j, distance) in forward_neighbours(x, y, width, height) {
+        let weight = (GAMMA / distance) * (-beta * squared_difference(&colors[i], &colors[j])).exp();
+        edges.push((i, j, weight));
+      }
+    }
+  }
+  edges
+}
+
+fn samples(colors: &[[f32; 3]], trimap: &[Trimap], foreground: bool) -> Vec<[f32; 3]> {
+  colors
+    .iter()
+    .zip(trimap.iter())
+    .filter(|(_, label)| label.is_foreground() == foreground)
+    .map(|(color, _)| *color)
+    .collect()
+}
+
+/// GrabCut (Rother et al. 2004): alternates GMM colour models and a min-cut over the
+/// probable pixels of `trimap`, which is updated in place. Returns the foreground.
+pub fn grabcut(
+  colors: &[[f32; 3]],
+  trimap: &mut [Trimap],
+  width: usize,
+  height: usize,
+  iterations: usize
+) -> Vec<bool> {
+  let edges = smoothness_edges(colors, width, height);
+  // Larger than any sum of pairwise weights around a pixel, so hard labels never flip
+  let hard = 1.0 + 8.0 * GAMMA;
+
+  let mut models: Option<(Gmm, Gmm)> = None;
+  for _ in 0..iterations {
+    let foreground_samples = samples(colors, trimap, true);
+    let background_samples = samples(colors, trimap, false);
+    if foreground_samples.is_empty() || background_samples.is_empty() {
+      break;
+    }
+    let (foreground, background) = match &models {
+      None =>
+        (Gmm::fit(&foreground_samples, COMPONENTS), Gmm::fit(&background_samples, COMPONENTS)),
+      Some((foreground, background)) =>
+        (foreground.refine(&foreground_samples), background.refine(&background_samples)),
+    };
+
+    let mut graph = FlowGraph::new(width * height);
+    for (i, (color, label)) in colors.iter().zip(trimap.iter()).enumerate() {
+      let (source, sink) = match label {
+        Trimap::Foreground => (hard, 0.0),
+        Trimap::Background => (0.0, hard),
+        _ =>
+          (
+            -(background.likelihood(color).max(1e-300).ln() as f32),
+            -(foreground.likelihood(color).max(1e-300).ln() as f32),
+          ),
+      };
+      graph.add_terminal_weights(i, source, sink);
+    }
+    for &(i, j, weight) in &edges {
+      graph.add_edge(i, j, weight, weight);
+    }
+    graph.max_flow();
+
+    let mut changed = 0;
+    for (i, label) in trimap.iter_mut().enumerate() {
+      if matches!(label, Trimap::ProbableForeground | Trimap::ProbableBackground) {
+        let new_label = if graph.is_source_side(i) {
+          Trimap::ProbableForeground
+        } else {
+          Trimap::ProbableBackground
+        };
+        if new_label != *label {
+          changed += 1;
+        }
+        *label = new_label;
+      }
+    }
+    models = Some((foreground, background));
+    if changed == 0 {
+      break;
+    }
+  }
+
+  trimap
+    .iter()
+    .map(|label| label.is_foreground())
+    .collect()
+}
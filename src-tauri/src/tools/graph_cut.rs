@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+
+const EPSILON: f32 = 1e-6;
+
+struct Edge {
+  to: usize,
+  capacity: f32,
+}
+
+/// s-t graph for binary labelling problems, solved with Dinic's max-flow.
+/// Same interface as the Boykov-Kolmogorov graph used by GrabCut: terminal weights
+/// per node plus symmetric or asymmetric edges between nodes.
+pub struct FlowGraph {
+  nodes: usize,
+  // Edges come in pairs, the residual of edge `e` is `e ^ 1`
+  edges: Vec<Edge>,
+  adjacency: Vec<Vec<usize>>,
+  // Flow pushed directly through a node with both terminal capacities
+  flow: f32,
+  source_side: Vec<bool>,
+}
+
+impl FlowGraph {
+  pub fn new(nodes: usize) -> Self {
+    FlowGraph {
+      nodes,
+      edges: Vec::new(),
+      adjacency: vec![Vec::new(); nodes + 2],
+      flow: 0.0,
+      source_side: vec![false; nodes],
+    }
+  }
+
+  fn source(&self) -> usize {
+    self.nodes
+  }
+
+  fn sink(&self) -> usize {
+    self.nodes + 1
+  }
+
+  fn push_edge(&mut self, from: usize, to: usize, capacity: f32, reverse_capacity: f32) {
+    let index = self.edges.len();
+    self.edges.push(Edge { to, capacity });
+    self.edges.push(Edge { to: from, capacity: reverse_capacity });
+    self.adjacency[from].push(index);
+    self.adjacency[to].push(index + 1);
+  }
+
+  /// Cost of cutting `node` from the source (`source`) and from the sink (`sink`).
+  pub fn add_terminal_weights(&mut self, node: usize, source: f32, sink: f32) {
+    // The common part always flows through the node
+    let common = source.min(sink);
+    self.flow += common;
+    let (source, sink) = (source - common, sink - common);
+    if source > EPSILON {
+      let s = self.source();
+      self.push_edge(s, node, source, 0.0);
+    }
+    if sink > EPSILON {
+      let t = self.sink();
+      self.push_edge(node, t, sink, 0.0);
+    }
+  }
+
+  pub fn add_edge(&mut self, from: usize, to: usize, capacity: f32, reverse_capacity: f32) {
+    self.push_edge(from, to, capacity, reverse_capacity);
+  }
+
+  fn build_levels(&self, level: &mut [usize]) -> bool {
+    level.fill(usize::MAX);
+    let s = self.source();
+    level[s] = 0;
+    let mut queue = VecDeque::from([s]);
+    while let Some(u) = queue.pop_front() {
+      for &e in &self.adjacency[u] {
+        let v = self.edges[e].to;
+        if self.edges[e].capacity > EPSILON && level[v] == usize::MAX {
+          level[v] = level[u] + 1;
+          queue.push_back(v);
+        }
+      }
+    }
+    level[self.sink()] != usize::MAX
+  }
+
+  // Finds one augmenting path in the level graph (iterative DFS) and pushes flow along it.
+  fn augment(&mut self, level: &mut [usize], next: &mut [usize]) -> f32 {
+    let (s, t) = (self.source(), self.sink());
+    let mut path: Vec<usize> = Vec::new();
+    let mut u = s;
+    loop {
+      if u == t {
+        let bottleneck = path
+          .iter()
+          .map(|&e| self.edges[e].capacity)
+          .fold(f32::INFINITY, f32::min);
+        for &e in &path {
+          self.edges[e].capacity -= bottleneck;
+          self.edges[e ^ 1].capacity += bottleneck;
+        }
+        return bottleneck;
+      }
+      let mut advanced = false;
+      while next[u] < self.adjacency[u].len() {
+        let e = self.adjacency[u][next[u]];
+        let v = self.edges[e].to;
+        if self.edges[e].capacity > EPSILON && level[v] == level[u] + 1 {
+          path.push(e);
+          u = v;
+          advanced = true;
+          break;
+        }
+        next[u] += 1;
+      }
+      if !advanced {
+        if u == s {
+          return 0.0;
+        }
+        // Dead end, never visit it again in this phase
+        level[u] = usize::MAX;
+        let e = path.pop().unwrap();
+        u = self.edges[e ^ 1].to;
+        next[u] += 1;
+      }
+    }
+  }
+
+  /// Computes the minimum cut and returns its cost.
+  pub fn max_flow(&mut self) -> f32 {
+    let mut level = vec![usize::MAX; self.nodes + 2];
+    let mut next = vec![0usize; self.nodes + 2];
+    while self.build_levels(&mut level) {
+      next.fill(0);
+      loop {
+        let pushed = self.augment(&mut level, &mut next);
+        if pushed <= 0.0 {
+          break;
+        }
+        self.flow += pushed;
+      }
+    }
+
+    // Nodes still reachable from the source in the residual graph
+    let mut visited = vec![false; self.nodes + 2];
+    let s = self.source();
+    visited[s] = true;
+    let mut queue = VecDeque::from([s]);
+    while let Some(u) = queue.pop_front() {
+      for &e in &self.adjacency[u] {
+        let v = self.edges[e].to;
+        if self.edges[e].capacity > EPSILON && !visited[v] {
+          visited[v] = true;
+          queue.push_back(v);
+        }
+      }
+    }
+    self.source_side = visited[..self.nodes].to_vec();
+    self.flow
+  }
+
+  /// Whether `node` ended on the source side of the cut (after `max_flow`).
+  pub fn is_source_side(&self, node: usize) -> bool {
+    self.source_side[node]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn max_flow_of_textbook_network() {
+    // CLRS figure 26.1 with v1..v4 as nodes 0..3, its max flow is 23
+    let mut graph = FlowGraph::new(4);
+    graph.add_terminal_weights(0, 16.0, 0.0);
+    graph.add_terminal_weights(1, 13.0, 0.0);
+    graph.add_terminal_weights(2, 0.0, 20.0);
+    graph.add_terminal_weights(3, 0.0, 4.0);
+    graph.add_edge(0, 2, 12.0, 0.0);
+    graph.add_edge(1, 0, 4.0, 0.0);
+    graph.add_edge(1, 3, 14.0, 0.0);
+    graph.add_edge(2, 1, 9.0, 0.0);
+    graph.add_edge(3, 2, 7.0, 0.0);
+    assert!((graph.max_flow() - 23.0).abs() < 1e-4);
+    let sides: Vec<bool> = (0..4).map(|node| graph.is_source_side(node)).collect();
+    assert_eq!(sides, [true, true, false, true]);
+  }
+
+  #[test]
+  fn max_flow_matches_cheapest_cut() {
+    // Small random graphs against every possible labelling
+    let mut seed = 12345u32;
+    let mut random = move || {
+      seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+      ((seed >> 8) % 10) as f32
+    };
+    let nodes = 6;
+    for _ in 0..20 {
+      let terminals: Vec<[f32; 2]> = (0..nodes).map(|_| [random(), random()]).collect();
+      let mut edges = Vec::new();
+      for from in 0..nodes {
+        for to in from + 1..nodes {
+          edges.push((from, to, random(), random()));
+        }
+      }
+
+      let mut graph = FlowGraph::new(nodes);
+      for (node, &[source, sink]) in terminals.iter().enumerate() {
+        graph.add_terminal_weights(node, source, sink);
+      }
+      for &(from, to, capacity, reverse_capacity) in &edges {
+        graph.add_edge(from, to, capacity, reverse_capacity);
+      }
+      let flow = graph.max_flow();
+
+      let cut_cost = |source_side: &dyn Fn(usize) -> bool| {
+        let mut cost = 0.0;
+        for (node, &[source, sink]) in terminals.iter().enumerate() {
+          cost += if source_side(node) { sink } else { source };
+        }
+        for &(from, to, capacity, reverse_capacity) in &edges {
+          if source_side(from) && !source_side(to) {
+            cost += capacity;
+          } else if !source_side(from) && source_side(to) {
+            cost += reverse_capacity;
+          }
+        }
+        cost
+      };
+      let cheapest = (0..1u32 << nodes)
+        .map(|labels| cut_cost(&|node| (labels >> node) & 1 == 1))
+        .fold(f32::INFINITY, f32::min);
+      assert!((flow - cheapest).abs() < 1e-3, "flow {} but cheapest cut {}", flow, cheapest);
+      // The labelling found is a minimum cut
+      assert!((cut_cost(&|node| graph.is_source_side(node)) - cheapest).abs() < 1e-3);
+    }
+  }
+}
@@ -1 +1,16 @@
-pub mod split_and_merge;
\ No newline at end of file
+pub mod split_and_merge;
+pub mod permutohedral;
+pub mod dense_crf;
+pub mod color;
+pub mod gmm;
+pub mod graph_cut;
+pub mod grabcut;
+pub mod scribbles;
+pub mod watershed;
+pub mod thresholding;
+pub mod clustering;
+pub mod vesselness;
+pub mod vessel_graph;
+pub mod polygons;
+pub mod morphology;
+pub mod field_of_view;
@@ -0,0 +1,251 @@
+use std::collections::{ HashMap, HashSet };
+
+use image::{ GrayImage, Luma };
+use imageproc::distance_transform::{ euclidean_squared_distance_transform, Norm };
+use imageproc::geometry::convex_hull;
+use imageproc::morphology::{ dilate, erode };
+use imageproc::point::Point;
+use imageproc::region_labelling::{ connected_components, Connectivity };
+use serde::{ Deserialize, Serialize };
+
+use crate::tools::polygons::{ polygons_to_mask, Polygon };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskNorm {
+  // Diamond structuring element
+  L1,
+  // Square structuring element
+  LInf,
+  // Disk structuring element
+  L2,
+}
+
+/// One step of a morphology chain.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum MorphologyOp {
+  Dilate {
+    norm: MaskNorm,
+    radius: u8,
+  },
+  Erode {
+    norm: MaskNorm,
+    radius: u8,
+  },
+  // Erode then dilate, removes specks and thin protrusions
+  Open {
+    norm: MaskNorm,
+    radius: u8,
+  },
+  // Dilate then erode, closes small gaps
+  Close {
+    norm: MaskNorm,
+    radius: u8,
+  },
+  // Background regions not connected to the image border
+  FillHoles,
+  // Drops 8-connected components smaller than `min_size` pixels
+  RemoveSmall {
+    min_size: usize,
+  },
+  // Keeps the `count` largest 8-connected components
+  KeepLargest {
+    count: usize,
+  },
+  // Replaces each 8-connected component by its convex hull
+  ConvexHull,
+}
+
+fn binary(width: u32, height: u32, f: impl Fn(u32, u32) -> bool) -> GrayImage {
+  GrayImage::from_fn(width, height, |x, y| Luma([if f(x, y) { 255 } else { 0 }]))
+}
+
+fn inverted(mask: &GrayImage) -> GrayImage {
+  binary(mask.width(), mask.height(), |x, y| mask.get_pixel(x, y)[0] == 0)
+}
+
+// Pixels within `radius` (Euclidean) of the foreground
+fn dilate_l2(mask: &GrayImage, radius: u8) -> GrayImage {
+  let distance = euclidean_squared_distance_transform(mask);
+  let limit = (radius as f64) * (radius as f64);
+  binary(mask.width(), mask.height(), |x, y| distance.get_pixel(x, y)[0] <= limit)
+}
+
+fn dilate_with(mask: &GrayImage, norm: MaskNorm, radius: u8) -> GrayImage {
+  match norm {
+    MaskNorm::L1 => dilate(mask, Norm::L1, radius),
+    MaskNorm::LInf => dilate(mask, Norm::LInf, radius),
+    MaskNorm::L2 => dilate_l2(mask, radius),
+  }
+}
+
+fn erode_with(mask: &GrayImage, norm: MaskNorm, radius: u8) -> GrayImage {
+  match norm {
+    MaskNorm::L1 => erode(mask, Norm::L1, radius),
+    MaskNorm::LInf => erode(mask, Norm::LInf, radius),
+    MaskNorm::L2 => inverted(&dilate_l2(&inverted(mask), radius)),
+  }
+}
+
+fn fill_holes(mask: &GrayImage) -> GrayImage {
+  let (width, height) = (mask.width(), mask.height());
+  if width == 0 || height == 0 {
+    return mask.clone();
+  }
+  let background = connected_components(&inverted(mask), Connectivity::Four, Luma([0]));
+  let mut outside = HashSet::new();
+  for x in 0..width {
+    outside.insert(background.get_pixel(x, 0)[0]);
+    outside.insert(background.get_pixel(x, height - 1)[0]);
+  }
+  for y in 0..height {
+    outside.insert(background.get_pixel(0, y)[0]);
+    outside.insert(background.get_pixel(width - 1, y)[0]);
+  }
+  binary(width, height, |x, y| {
+    let label = background.get_pixel(x, y)[0];
+    label == 0 || !outside.contains(&label)
+  })
+}
+
+// Components with their sizes, largest first
+fn component_sizes(labels: &image::ImageBuffer<Luma<u32>, Vec<u32>>) -> Vec<(u32, usize)> {
+  let mut sizes: HashMap<u32, usize> = HashMap::new();
+  for pixel in labels.pixels() {
+    if pixel[0] != 0 {
+      *sizes.entry(pixel[0]).or_insert(0) += 1;
+    }
+  }
+  let mut sizes: Vec<(u32, usize)> = sizes.into_iter().collect();
+  sizes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+  sizes
+}
+
+fn keep_components(mask: &GrayImage, keep: impl Fn(&[(u32, usize)]) -> Vec<u32>) -> GrayImage {
+  let labels = connected_components(mask, Connectivity::Eight, Luma([0]));
+  let kept: HashSet<u32> = keep(&component_sizes(&labels)).into_iter().collect();
+  binary(mask.width(), mask.height(), |x, y| kept.contains(&labels.get_pixel(x, y)[0]))
+}
+
+fn convex_hulls(mask: &GrayImage) -> GrayImage {
+  let labels = connected_components(mask, Connectivity::Eight, Luma([0]));
+  let mut points: HashMap<u32, Vec<Point<i32>>> = HashMap::new();
+  for (x, y, label) in labels.enumerate_pixels() {
+    if label[0] != 0 {
+      points.entry(label[0]).or_default().push(Point::new(x as i32, y as i32));
+    }
+  }
+  let polygons: Vec<Polygon> = points
+    .values()
+    .map(|points| Polygon {
+      exterior: convex_hull(points)
+        .iter()
+        .map(|p| [p.x as f32, p.y as f32])
+        .collect(),
+      holes: Vec::new(),
+    })
+    .collect();
+  let (width, height) = (mask.width() as usize, mask.height() as usize);
+  let filled = polygons_to_mask(&polygons, width, height);
+  binary(mask.width(), mask.height(), |x, y| filled[(y as usize) * width + (x as usize)])
+}
+
+/// Applies the operations in order to a binary mask (non-zero is foreground).
+pub fn apply_morphology(mask: &GrayImage, operations: &[MorphologyOp]) -> GrayImage {
+  let mut mask = binary(mask.width(), mask.height(), |x, y| mask.get_pixel(x, y)[0] > 0);
+  for operation in operations {
+    mask = match *operation {
+      MorphologyOp::Dilate { norm, radius } => dilate_with(&mask, norm, radius),
+      MorphologyOp::Erode { norm, radius } => erode_with(&mask, norm, radius),
+      MorphologyOp::Open { norm, radius } => dilate_with(&erode_with(&mask, norm, radius), norm, radius),
+      MorphologyOp::Close { norm, radius } => erode_with(&dilate_with(&mask, norm, radius), norm, radius),
+      MorphologyOp::FillHoles => fill_holes(&mask),
+      MorphologyOp::RemoveSmall { min_size } =>
+        keep_components(&mask, |sizes| {
+          sizes
+            .iter()
+            .filter(|(_, size)| *size >= min_size)
+            .map(|(label, _)| *label)
+            .collect()
+        }),
+      MorphologyOp::KeepLargest { count } =>
+        keep_components(&mask, |sizes| {
+          sizes
+            .iter()
+            .take(count)
+            .map(|(label, _)| *label)
+            .collect()
+        }),
+      MorphologyOp::ConvexHull => convex_hulls(&mask),
+    };
+  }
+  mask
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn count(mask: &GrayImage) -> usize {
+    mask.pixels().filter(|p| p[0] > 0).count()
+  }
+
+  #[test]
+  fn open_removes_specks_and_keeps_squares() {
+    let square = |x: u32, y: u32| (4..20).contains(&x) && (4..20).contains(&y);
+    let mask = binary(32, 32, |x, y| square(x, y) || (x == 26 && y == 26));
+    let opened = apply_morphology(&mask, &[MorphologyOp::Open { norm: MaskNorm::LInf, radius: 1 }]);
+    assert_eq!(opened, binary(32, 32, square));
+  }
+
+  #[test]
+  fn close_bridges_a_gap() {
+    let mask = binary(32, 16, |x, y| {
+      (4..12).contains(&y) && ((2..15).contains(&x) || (16..30).contains(&x))
+    });
+    let closed = apply_morphology(&mask, &[MorphologyOp::Close { norm: MaskNorm::L1, radius: 1 }]);
+    assert!(closed.get_pixel(15, 8)[0] > 0);
+    let labels = connected_components(&closed, Connectivity::Eight, Luma([0]));
+    assert_eq!(component_sizes(&labels).len(), 1);
+  }
+
+  #[test]
+  fn disk_dilation_and_erosion() {
+    let dot = binary(9, 9, |x, y| x == 4 && y == 4);
+    let dilated = apply_morphology(&dot, &[MorphologyOp::Dilate { norm: MaskNorm::L2, radius: 2 }]);
+    // Offsets with dx² + dy² <= 4
+    assert_eq!(count(&dilated), 13);
+    let eroded = apply_morphology(&dilated, &[MorphologyOp::Erode { norm: MaskNorm::L2, radius: 2 }]);
+    assert_eq!(eroded, dot);
+  }
+
+  #[test]
+  fn component_chain() {
+    // A ring around a hole, a blob of 9 pixels and one of 2
+    let mask = binary(40, 24, |x, y| {
+      let square = (2..18).contains(&x) && (2..18).contains(&y);
+      let hole = (6..14).contains(&x) && (6..14).contains(&y);
+      let blob = (24..27).contains(&x) && (4..7).contains(&y);
+      let speck = x == 32 && (10..12).contains(&y);
+      (square && !hole) || blob || speck
+    });
+    let mut chain = vec![MorphologyOp::FillHoles, MorphologyOp::RemoveSmall { min_size: 5 }];
+    assert_eq!(count(&apply_morphology(&mask, &chain)), 16 * 16 + 9);
+    chain.push(MorphologyOp::KeepLargest { count: 1 });
+    let largest = apply_morphology(&mask, &chain);
+    assert_eq!(largest, binary(40, 24, |x, y| (2..18).contains(&x) && (2..18).contains(&y)));
+  }
+
+  #[test]
+  fn convex_hull_fills_an_l_shape() {
+    let mask = binary(16, 16, |x, y| {
+      ((2..5).contains(&x) && (2..14).contains(&y)) || ((2..14).contains(&x) && (11..14).contains(&y))
+    });
+    let hull = apply_morphology(&mask, &[MorphologyOp::ConvexHull]);
+    assert!(count(&hull) > count(&mask));
+    assert!(hull.get_pixel(7, 9)[0] > 0);
+    assert!(hull.get_pixel(11, 4)[0] == 0);
+    assert!(mask.pixels().zip(hull.pixels()).all(|(m, h)| m[0] == 0 || h[0] > 0));
+  }
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use rayon::prelude::*;
+
+/// Permutohedral lattice (Adams et al. 2010) for Gaussian filtering in a d-dimensional
+/// feature space in linear time. Follows the layout of Krähenbühl's densecrf.
+pub struct PermutohedralLattice {
+  d: usize,
+  n: usize,
+  // Number of lattice vertices
+  m: usize,
+  // For each point, its d + 1 enclosing vertices (shifted by one, 0 is an empty vertex)
+  offsets: Vec<usize>,
+  // and the matching barycentric weights
+  weights: Vec<f32>,
+  // For each axis then each vertex, its two neighbours along that axis (shifted by one)
+  neighbours: Vec<[usize; 2]>,
+}
+
+impl PermutohedralLattice {
+  /// `features` holds `d` values per point, already divided by the kernel bandwidths.
+  pub fn new(features: &[f32], d: usize) -> Self {
+    let n = features.len() / d;
+    let d1 = d + 1;
+
+    // Diagonal part of the elevation matrix, scaled to the expected standard deviation
+    let inv_std_dev = (2.0f32 / 3.0).sqrt() * (d1 as f32);
+    let scale_factor: Vec<f32> = (0..d)
+      .map(|i| inv_std_dev / (((i + 1) * (i + 2)) as f32).sqrt())
+      .collect();
+
+    // Canonical simplex
+    let mut canonical = vec![0i32; d1 * d1];
+    for i in 0..=d {
+      for j in 0..=d - i {
+        canonical[i * d1 + j] = i as i32;
+      }
+      for j in d - i + 1..=d {
+        canonical[i * d1 + j] = (i as i32) - (d1 as i32);
+      }
+    }
+
+    // Enclosing simplex of every point, computed in parallel
+    let mut point_keys = vec![0i32; n * d1 * d];
+    let mut weights = vec![0f32; n * d1];
+    point_keys
+      .par_chunks_mut(d1 * d)
+      .zip(weights.par_chunks_mut(d1))
+      .enumerate()
+      .for_each(|(k, (keys, barycentric_out))| {
+        let f = &features[k * d..(k + 1) * d];
+
+        // Elevate the feature onto the hyperplane
+        let mut elevated = vec![0f32; d1];
+        let mut sm = 0.0;
+        for j in (1..=d).rev() {
+          let cf = f[j - 1] * scale_factor[j - 1];
+          elevated[j] = sm - (j as f32) * cf;
+          sm += cf;
+        }
+        elevated[0] = sm;
+
+        // Closest 0-coloured lattice point
+        let down_factor = 1.0 / (d1 as f32);
+        let up_factor = d1 as f32;
+        let mut rem0 = vec![0f32; d1];
+        let mut sum = 0i32;
+        for i in 0..=d {
+          let v = down_factor * elevated[i];
+          let up = v.ceil() * up_factor;
+          let down = v.floor() * up_factor;
+          rem0[i] = if up - elevated[i] < elevated[i] - down { up } else { down };
+          sum += (rem0[i] * down_factor).round() as i32;
+        }
+
+        // Rank of each coordinate in the sorted differential
+        let mut rank = vec![0i32; d1];
+        for i in 0..d {
+          let di = elevated[i] - rem0[i];
+          for j in i + 1..=d {
+            if di < elevated[j] - rem0[j] {
+              rank[i] += 1;
+            } else {
+              rank[j] += 1;
+            }
+          }
+        }
+
+        // Bring the point back onto the plane if the rounding moved it off
+        for i in 0..=d {
+          rank[i] += sum;
+          if rank[i] < 0 {
+            rank[i] += d1 as i32;
+            rem0[i] += d1 as f32;
+          } else if rank[i] > (d as i32) {
+            rank[i] -= d1 as i32;
+            rem0[i] -= d1 as f32;
+          }
+        }
+
+        // Barycentric coordinates
+        let mut barycentric = vec![0f32; d + 2];
+        for i in 0..=d {
+          let v = (elevated[i] - rem0[i]) * down_factor;
+          let r = rank[i] as usize;
+          barycentric[d - r] += v;
+          barycentric[d - r + 1] -= v;
+        }
+        barycentric[0] += 1.0 + barycentric[d1];
+
+        for remainder in 0..=d {
+          for i in 0..d {
+            keys[remainder * d + i] =
+              (rem0[i] as i32) + canonical[remainder * d1 + (rank[i] as usize)];
+          }
+          barycentric_out[remainder] = barycentric[remainder];
+        }
+      });
+
+    // Hash the vertices (sequential, the table is shared)
+    let mut table: HashMap<Vec<i32>, usize> = HashMap::new();
+    let mut vertex_keys: Vec<i32> = Vec::new();
+    let mut offsets = Vec::with_capacity(n * d1);
+    for key in point_keys.chunks(d) {
+      let index = match table.get(key) {
+        Some(&index) => index,
+        None => {
+          let index = table.len();
+          table.insert(key.to_vec(), index);
+          vertex_keys.extend_from_slice(key);
+          index
+        }
+      };
+      offsets.push(index + 1);
+    }
+    let m = table.len();
+
+    // Neighbours of each vertex along each of the d + 1 axes
+    let mut neighbours = vec![[0usize; 2]; d1 * m];
+    neighbours
+      .par_chunks_mut(m.max(1))
+      .enumerate()
+      .for_each(|(j, axis)| {
+        let mut n1 = vec![0i32; d];
+        let mut n2 = vec![0i32; d];
+        for (i, slot) in axis.iter_mut().enumerate() {
+          let key = &vertex_keys[i * d..(i + 1) * d];
+          for k in 0..d {
+            n1[k] = key[k] - 1;
+            n2[k] = key[k] + 1;
+          }
+          if j < d {
+            n1[j] = key[j] + (d as i32);
+            n2[j] = key[j] - (d as i32);
+          }
+          *slot = [
+            table.get(&n1[..]).map_or(0, |&index| index + 1),
+            table.get(&n2[..]).map_or(0, |&index| index + 1),
+          ];
+        }
+      });
+
+    PermutohedralLattice {
+      d,
+      n,
+      m,
+      offsets,
+      weights,
+      neighbours,
+    }
+  }
+
+  /// Gaussian-filters `value_size` values per point (splat, blur, slice).
+  pub fn filter(&self, input: &[f32], value_size: usize) -> Vec<f32> {
+    let d1 = self.d + 1;
+    let vs = value_size;
+
+    // Splat
+    let mut values = vec![0f32; (self.m + 1) * vs];
+    for i in 0..self.n {
+      for j in 0..d1 {
+        let o = self.offsets[i * d1 + j];
+        let w = self.weights[i * d1 + j];
+        for k in 0..vs {
+          values[o * vs + k] += w * input[i * vs + k];
+        }
+      }
+    }
+
+    // Blur along each lattice axis; slot 0 stays empty in both buffers
+    let mut new_values = vec![0f32; (self.m + 1) * vs];
+    for j in 0..d1 {
+      let axis = &self.neighbours[j * self.m..(j + 1) * self.m];
+      let old_values = &values;
+      new_values[vs..]
+        .par_chunks_mut(vs)
+        .enumerate()
+        .for_each(|(i, new_val)| {
+          let [n1, n2] = axis[i];
+          for k in 0..vs {
+            new_val[k] =
+              old_values[(i + 1) * vs + k] +
+              0.5 * (old_values[n1 * vs + k] + old_values[n2 * vs + k]);
+          }
+        });
+      std::mem::swap(&mut values, &mut new_values);
+    }
+
+    // Slice
+    let alpha = 1.0 / (1.0 + (2.0f32).powi(-(self.d as i32)));
+    let mut output = vec![0f32; self.n * vs];
+    output
+      .par_chunks_mut(vs)
+      .enumerate()
+      .for_each(|(i, out)| {
+        for j in 0..d1 {
+          let o = self.offsets[i * d1 + j];
+          let w = self.weights[i * d1 + j] * alpha;
+          for k in 0..vs {
+            out[k] += w * values[o * vs + k];
+          }
+        }
+      });
+    output
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Gaussian filter exp(-|fi - fj|² / 2) of `values`, normalised by the filtered ones
+  fn brute_force(features: &[f32], values: &[f32], d: usize) -> Vec<f32> {
+    let n = values.len();
+    (0..n)
+      .map(|i| {
+        let (mut sum, mut norm) = (0.0, 0.0);
+        for j in 0..n {
+          let distance: f32 = (0..d)
+            .map(|k| (features[i * d + k] - features[j * d + k]).powi(2))
+            .sum();
+          let w = (-distance / 2.0).exp();
+          sum += w * values[j];
+          norm += w;
+        }
+        sum / norm
+      })
+      .collect()
+  }
+
+  fn normalised_filter(features: &[f32], values: &[f32], d: usize) -> Vec<f32> {
+    let lattice = PermutohedralLattice::new(features, d);
+    let ones = vec![1.0; values.len()];
+    let norm = lattice.filter(&ones, 1);
+    lattice
+      .filter(values, 1)
+      .iter()
+      .zip(norm.iter())
+      .map(|(v, n)| v / n)
+      .collect()
+  }
+
+  #[test]
+  fn matches_brute_force_gaussian() {
+    // 3D features: a 12x12 grid with a colour step down the middle
+    let (size, scale) = (12, 0.5);
+    let mut features = Vec::new();
+    let mut values = Vec::new();
+    for y in 0..size {
+      for x in 0..size {
+        let step = if x < size / 2 { 0.0 } else { 3.0 };
+        features.extend_from_slice(&[(x as f32) * scale, (y as f32) * scale, step]);
+        values.push(((x + 2 * y) % 5) as f32 / 4.0);
+      }
+    }
+    let expected = brute_force(&features, &values, 3);
+    let filtered = normalised_filter(&features, &values, 3);
+    let error = expected
+      .iter()
+      .zip(filtered.iter())
+      .map(|(a, b)| (a - b).abs())
+      .fold(0.0f32, f32::max);
+    assert!(error < 0.1, "max error {}", error);
+  }
+
+  #[test]
+  fn far_features_do_not_mix() {
+    // Coordinates past the i16 range overflowed the lattice keys
+    let features = [0.0, 0.0, 70000.0, 0.0, 0.0, 70000.0, 70000.0, 70000.0];
+    let values = [1.0, 0.0, 0.0, 0.0];
+    let filtered = normalised_filter(&features, &values, 2);
+    assert!((filtered[0] - 1.0).abs() < 1e-4);
+    assert!(filtered[1..].iter().all(|&v| v.abs() < 1e-4));
+  }
+}
@@ -0,0 +1,263 @@
+use image::{ GrayImage, Luma };
+use imageproc::contours::{ find_contours, BorderType };
+use imageproc::drawing::draw_line_segment_mut;
+use serde::{ Deserialize, Serialize };
+
+/// Polygon with holes, vertices [x, y] on the centres of the boundary pixels.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Polygon {
+  pub exterior: Vec<[f32; 2]>,
+  #[serde(default)]
+  pub holes: Vec<Vec<[f32; 2]>>,
+}
+
+fn distance_to_segment(p: [f32; 2], a: [f32; 2], b: [f32; 2]) -> f32 {
+  let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+  let length = dx * dx + dy * dy;
+  if length == 0.0 {
+    return (p[0] - a[0]).hypot(p[1] - a[1]);
+  }
+  let t = (((p[0] - a[0]) * dx + (p[1] - a[1]) * dy) / length).clamp(0.0, 1.0);
+  (p[0] - (a[0] + t * dx)).hypot(p[1] - (a[1] + t * dy))
+}
+
+// Douglas-Peucker on an open polyline, with an explicit stack for long contours
+fn simplify_open(points: &[[f32; 2]], tolerance: f32) -> Vec<[f32; 2]> {
+  if points.len() < 3 {
+    return points.to_vec();
+  }
+  let mut keep = vec![false; points.len()];
+  keep[0] = true;
+  keep[points.len() - 1] = true;
+  let mut stack = vec![(0, points.len() - 1)];
+  while let Some((first, last)) = stack.pop() {
+    let (index, distance) = (first + 1..last)
+      .map(|i| (i, distance_to_segment(points[i], points[first], points[last])))
+      .fold((first, 0f32), |best, candidate| if candidate.1 > best.1 { candidate } else { best });
+    if distance > tolerance {
+      keep[index] = true;
+      stack.push((first, index));
+      stack.push((index, last));
+    }
+  }
+  points
+    .iter()
+    .zip(keep.iter())
+    .filter(|(_, &keep)| keep)
+    .map(|(p, _)| *p)
+    .collect()
+}
+
+/// Douglas-Peucker on a closed ring, split at the vertex farthest from the first one
+/// so that neither half is degenerate.
+pub fn simplify_ring(ring: &[[f32; 2]], tolerance: f32) -> Vec<[f32; 2]> {
+  if tolerance <= 0.0 || ring.len() < 4 {
+    return ring.to_vec();
+  }
+  let start = ring[0];
+  let (far, _) = ring
+    .iter()
+    .enumerate()
+    .map(|(i, p)| (i, (p[0] - start[0]).hypot(p[1] - start[1])))
+    .fold((0, 0f32), |best, candidate| if candidate.1 > best.1 { candidate } else { best });
+  if far == 0 {
+    return vec![start];
+  }
+  let mut closed = ring.to_vec();
+  closed.push(start);
+  let mut first = simplify_open(&closed[..=far], tolerance);
+  let second = simplify_open(&closed[far..], tolerance);
+  first.pop();
+  first.extend_from_slice(&second[..second.len() - 1]);
+  first
+}
+
+/// Traces the outer borders and holes of a binary mask into polygons, simplified with
+/// Douglas-Peucker at `tolerance` pixels (0 keeps every boundary pixel).
+pub fn mask_to_polygons(mask: &[bool], width: usize, height: usize, tolerance: f32) -> Vec<Polygon> {
+  let image = GrayImage::from_fn(width as u32, height as u32, |x, y| {
+    Luma([if mask[(y as usize) * width + (x as usize)] { 255 } else { 0 }])
+  });
+  let contours = find_contours::<i32>(&image);
+  let ring = |i: usize| -> Vec<[f32; 2]> {
+    let points: Vec<[f32; 2]> = contours[i].points
+      .iter()
+      .map(|p| [p.x as f32, p.y as f32])
+      .collect();
+    simplify_ring(&points, tolerance)
+  };
+
+  let mut polygons = Vec::new();
+  let mut polygon_of = vec![None; contours.len()];
+  for (i, contour) in contours.iter().enumerate() {
+    if contour.border_type == BorderType::Outer {
+      polygon_of[i] = Some(polygons.len());
+      polygons.push(Polygon { exterior: ring(i), holes: Vec::new() });
+    }
+  }
+  // Holes belong to the outer border around them, objects inside holes are polygons of their own
+  for (i, contour) in contours.iter().enumerate() {
+    if contour.border_type == BorderType::Hole {
+      if let Some(index) = contour.parent.and_then(|parent| polygon_of[parent]) {
+        polygons[index].holes.push(ring(i));
+      }
+    }
+  }
+  polygons
+}
+
+// Scanline even-odd fill through the pixel centres, edges cover [ymin, ymax)
+fn fill_rings(canvas: &mut GrayImage, rings: &[&[[f32; 2]]]) {
+  let (width, height) = (canvas.width() as i64, canvas.height() as i64);
+  let edges: Vec<([f32; 2], [f32; 2])> = rings
+    .iter()
+    .filter(|ring| ring.len() >= 3)
+    .flat_map(|ring| (0..ring.len()).map(move |i| (ring[i], ring[(i + 1) % ring.len()])))
+    .filter(|(a, b)| a[1] != b[1])
+    .collect();
+  let (top, bottom) = edges
+    .iter()
+    .flat_map(|(a, b)| [a[1], b[1]])
+    .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), y| (lo.min(y), hi.max(y)));
+  if edges.is_empty() {
+    return;
+  }
+
+  let mut crossings = Vec::new();
+  for y in (top.ceil() as i64).max(0)..=(bottom.floor() as i64).min(height - 1) {
+    let yc = y as f32;
+    crossings.clear();
+    for (a, b) in &edges {
+      let (lo, hi) = if a[1] < b[1] { (a, b) } else { (b, a) };
+      if yc >= lo[1] && yc < hi[1] {
+        crossings.push(lo[0] + ((yc - lo[1]) / (hi[1] - lo[1])) * (hi[0] - lo[0]));
+      }
+    }
+    crossings.sort_by(|a, b| a.total_cmp(b));
+    for span in crossings.chunks_exact(2) {
+      let (x0, x1) = ((span[0].ceil() as i64).max(0), (span[1].floor() as i64).min(width - 1));
+      for x in x0..=x1 {
+        canvas.put_pixel(x as u32, y as u32, Luma([255]));
+      }
+    }
+  }
+}
+
+// Liang-Barsky clip of a segment to [xmin, xmax] x [ymin, ymax], None when it misses the box
+fn clip_segment(a: [f32; 2], b: [f32; 2], bounds: [f32; 4]) -> Option<([f32; 2], [f32; 2])> {
+  let [xmin, ymin, xmax, ymax] = bounds;
+  let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+  let (mut t0, mut t1) = (0.0f32, 1.0f32);
+  for (p, q) in [(-dx, a[0] - xmin), (dx, xmax - a[0]), (-dy, a[1] - ymin), (dy, ymax - a[1])] {
+    if !(p.is_finite() && q.is_finite()) {
+      return None;
+    }
+    if p == 0.0 {
+      if q < 0.0 {
+        return None;
+      }
+      continue;
+    }
+    let t = q / p;
+    if p < 0.0 {
+      t0 = t0.max(t);
+    } else {
+      t1 = t1.min(t);
+    }
+    if t0 > t1 {
+      return None;
+    }
+  }
+  Some(([a[0] + t0 * dx, a[1] + t0 * dy], [a[0] + t1 * dx, a[1] + t1 * dy]))
+}
+
+/// Rasterises polygons back into a binary mask. Interiors are filled with the even-odd rule,
+/// then every ring is drawn so the boundary pixels the vertices sit on stay in the mask.
+pub fn polygons_to_mask(polygons: &[Polygon], width: usize, height: usize) -> Vec<bool> {
+  let mut canvas = GrayImage::new(width as u32, height as u32);
+  // Segments are clipped to just around the canvas, the line drawer walks every pixel otherwise
+  let bounds = [-1.0, -1.0, width as f32, height as f32];
+  for polygon in polygons {
+    let rings: Vec<&[[f32; 2]]> = std::iter::once(&polygon.exterior)
+      .chain(polygon.holes.iter())
+      .map(|ring| ring.as_slice())
+      .collect();
+    fill_rings(&mut canvas, &rings);
+    for ring in rings {
+      for i in 0..ring.len() {
+        if let Some((a, b)) = clip_segment(ring[i], ring[(i + 1) % ring.len()], bounds) {
+          draw_line_segment_mut(&mut canvas, (a[0], a[1]), (b[0], b[1]), Luma([255]));
+        }
+      }
+    }
+  }
+  canvas
+    .pixels()
+    .map(|p| p[0] > 0)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // A square with a square hole, a disc, and a lone pixel
+  fn shapes(width: usize, height: usize) -> Vec<bool> {
+    (0..width * height)
+      .map(|i| {
+        let (x, y) = ((i % width) as f32, (i / width) as f32);
+        let square = (2.0..=20.0).contains(&x) && (2.0..=20.0).contains(&y);
+        let hole = (8.0..=13.0).contains(&x) && (8.0..=13.0).contains(&y);
+        let disc = (x - 34.0).hypot(y - 14.0) <= 9.0;
+        (square && !hole) || disc || (x == 30.0 && y == 28.0)
+      })
+      .collect()
+  }
+
+  #[test]
+  fn simplify_keeps_the_corners_of_a_square() {
+    let mut ring = Vec::new();
+    for i in 0..10 {
+      ring.push([i as f32, 0.0]);
+    }
+    for i in 0..10 {
+      ring.push([10.0, i as f32]);
+    }
+    for i in 0..10 {
+      ring.push([(10 - i) as f32, 10.0]);
+    }
+    for i in 0..10 {
+      ring.push([0.0, (10 - i) as f32]);
+    }
+    let simplified = simplify_ring(&ring, 0.5);
+    assert_eq!(simplified, vec![[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]]);
+  }
+
+  #[test]
+  fn exact_round_trip_without_tolerance() {
+    let (width, height) = (48, 32);
+    let mask = shapes(width, height);
+    let polygons = mask_to_polygons(&mask, width, height, 0.0);
+    assert_eq!(polygons.len(), 3);
+    assert_eq!(polygons.iter().map(|p| p.holes.len()).sum::<usize>(), 1);
+    assert_eq!(polygons_to_mask(&polygons, width, height), mask);
+  }
+
+  #[test]
+  fn simplified_round_trip_stays_close() {
+    let (width, height) = (48, 32);
+    let mask = shapes(width, height);
+    let polygons = mask_to_polygons(&mask, width, height, 1.0);
+    let filled = polygons_to_mask(&polygons, width, height);
+    let union = mask.iter().zip(filled.iter()).filter(|(a, b)| **a || **b).count();
+    let intersection = mask.iter().zip(filled.iter()).filter(|(a, b)| **a && **b).count();
+    let iou = (intersection as f32) / (union as f32);
+    assert!(iou > 0.95, "IoU {}", iou);
+    let vertices: usize = polygons.iter().map(|p| p.exterior.len()).sum();
+    let exact: usize = mask_to_polygons(&mask, width, height, 0.0)
+      .iter()
+      .map(|p| p.exterior.len())
+      .sum();
+    assert!(vertices < exact / 2);
+  }
+}
@@ -0,0 +1,267 @@
+use rayon::prelude::*;
+use serde::{ Deserialize, Serialize };
+
+use crate::tools::graph_cut::FlowGraph;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SeedMethod {
+  // Grady's random walker, each pixel takes the label a walker most likely reaches first.
+  RandomWalker,
+  // Potts alpha-expansion with min-cuts, the labelling with the cheapest boundaries.
+  MinCut,
+}
+
+// More than the four edge weights (at most 1) around a pixel, pins seeds to their label
+const HARD: f32 = 1e3;
+// Keeps the random walker system positive definite across strong edges
+const MIN_WEIGHT: f32 = 1e-6;
+
+/// Weights of the right and bottom edges of each pixel, exp(-beta * |ci - cj|² / mean).
+fn grid_weights(colors: &[[f32; 3]], width: usize, height: usize, beta: f32) -> Vec<[f32; 2]> {
+  let squared = |a: &[f32; 3], b: &[f32; 3]| -> f32 { (0..3).map(|c| (a[c] - b[c]).powi(2)).sum() };
+  let mut differences = vec![[f32::NAN; 2]; width * height];
+  let mut total = 0.0f64;
+  let mut count = 0usize;
+  for y in 0..height {
+    for x in 0..width {
+      let i = y * width + x;
+      if x + 1 < width {
+        differences[i][0] = squared(&colors[i], &colors[i + 1]);
+        total += differences[i][0] as f64;
+        count += 1;
+      }
+      if y + 1 < height {
+        differences[i][1] = squared(&colors[i], &colors[i + width]);
+        total += differences[i][1] as f64;
+        count += 1;
+      }
+    }
+  }
+  let mean = ((total / (count.max(1) as f64)) as f32).max(1e-6);
+  differences
+    .iter()
+    .map(|d| d.map(|d| if d.is_nan() { 0.0 } else { (-beta * d / mean).exp().max(MIN_WEIGHT) }))
+    .collect()
+}
+
+/// Neighbours of pixel `i` with the weight of the edge between them.
+fn neighbours(
+  i: usize,
+  width: usize,
+  height: usize,
+  weights: &[[f32; 2]]
+) -> impl Iterator<Item = (usize, f32)> + '_ {
+  let (x, y) = (i % width, i / width);
+  let right = (x + 1 < width).then(|| (i + 1, weights[i][0]));
+  let left = (x > 0).then(|| (i - 1, weights[i - 1][0]));
+  let down = (y + 1 < height).then(|| (i + width, weights[i][1]));
+  let up = (y > 0).then(|| (i - width, weights[i - width][1]));
+  [right, left, down, up].into_iter().flatten()
+}
+
+// Probability that a walker from each pixel first reaches a seed of `label`, solved with
+// Jacobi-preconditioned conjugate gradient on the unseeded pixels.
+fn walker_probabilities(
+  seeds: &[Option<usize>],
+  label: usize,
+  width: usize,
+  height: usize,
+  weights: &[[f32; 2]]
+) -> Vec<f32> {
+  let n = width * height;
+  let mut degree = vec![0f32; n];
+  let mut rhs = vec![0f32; n];
+  for i in 0..n {
+    for (j, w) in neighbours(i, width, height, weights) {
+      degree[i] += w;
+      if seeds[i].is_none() && seeds[j] == Some(label) {
+        rhs[i] += w;
+      }
+    }
+  }
+  let apply = |p: &[f32], out: &mut [f32]| {
+    for i in 0..n {
+      if seeds[i].is_some() {
+        out[i] = 0.0;
+        continue;
+      }
+      let mut value = degree[i] * p[i];
+      for (j, w) in neighbours(i, width, height, weights) {
+        if seeds[j].is_none() {
+          value -= w * p[j];
+        }
+      }
+      out[i] = value;
+    }
+  };
+  let dot = |a: &[f32], b: &[f32]| -> f64 {
+    a.iter().zip(b.iter()).map(|(&a, &b)| (a as f64) * (b as f64)).sum()
+  };
+
+  let mut x = vec![0f32; n];
+  let mut r = rhs.clone();
+  let mut z: Vec<f32> = r
+    .iter()
+    .zip(degree.iter())
+    .map(|(&r, &d)| r / d.max(MIN_WEIGHT))
+    .collect();
+  let mut p = z.clone();
+  let mut ap = vec![0f32; n];
+  let mut rz = dot(&r, &z);
+  let tolerance = 1e-6 * dot(&rhs, &rhs).max(1e-12);
+  for _ in 0..n.min(5000) {
+    if dot(&r, &r) <= tolerance {
+      break;
+    }
+    apply(&p, &mut ap);
+    let alpha = (rz / dot(&p, &ap).max(1e-30)) as f32;
+    for i in 0..n {
+      x[i] += alpha * p[i];
+      r[i] -= alpha * ap[i];
+      z[i] = r[i] / degree[i].max(MIN_WEIGHT);
+    }
+    let rz_next = dot(&r, &z);
+    let beta = (rz_next / rz.max(1e-30)) as f32;
+    for i in 0..n {
+      p[i] = z[i] + beta * p[i];
+    }
+    rz = rz_next;
+  }
+
+  for i in 0..n {
+    if let Some(seed) = seeds[i] {
+      x[i] = if seed == label { 1.0 } else { 0.0 };
+    }
+  }
+  x
+}
+
+fn random_walker(
+  seeds: &[Option<usize>],
+  labels: usize,
+  width: usize,
+  height: usize,
+  weights: &[[f32; 2]]
+) -> Vec<usize> {
+  let probabilities: Vec<Vec<f32>> = (0..labels)
+    .into_par_iter()
+    .map(|label| walker_probabilities(seeds, label, width, height, weights))
+    .collect();
+  (0..width * height)
+    .map(|i| {
+      (0..labels)
+        .max_by(|&a, &b| probabilities[a][i].total_cmp(&probabilities[b][i]))
+        .unwrap_or(0)
+    })
+    .collect()
+}
+
+// One expansion move: every pixel either keeps its label or switches to `alpha`
+// (source side). Pairwise Potts terms follow Kolmogorov & Zabih's construction.
+fn expand(
+  seeds: &[Option<usize>],
+  labelling: &mut [usize],
+  alpha: usize,
+  width: usize,
+  height: usize,
+  weights: &[[f32; 2]]
+) -> bool {
+  let n = width * height;
+  // Linear cost of switching (positive) or keeping (negative) for each pixel
+  let mut switch_cost = vec![0f32; n];
+  for i in 0..n {
+    if let Some(seed) = seeds[i] {
+      if seed != alpha {
+        switch_cost[i] += HARD;
+      } else if labelling[i] != alpha {
+        switch_cost[i] -= HARD;
+      }
+    }
+  }
+
+  let mut graph = FlowGraph::new(n);
+  for i in 0..n {
+    for (j, w) in neighbours(i, width, height, weights) {
+      if j < i {
+        continue;
+      }
+      let (a, b) = (labelling[i], labelling[j]);
+      if a == b {
+        // Cut exactly when only one of them switches
+        if a != alpha {
+          graph.add_edge(i, j, w, w);
+        }
+        continue;
+      }
+      let potts = |p: usize, q: usize| if p != q { w } else { 0.0 };
+      let e00 = potts(a, b);
+      let e01 = potts(a, alpha);
+      let e10 = potts(alpha, b);
+      switch_cost[i] += e10 - e00;
+      switch_cost[j] += 0.0 - e10;
+      // Paid when j switches and i keeps its label
+      let cross = e01 + e10 - e00;
+      if cross > 0.0 {
+        graph.add_edge(j, i, cross, 0.0);
+      }
+    }
+  }
+  for (i, &cost) in switch_cost.iter().enumerate() {
+    if cost > 0.0 {
+      graph.add_terminal_weights(i, 0.0, cost);
+    } else {
+      graph.add_terminal_weights(i, -cost, 0.0);
+    }
+  }
+  graph.max_flow();
+
+  let mut changed = false;
+  for (i, label) in labelling.iter_mut().enumerate() {
+    if graph.is_source_side(i) && *label != alpha {
+      *label = alpha;
+      changed = true;
+    }
+  }
+  changed
+}
+
+fn min_cut(
+  seeds: &[Option<usize>],
+  labels: usize,
+  width: usize,
+  height: usize,
+  weights: &[[f32; 2]]
+) -> Vec<usize> {
+  let mut labelling: Vec<usize> = seeds
+    .iter()
+    .map(|seed| seed.unwrap_or(0))
+    .collect();
+  for _ in 0..5 {
+    let mut changed = false;
+    for alpha in 0..labels {
+      changed |= expand(seeds, &mut labelling, alpha, width, height, weights);
+    }
+    if !changed {
+      break;
+    }
+  }
+  labelling
+}
+
+/// Labels every pixel from scribbled seeds (`seeds[i]` is the label drawn on pixel i).
+pub fn segment_from_seeds(
+  colors: &[[f32; 3]],
+  seeds: &[Option<usize>],
+  labels: usize,
+  width: usize,
+  height: usize,
+  method: SeedMethod,
+  beta: f32
+) -> Vec<usize> {
+  let weights = grid_weights(colors, width, height, beta);
+  match method {
+    SeedMethod::RandomWalker => random_walker(seeds, labels, width, height, &weights),
+    SeedMethod::MinCut => min_cut(seeds, labels, width, height, &weights),
+  }
+}
@@ -67,12 +67,7 @@ fn split_region(
   let c4 = split_region(mask, midx, midy, xmax, ymax, max_depth - 1, min_size);
 
   // 4. Collect children
-  let mut children = Vec::new();
-  for c in [c1, c2, c3, c4] {
-    if let Some(node) = c {
-      children.push(node);
-    }
-  }
+  let children: Vec<QuadNode> = [c1, c2, c3, c4].into_iter().flatten().collect();
 
   if children.is_empty() {
     None
@@ -198,7 +193,7 @@ pub fn quadtree_bounding_boxes(mask: &GrayImage, max_depth: u32, min_size: u32)
     }
   }
 
-  let root: Option<QuadNode> = split_region(&mask, xmin, ymin, xmax, ymax, max_depth, min_size);
+  let root: Option<QuadNode> = split_region(mask, xmin, ymin, xmax, ymax, max_depth, min_size);
 
   // If there's no foreground, return empty
   let root: QuadNode = match root {
@@ -214,12 +209,12 @@ pub fn quadtree_bounding_boxes(mask: &GrayImage, max_depth: u32, min_size: u32)
   let mut boxes: Vec<[u32; 4]> = Vec::new();
   collect_leaves(&merged, &mut boxes);
   println!("Found {} bounding boxes", boxes.len());
-  let boxes = reduce_bbox_to_fit_mask_content(&mask, &boxes);
+  let boxes = reduce_bbox_to_fit_mask_content(mask, &boxes);
   println!("Reduced to {} bounding boxes", boxes.len());
   boxes
 }
 
-fn reduce_bbox_to_fit_mask_content(mask: &GrayImage, boxes: &Vec<[u32; 4]>) -> Vec<[u32; 4]> {
+fn reduce_bbox_to_fit_mask_content(mask: &GrayImage, boxes: &[[u32; 4]]) -> Vec<[u32; 4]> {
   /*
   For each bounding box:
   We find the xmin/xmax; ymin/ymax of the mask content within the box.
@@ -251,13 +246,13 @@ fn reduce_bbox_to_fit_mask_content(mask: &GrayImage, boxes: &Vec<[u32; 4]>) -> V
       output.push([xmin - offsetx, ymin - offsety, xmax + offsetx, ymax + offsetx]);
     }
   }
-  if output.len() == 0 {
-    return boxes.clone();
+  if output.is_empty() {
+    return boxes.to_vec();
   };
   output
 }
 
-pub fn format_bounding_boxes(boxes: &Vec<[u32; 4]>) -> Array3<f32> {
+pub fn format_bounding_boxes(boxes: &[[u32; 4]]) -> Array3<f32> {
   // 5. Put results into Array3 of shape (N, 1, 4)
   let n = boxes.len();
   let mut arr: ndarray::ArrayBase<
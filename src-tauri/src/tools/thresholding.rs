@@ -0,0 +1,491 @@
+use ndarray::{ Array2, Zip };
+use rayon::prelude::*;
+use serde::{ Deserialize, Serialize };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdMode {
+  // One Otsu threshold over the whole mask
+  Global,
+  // Otsu thresholds of overlapping windows, interpolated between window centres
+  WindowedOtsu,
+  // Local mean + k * local standard deviation
+  Niblack,
+  // Local mean * (1 + k * (std / 128 - 1)) on the inverted image, steadier than Niblack on flat background
+  Sauvola,
+  // Global Otsu after contrast limited adaptive histogram equalisation
+  ClaheOtsu,
+}
+
+// Windows flatter than this (in grey levels of standard deviation) fall back to the global threshold
+const MIN_CONTRAST: f64 = 8.0;
+// Dynamic range of the standard deviation in Sauvola's formula
+const SAUVOLA_RANGE: f32 = 128.0;
+
+pub fn histogram(values: impl Iterator<Item = u8>) -> [u32; 256] {
+  let mut histogram = [0u32; 256];
+  for value in values {
+    histogram[value as usize] += 1;
+  }
+  histogram
+}
+
+/// Threshold maximising the between-class variance of `histogram`.
+pub fn otsu_from_histogram(histogram: &[u32; 256]) -> u8 {
+  let total_pixels = histogram.iter().map(|&h| h as f64).sum::<f64>();
+  if total_pixels == 0.0 {
+    return 0;
+  }
+
+  // Compute probabilities
+  let mut probability = [0f64; 256];
+  for i in 0..256 {
+    probability[i] = (histogram[i] as f64) / total_pixels;
+  }
+
+  // Initialize variables
+  let mut max_between_class_variance = 0.0;
+  let mut optimal_threshold = 0u8;
+
+  let mut w0 = 0.0; // Weight for background class
+  let mut sum0 = 0.0; // Cumulative sum for background class
+  let mut total_mean = 0.0;
+
+  // Compute total mean
+  for (i, &p) in probability.iter().enumerate() {
+    total_mean += (i as f64) * p;
+  }
+
+  // Iterate over possible thresholds
+  for (t, &p) in probability.iter().enumerate() {
+    w0 += p;
+    if w0 == 0.0 {
+      continue;
+    }
+
+    let w1 = 1.0 - w0;
+    if w1 == 0.0 {
+      break;
+    }
+
+    sum0 += (t as f64) * p;
+    let μ0 = sum0 / w0;
+    let μ1 = (total_mean - sum0) / w1;
+
+    // Between-class variance
+    let between_class_variance = w0 * w1 * (μ0 - μ1) * (μ0 - μ1);
+
+    // Update maximum variance and threshold
+    if between_class_variance > max_between_class_variance {
+      max_between_class_variance = between_class_variance;
+      optimal_threshold = t as u8;
+    }
+  }
+
+  optimal_threshold
+}
+
+pub fn otsu_level(pixels: &[u8]) -> u8 {
+  otsu_from_histogram(&histogram(pixels.iter().copied()))
+}
+
+/// Multi-level Otsu: the `classes - 1` ascending thresholds maximising the between-class
+/// variance, found exactly by dynamic programming over the histogram. A pixel belongs to
+/// class i when it is above i of the thresholds.
+pub fn multi_otsu(histogram: &[u32; 256], classes: usize) -> Vec<u8> {
+  let classes = classes.clamp(2, 256);
+  // Cumulative weights and intensity sums, shifted by one so ranges are cumulative[b + 1] - cumulative[a]
+  let mut weights = [0f64; 257];
+  let mut sums = [0f64; 257];
+  for v in 0..256 {
+    weights[v + 1] = weights[v] + (histogram[v] as f64);
+    sums[v + 1] = sums[v] + (v as f64) * (histogram[v] as f64);
+  }
+  // Contribution of the class covering [a, b] to the between-class variance
+  let term = |a: usize, b: usize| -> f64 {
+    let w = weights[b + 1] - weights[a];
+    if w > 0.0 { (sums[b + 1] - sums[a]).powi(2) / w } else { 0.0 }
+  };
+
+  // best[c][t]: first c + 1 classes covering [0, t], with the end of class c - 1 in previous[c][t]
+  let mut best = vec![[f64::NEG_INFINITY; 256]; classes];
+  let mut previous = vec![[0usize; 256]; classes];
+  for (t, value) in best[0].iter_mut().enumerate() {
+    *value = term(0, t);
+  }
+  for c in 1..classes {
+    for t in c..256 {
+      for s in c - 1..t {
+        let value = best[c - 1][s] + term(s + 1, t);
+        if value > best[c][t] {
+          best[c][t] = value;
+          previous[c][t] = s;
+        }
+      }
+    }
+  }
+
+  let mut thresholds = vec![0u8; classes - 1];
+  let mut t = 255;
+  for c in (1..classes).rev() {
+    t = previous[c][t];
+    thresholds[c - 1] = t as u8;
+  }
+  thresholds
+}
+
+fn standard_deviation(histogram: &[u32; 256]) -> f64 {
+  let count = histogram.iter().map(|&h| h as f64).sum::<f64>().max(1.0);
+  let mean = histogram.iter().enumerate().map(|(v, &h)| (v as f64) * (h as f64)).sum::<f64>() / count;
+  let variance = histogram
+    .iter()
+    .enumerate()
+    .map(|(v, &h)| (h as f64) * ((v as f64) - mean).powi(2))
+    .sum::<f64>() / count;
+  variance.sqrt()
+}
+
+// Neighbouring centres around `position` and the weight of the second one
+fn bracket(centres: &[f32], position: f32) -> (usize, usize, f32) {
+  let upper = centres.partition_point(|&c| c <= position);
+  if upper == 0 {
+    (0, 0, 0.0)
+  } else if upper == centres.len() {
+    (upper - 1, upper - 1, 0.0)
+  } else {
+    let (c0, c1) = (centres[upper - 1], centres[upper]);
+    (upper - 1, upper, (position - c0) / (c1 - c0))
+  }
+}
+
+// Bilinear interpolation of values defined on a grid of centres
+fn interpolate(
+  grid: &Array2<f32>,
+  row_centres: &[f32],
+  column_centres: &[f32],
+  x: usize,
+  y: usize
+) -> f32 {
+  let (y0, y1, fy) = bracket(row_centres, y as f32);
+  let (x0, x1, fx) = bracket(column_centres, x as f32);
+  let top = grid[[y0, x0]] * (1.0 - fx) + grid[[y0, x1]] * fx;
+  let bottom = grid[[y1, x0]] * (1.0 - fx) + grid[[y1, x1]] * fx;
+  top * (1.0 - fy) + bottom * fy
+}
+
+/// Otsu thresholds of the masked pixels in windows of `window` pixels overlapping by half,
+/// bilinearly interpolated to every pixel.
+fn windowed_otsu(image: &Array2<u8>, mask: &Array2<bool>, window: usize, fallback: u8) -> Array2<f32> {
+  let (height, width) = image.dim();
+  let half = (window / 2).max(1);
+  let centres = |size: usize| -> Vec<usize> {
+    let mut centres: Vec<usize> = (0..size).step_by(half).collect();
+    if centres.last() != Some(&(size - 1)) {
+      centres.push(size - 1);
+    }
+    centres
+  };
+  let (rows, columns) = (centres(height), centres(width));
+
+  let cells: Vec<(usize, usize)> = (0..rows.len())
+    .flat_map(|r| (0..columns.len()).map(move |c| (r, c)))
+    .collect();
+  let thresholds: Vec<f32> = cells
+    .par_iter()
+    .map(|&(r, c)| {
+      let (cy, cx) = (rows[r], columns[c]);
+      let (y0, y1) = (cy.saturating_sub(half), (cy + half).min(height - 1));
+      let (x0, x1) = (cx.saturating_sub(half), (cx + half).min(width - 1));
+      let values = (y0..=y1)
+        .flat_map(|y| (x0..=x1).map(move |x| (y, x)))
+        .filter(|&(y, x)| mask[[y, x]])
+        .map(|(y, x)| image[[y, x]]);
+      let local = histogram(values);
+      let count: u32 = local.iter().sum();
+      // Too few samples or no structure in the window: keep the global threshold
+      if (count as usize) < ((x1 - x0 + 1) * (y1 - y0 + 1)) / 4 || standard_deviation(&local) < MIN_CONTRAST {
+        fallback as f32
+      } else {
+        otsu_from_histogram(&local) as f32
+      }
+    })
+    .collect();
+  let grid = Array2::from_shape_vec((rows.len(), columns.len()), thresholds).unwrap();
+
+  let row_centres: Vec<f32> = rows.iter().map(|&c| c as f32).collect();
+  let column_centres: Vec<f32> = columns.iter().map(|&c| c as f32).collect();
+  Array2::from_shape_fn((height, width), |(y, x)| interpolate(&grid, &row_centres, &column_centres, x, y))
+}
+
+/// Mean and standard deviation of the masked pixels in a window around each pixel,
+/// from integral images of the values, their squares and the mask.
+fn local_statistics(image: &Array2<u8>, mask: &Array2<bool>, window: usize) -> (Array2<f32>, Array2<f32>) {
+  let (height, width) = image.dim();
+  let mut sums = Array2::<f64>::zeros((height + 1, width + 1));
+  let mut squares = Array2::<f64>::zeros((height + 1, width + 1));
+  let mut counts = Array2::<f64>::zeros((height + 1, width + 1));
+  for y in 0..height {
+    for x in 0..width {
+      let (value, inside) = if mask[[y, x]] { (image[[y, x]] as f64, 1.0) } else { (0.0, 0.0) };
+      sums[[y + 1, x + 1]] = value + sums[[y, x + 1]] + sums[[y + 1, x]] - sums[[y, x]];
+      squares[[y + 1, x + 1]] = value * value + squares[[y, x + 1]] + squares[[y + 1, x]] - squares[[y, x]];
+      counts[[y + 1, x + 1]] = inside + counts[[y, x + 1]] + counts[[y + 1, x]] - counts[[y, x]];
+    }
+  }
+
+  let half = window / 2;
+  let area = |table: &Array2<f64>, y0: usize, x0: usize, y1: usize, x1: usize| {
+    table[[y1, x1]] - table[[y0, x1]] - table[[y1, x0]] + table[[y0, x0]]
+  };
+  let mut mean = Array2::<f32>::zeros((height, width));
+  let mut std = Array2::<f32>::zeros((height, width));
+  for y in 0..height {
+    let (y0, y1) = (y.saturating_sub(half), (y + half + 1).min(height));
+    for x in 0..width {
+      let (x0, x1) = (x.saturating_sub(half), (x + half + 1).min(width));
+      let count = area(&counts, y0, x0, y1, x1).max(1.0);
+      let m = area(&sums, y0, x0, y1, x1) / count;
+      let variance = (area(&squares, y0, x0, y1, x1) / count - m * m).max(0.0);
+      mean[[y, x]] = m as f32;
+      std[[y, x]] = variance.sqrt() as f32;
+    }
+  }
+  (mean, std)
+}
+
+/// Contrast limited adaptive histogram equalisation over tiles of `tile` pixels. Histograms
+/// only count masked pixels and are clipped at `clip_limit` times the mean bin height.
+pub fn clahe(image: &Array2<u8>, mask: &Array2<bool>, tile: usize, clip_limit: f32) -> Array2<u8> {
+  let (height, width) = image.dim();
+  let tile = tile.max(8);
+  let (tiles_y, tiles_x) = (height.div_ceil(tile), width.div_ceil(tile));
+
+  let cells: Vec<(usize, usize)> = (0..tiles_y)
+    .flat_map(|r| (0..tiles_x).map(move |c| (r, c)))
+    .collect();
+  let lookups: Vec<[u8; 256]> = cells
+    .par_iter()
+    .map(|&(r, c)| {
+      let (y0, y1) = (r * tile, ((r + 1) * tile).min(height));
+      let (x0, x1) = (c * tile, ((c + 1) * tile).min(width));
+      let values = (y0..y1)
+        .flat_map(|y| (x0..x1).map(move |x| (y, x)))
+        .filter(|&(y, x)| mask[[y, x]])
+        .map(|(y, x)| image[[y, x]]);
+      let mut local = histogram(values);
+      let count: u32 = local.iter().sum();
+      let mut lookup = [0u8; 256];
+      if count == 0 {
+        for (v, l) in lookup.iter_mut().enumerate() {
+          *l = v as u8;
+        }
+        return lookup;
+      }
+
+      // Clip and spread the excess evenly over all bins
+      let limit = ((clip_limit * (count as f32)) / 256.0).max(1.0) as u32;
+      let mut excess = 0u32;
+      for h in local.iter_mut() {
+        if *h > limit {
+          excess += *h - limit;
+          *h = limit;
+        }
+      }
+      let (share, remainder) = (excess / 256, (excess % 256) as usize);
+      for (v, h) in local.iter_mut().enumerate() {
+        *h += share + if v < remainder { 1 } else { 0 };
+      }
+
+      let mut cumulative = 0u32;
+      for (v, &h) in local.iter().enumerate() {
+        cumulative += h;
+        lookup[v] = (((cumulative as f32) * 255.0) / (count as f32)).round().min(255.0) as u8;
+      }
+      lookup
+    })
+    .collect();
+
+  // Interpolate the mappings of the four nearest tiles
+  let row_centres: Vec<f32> = (0..tiles_y).map(|r| ((r * tile) as f32) + (tile as f32) / 2.0 - 0.5).collect();
+  let column_centres: Vec<f32> = (0..tiles_x).map(|c| ((c * tile) as f32) + (tile as f32) / 2.0 - 0.5).collect();
+  Array2::from_shape_fn((height, width), |(y, x)| {
+    let value = image[[y, x]] as usize;
+    let (ty0, ty1, fy) = bracket(&row_centres, y as f32);
+    let (tx0, tx1, fx) = bracket(&column_centres, x as f32);
+    let at = |ty: usize, tx: usize| lookups[ty * tiles_x + tx][value] as f32;
+    let top = at(ty0, tx0) * (1.0 - fx) + at(ty0, tx1) * fx;
+    let bottom = at(ty1, tx0) * (1.0 - fx) + at(ty1, tx1) * fx;
+    (top * (1.0 - fy) + bottom * fy).round() as u8
+  })
+}
+
+/// Foreground of `image` (bright pixels) inside `mask` with the given thresholding mode.
+/// `window` is the local window (or CLAHE tile) size in pixels and `k` the Niblack/Sauvola
+/// weight of the local standard deviation.
+pub fn threshold_in_mask(
+  image: &Array2<u8>,
+  mask: &Array2<bool>,
+  mode: ThresholdMode,
+  window: usize,
+  k: f32,
+  clip_limit: f32
+) -> Array2<bool> {
+  let masked_pixels = |image: &Array2<u8>| -> Vec<u8> {
+    image
+      .iter()
+      .zip(mask.iter())
+      .filter(|(_, &inside)| inside)
+      .map(|(&pixel, _)| pixel)
+      .collect()
+  };
+  let foreground = |image: &Array2<u8>, thresholds: &Array2<f32>| -> Array2<bool> {
+    Array2::from_shape_fn(image.dim(), |(y, x)| mask[[y, x]] && (image[[y, x]] as f32) > thresholds[[y, x]])
+  };
+
+  match mode {
+    ThresholdMode::Global => {
+      let threshold = otsu_level(&masked_pixels(image)) as f32;
+      foreground(image, &Array2::from_elem(image.dim(), threshold))
+    }
+    ThresholdMode::WindowedOtsu => {
+      let global = otsu_level(&masked_pixels(image));
+      foreground(image, &windowed_otsu(image, mask, window, global))
+    }
+    ThresholdMode::Niblack => {
+      let (mean, std) = local_statistics(image, mask, window);
+      foreground(image, &(&mean + &(std * k)))
+    }
+    ThresholdMode::Sauvola => {
+      let (mean, std) = local_statistics(image, mask, window);
+      // Sauvola expects dark foreground, so it thresholds the inverted image
+      let thresholds = Zip::from(&mean)
+        .and(&std)
+        .map_collect(|&m, &s| 255.0 - (255.0 - m) * (1.0 + k * (s / SAUVOLA_RANGE - 1.0)));
+      foreground(image, &thresholds)
+    }
+    ThresholdMode::ClaheOtsu => {
+      let equalised = clahe(image, mask, window, clip_limit);
+      let threshold = otsu_level(&masked_pixels(&equalised)) as f32;
+      foreground(&equalised, &Array2::from_elem(image.dim(), threshold))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use ndarray::s;
+
+  use super::*;
+
+  // Bright 6x6 spots on a background lit from dim (left) to bright (right)
+  fn spots_on_ramp() -> (Array2<u8>, Array2<bool>) {
+    let spot = |y: usize, x: usize| (28..34).contains(&y) && ((8..14).contains(&x) || (50..56).contains(&x));
+    let image = Array2::from_shape_fn((64, 64), |(y, x)| {
+      let background = 40 + (x * 120) / 63;
+      (if spot(y, x) { background + 60 } else { background }) as u8
+    });
+    let truth = Array2::from_shape_fn((64, 64), |(y, x)| spot(y, x));
+    (image, truth)
+  }
+
+  // Spot pixels found and background pixels taken, away from the image border
+  fn score(foreground: &Array2<bool>, truth: &Array2<bool>, border: usize) -> (f32, f32) {
+    let (mut hits, mut spots, mut false_positives, mut background) = (0, 0, 0, 0);
+    for ((y, x), &inside) in truth.indexed_iter() {
+      if y < border || x < border || y >= 64 - border || x >= 64 - border {
+        continue;
+      }
+      if inside {
+        spots += 1;
+        hits += foreground[[y, x]] as usize;
+      } else {
+        background += 1;
+        false_positives += foreground[[y, x]] as usize;
+      }
+    }
+    ((hits as f32) / (spots as f32), (false_positives as f32) / (background as f32))
+  }
+
+  #[test]
+  fn local_thresholds_follow_uneven_lighting() {
+    let (image, truth) = spots_on_ramp();
+    let mask = Array2::from_elem(image.dim(), true);
+
+    // One global threshold takes the bright side of the background and misses the dim spot
+    let global = threshold_in_mask(&image, &mask, ThresholdMode::Global, 15, 0.2, 2.0);
+    let (found, taken) = score(&global, &truth, 8);
+    assert!(found < 0.9 || taken > 0.2);
+
+    for mode in [ThresholdMode::Niblack, ThresholdMode::Sauvola] {
+      let foreground = threshold_in_mask(&image, &mask, mode, 15, 0.2, 2.0);
+      let (found, taken) = score(&foreground, &truth, 8);
+      assert!(found > 0.95, "{:?} found {} of the spots", mode, found);
+      assert!(taken < 0.05, "{:?} took {} of the background", mode, taken);
+    }
+  }
+
+  #[test]
+  fn clahe_stretches_low_contrast() {
+    let image = Array2::from_shape_fn((64, 64), |(y, x)| (100 + (x + y) % 16) as u8);
+    let mask = Array2::from_elem(image.dim(), true);
+    let equalised = clahe(&image, &mask, 16, 4.0);
+    let min = *equalised.iter().min().unwrap();
+    let max = *equalised.iter().max().unwrap();
+    assert!(max - min > 45, "range {}..{}", min, max);
+    // Equalisation keeps the order of the grey levels inside a tile
+    for y in 0..16 {
+      for x in 1..16 {
+        if image[[y, x]] > image[[y, x - 1]] {
+          assert!(equalised[[y, x]] >= equalised[[y, x - 1]]);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn clahe_ignores_pixels_outside_the_mask() {
+    // The masked out half is black, it must not flatten the mapping of the masked half
+    let image = Array2::from_shape_fn((32, 32), |(y, x)| if x < 16 { 0 } else { (100 + (x + y) % 16) as u8 });
+    let half = Array2::from_shape_fn((32, 32), |(_, x)| x >= 16);
+    let equalised = clahe(&image, &half, 32, 4.0);
+    let right = image.slice(s![.., 16..]).to_owned();
+    let stretched = clahe(&right, &Array2::from_elem(right.dim(), true), 32, 4.0);
+    assert_eq!(equalised.slice(s![.., 16..]), stretched);
+  }
+
+  // Three grey level blobs around 30, 120 and 220
+  fn three_peaks() -> [u32; 256] {
+    let mut histogram = [0u32; 256];
+    for (centre, height) in [(30i32, 400.0f32), (120, 250.0), (220, 300.0)] {
+      for offset in -12i32..=12 {
+        histogram[(centre + offset) as usize] += (height * (-(offset * offset) as f32 / 50.0).exp()) as u32;
+      }
+    }
+    histogram
+  }
+
+  #[test]
+  fn multi_otsu_separates_the_peaks() {
+    let thresholds = multi_otsu(&three_peaks(), 3);
+    assert_eq!(thresholds.len(), 2);
+    assert!((42..108).contains(&thresholds[0]), "{:?}", thresholds);
+    assert!((132..208).contains(&thresholds[1]), "{:?}", thresholds);
+  }
+
+  #[test]
+  fn multi_otsu_with_two_classes_is_otsu() {
+    let mut histogram = three_peaks();
+    histogram[220..].fill(0);
+    assert_eq!(multi_otsu(&histogram, 2), vec![otsu_from_histogram(&histogram)]);
+  }
+
+  #[test]
+  fn multi_otsu_thresholds_ascend() {
+    let histogram = histogram((0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8));
+    for classes in 2..6 {
+      let thresholds = multi_otsu(&histogram, classes);
+      assert_eq!(thresholds.len(), classes - 1);
+      assert!(thresholds.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", thresholds);
+    }
+  }
+}
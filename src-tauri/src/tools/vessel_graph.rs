@@ -0,0 +1,449 @@
+use std::collections::HashSet;
+
+use image::{ GrayImage, Luma };
+use imageproc::distance_transform::euclidean_squared_distance_transform;
+use serde::Serialize;
+use skeletonize::{ error::SkeletonizeError, foreground, thin_image_edges, MarkingMethod };
+
+// Clockwise around a pixel, so consecutive entries are neighbours of each other
+const RING: [(isize, isize); 8] = [
+  (0, -1), (1, -1), (1, 0), (1, 1),
+  (0, 1), (-1, 1), (-1, 0), (-1, -1),
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+  Endpoint,
+  // Branch or crossing point, possibly several skeleton pixels merged into one node
+  Junction,
+  // Single skeleton pixel, or the anchor of a closed loop
+  Isolated,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct GraphNode {
+  pub id: usize,
+  pub kind: NodeKind,
+  pub x: f32,
+  pub y: f32,
+  pub degree: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct GraphSegment {
+  pub id: usize,
+  pub source: usize,
+  pub target: usize,
+  // Along the skeleton, in pixels
+  pub length: f32,
+  // Mean vessel width, from the distance of the skeleton pixels to the background
+  pub mean_calibre: f32,
+  // Length over the distance between the two ends, None for loops
+  pub tortuosity: Option<f32>,
+  // Skeleton pixels [x, y] from source to target
+  pub points: Vec<[usize; 2]>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct VesselGraph {
+  pub nodes: Vec<GraphNode>,
+  pub segments: Vec<GraphSegment>,
+}
+
+/// One pixel wide skeleton of a binary mask, with the same thinning as the CRF prior.
+pub fn skeletonize(mask: &[bool], width: usize, height: usize) -> Result<Vec<bool>, SkeletonizeError> {
+  let binary = GrayImage::from_fn(width as u32, height as u32, |x, y| {
+    Luma([if mask[(y as usize) * width + (x as usize)] { 255 } else { 0 }])
+  });
+  let mut dynamic_mask = image::DynamicImage::ImageLuma8(binary);
+  thin_image_edges::<foreground::White>(&mut dynamic_mask, MarkingMethod::Modified, None)?;
+  Ok(
+    dynamic_mask
+      .to_luma8()
+      .pixels()
+      .map(|p| p[0] > 0)
+      .collect()
+  )
+}
+
+struct Skeleton<'a> {
+  pixels: &'a [bool],
+  width: usize,
+  height: usize,
+}
+
+impl Skeleton<'_> {
+  fn at(&self, x: isize, y: isize) -> bool {
+    x >= 0 &&
+      y >= 0 &&
+      (x as usize) < self.width &&
+      (y as usize) < self.height &&
+      self.pixels[(y as usize) * self.width + (x as usize)]
+  }
+
+  fn neighbours(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+    let (x, y) = ((i % self.width) as isize, (i / self.width) as isize);
+    RING.into_iter()
+      .filter(move |&(dx, dy)| self.at(x + dx, y + dy))
+      .map(move |(dx, dy)| ((y + dy) as usize) * self.width + ((x + dx) as usize))
+  }
+
+  // Number of background to skeleton transitions around the pixel
+  fn crossing_number(&self, i: usize) -> usize {
+    let (x, y) = ((i % self.width) as isize, (i / self.width) as isize);
+    (0..8)
+      .filter(|&k| {
+        let (dx, dy) = RING[k];
+        let (nx, ny) = RING[(k + 1) % 8];
+        !self.at(x + dx, y + dy) && self.at(x + nx, y + ny)
+      })
+      .count()
+  }
+}
+
+fn step_length(a: usize, b: usize, width: usize) -> f32 {
+  if a % width == b % width || a / width == b / width { 1.0 } else { std::f32::consts::SQRT_2 }
+}
+
+/// Graph of a skeleton: endpoints and junctions as nodes, the skeleton branches between them
+/// as segments. `mask` is the original vessel mask, used for the calibre.
+pub fn vessel_graph(skeleton: &[bool], mask: &[bool], width: usize, height: usize) -> VesselGraph {
+  let sk = Skeleton { pixels: skeleton, width, height };
+  let background = GrayImage::from_fn(width as u32, height as u32, |x, y| {
+    Luma([if mask[(y as usize) * width + (x as usize)] { 0 } else { 255 }])
+  });
+  let radius: Vec<f32> = euclidean_squared_distance_transform(&background)
+    .pixels()
+    .map(|d| d[0].sqrt() as f32)
+    .collect();
+
+  // Node of each skeleton pixel, junction pixels touching each other share one node
+  let mut node_of: Vec<Option<usize>> = vec![None; width * height];
+  let mut members: Vec<Vec<usize>> = Vec::new();
+  let mut kinds: Vec<NodeKind> = Vec::new();
+  for i in 0..width * height {
+    if !skeleton[i] || node_of[i].is_some() {
+      continue;
+    }
+    let neighbours = sk.neighbours(i).count();
+    let crossings = sk.crossing_number(i);
+    let kind = if neighbours == 0 {
+      NodeKind::Isolated
+    } else if neighbours == 1 || crossings == 1 {
+      NodeKind::Endpoint
+    } else if crossings >= 3 {
+      NodeKind::Junction
+    } else {
+      continue;
+    };
+    let id = members.len();
+    node_of[i] = Some(id);
+    let mut cluster = vec![i];
+    if kind == NodeKind::Junction {
+      let mut stack = vec![i];
+      while let Some(j) = stack.pop() {
+        for n in sk.neighbours(j) {
+          if node_of[n].is_none() && sk.crossing_number(n) >= 3 {
+            node_of[n] = Some(id);
+            cluster.push(n);
+            stack.push(n);
+          }
+        }
+      }
+    }
+    members.push(cluster);
+    kinds.push(kind);
+  }
+
+  let mut graph = VesselGraph::default();
+  let mut visited = vec![false; width * height];
+  let mut direct_links: HashSet<(usize, usize)> = HashSet::new();
+
+  let push_segment = |graph: &mut VesselGraph, path: Vec<usize>, source: usize, target: usize| {
+    let length: f32 = path
+      .windows(2)
+      .map(|pair| step_length(pair[0], pair[1], width))
+      .sum();
+    let mean_calibre = path
+      .iter()
+      .map(|&i| (2.0 * radius[i] - 1.0).max(1.0))
+      .sum::<f32>() / (path.len().max(1) as f32);
+    let (first, last) = (path[0], path[path.len() - 1]);
+    let chord = (
+      ((first % width) as f32) - ((last % width) as f32)
+    ).hypot(((first / width) as f32) - ((last / width) as f32));
+    let tortuosity = if source != target && chord > 0.0 { Some(length / chord) } else { None };
+    graph.segments.push(GraphSegment {
+      id: graph.segments.len(),
+      source,
+      target,
+      length,
+      mean_calibre,
+      tortuosity,
+      points: path
+        .iter()
+        .map(|&i| [i % width, i / width])
+        .collect(),
+    });
+  };
+
+  // Walks from a node pixel through branch pixels until another (or the same) node
+  let trace = |start: usize, first: usize, visited: &mut [bool]| -> (Vec<usize>, Option<usize>) {
+    let mut path = vec![start, first];
+    visited[first] = true;
+    let (mut previous, mut current) = (start, first);
+    loop {
+      let candidates: Vec<usize> = sk
+        .neighbours(current)
+        .filter(|&n| n != previous && (node_of[n].is_some() || !visited[n]))
+        .collect();
+      // A node pixel ends the branch, unless it belongs to the node we just left
+      let ends = |n: usize| node_of[n].is_some() && !(path.len() == 2 && node_of[n] == node_of[start]);
+      if let Some(&n) = candidates.iter().find(|&&n| ends(n)) {
+        path.push(n);
+        return (path, node_of[n]);
+      }
+      // Prefer 4-connected steps so staircase corners are not skipped
+      let next = candidates
+        .iter()
+        .copied()
+        .filter(|&n| node_of[n].is_none())
+        .min_by(|&a, &b| step_length(current, a, width).total_cmp(&step_length(current, b, width)));
+      match next {
+        Some(n) => {
+          visited[n] = true;
+          path.push(n);
+          previous = current;
+          current = n;
+        }
+        None => {
+          return (path, None);
+        }
+      }
+    }
+  };
+
+  for (id, cluster) in members.iter().enumerate() {
+    for &pixel in cluster {
+      for n in sk.neighbours(pixel) {
+        match node_of[n] {
+          Some(other) if other == id => {}
+          Some(other) => {
+            let pair = (id.min(other), id.max(other));
+            if direct_links.insert(pair) {
+              push_segment(&mut graph, vec![pixel, n], id, other);
+            }
+          }
+          None if !visited[n] => {
+            let (path, end) = trace(pixel, n, &mut visited);
+            // Branches that fade out without reaching a node end on their last pixel
+            let target = end.unwrap_or_else(|| {
+              let last = *path.last().unwrap();
+              let new_id = members.len() + graph.nodes.len();
+              graph.nodes.push(GraphNode {
+                id: new_id,
+                kind: NodeKind::Endpoint,
+                x: (last % width) as f32,
+                y: (last / width) as f32,
+                degree: 0,
+              });
+              new_id
+            });
+            push_segment(&mut graph, path, id, target);
+          }
+          None => {}
+        }
+      }
+    }
+  }
+
+  // Closed loops have no node, anchor each one on its first pixel
+  let mut loop_nodes = Vec::new();
+  for i in 0..width * height {
+    if !skeleton[i] || visited[i] || node_of[i].is_some() {
+      continue;
+    }
+    let id = members.len() + graph.nodes.len() + loop_nodes.len();
+    visited[i] = true;
+    loop_nodes.push(i);
+    if let Some(first) = sk.neighbours(i).find(|&n| !visited[n]) {
+      let (mut path, _) = trace(i, first, &mut visited);
+      path.push(i);
+      push_segment(&mut graph, path, id, id);
+    }
+  }
+
+  // Nodes in id order: clustered nodes, nodes at faded branch ends, loop anchors
+  let extra = std::mem::take(&mut graph.nodes);
+  let first_loop = members.len() + extra.len();
+  graph.nodes = members
+    .iter()
+    .zip(kinds.iter())
+    .enumerate()
+    .map(|(id, (cluster, &kind))| {
+      let n = cluster.len() as f32;
+      GraphNode {
+        id,
+        kind,
+        x: cluster.iter().map(|&i| (i % width) as f32).sum::<f32>() / n,
+        y: cluster.iter().map(|&i| (i / width) as f32).sum::<f32>() / n,
+        degree: 0,
+      }
+    })
+    .chain(extra)
+    .chain(
+      loop_nodes.iter().enumerate().map(|(k, &i)| GraphNode {
+        id: first_loop + k,
+        kind: NodeKind::Isolated,
+        x: (i % width) as f32,
+        y: (i / width) as f32,
+        degree: 0,
+      })
+    )
+    .collect();
+  for segment in &graph.segments {
+    graph.nodes[segment.source].degree += 1;
+    graph.nodes[segment.target].degree += 1;
+  }
+  graph
+}
+
+impl VesselGraph {
+  /// GraphML document with node kind and position, and segment length, calibre and tortuosity.
+  pub fn to_graphml(&self) -> String {
+    let mut out = String::from(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+       <graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n\
+       \x20 <key id=\"kind\" for=\"node\" attr.name=\"kind\" attr.type=\"string\"/>\n\
+       \x20 <key id=\"x\" for=\"node\" attr.name=\"x\" attr.type=\"float\"/>\n\
+       \x20 <key id=\"y\" for=\"node\" attr.name=\"y\" attr.type=\"float\"/>\n\
+       \x20 <key id=\"length\" for=\"edge\" attr.name=\"length\" attr.type=\"float\"/>\n\
+       \x20 <key id=\"mean_calibre\" for=\"edge\" attr.name=\"mean_calibre\" attr.type=\"float\"/>\n\
+       \x20 <key id=\"tortuosity\" for=\"edge\" attr.name=\"tortuosity\" attr.type=\"float\"/>\n\
+       \x20 <graph id=\"vessels\" edgedefault=\"undirected\">\n"
+    );
+    for node in &self.nodes {
+      let kind = match node.kind {
+        NodeKind::Endpoint => "endpoint",
+        NodeKind::Junction => "junction",
+        NodeKind::Isolated => "isolated",
+      };
+      out.push_str(
+        &format!(
+          "    <node id=\"n{}\"><data key=\"kind\">{}</data><data key=\"x\">{}</data><data key=\"y\">{}</data></node>\n",
+          node.id,
+          kind,
+          node.x,
+          node.y
+        )
+      );
+    }
+    for segment in &self.segments {
+      let tortuosity = segment.tortuosity
+        .map(|t| format!("<data key=\"tortuosity\">{}</data>", t))
+        .unwrap_or_default();
+      out.push_str(
+        &format!(
+          "    <edge id=\"e{}\" source=\"n{}\" target=\"n{}\"><data key=\"length\">{}</data><data key=\"mean_calibre\">{}</data>{}</edge>\n",
+          segment.id,
+          segment.source,
+          segment.target,
+          segment.length,
+          segment.mean_calibre,
+          tortuosity
+        )
+      );
+    }
+    out.push_str("  </graph>\n</graphml>\n");
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const WIDTH: usize = 40;
+  const HEIGHT: usize = 44;
+
+  // Pixels within `radius` of a Y: a stem from (20, 38) up to (20, 20), arms to (8, 8) and (32, 8)
+  fn y_shape(radius: f32) -> Vec<bool> {
+    let segments = [
+      ([20.0, 38.0], [20.0, 20.0]),
+      ([20.0, 20.0], [8.0, 8.0]),
+      ([20.0, 20.0], [32.0, 8.0]),
+    ];
+    (0..WIDTH * HEIGHT)
+      .map(|i| {
+        let (x, y) = ((i % WIDTH) as f32, (i / WIDTH) as f32);
+        segments.iter().any(|&([ax, ay], [bx, by])| {
+          let (dx, dy) = (bx - ax, by - ay);
+          let t = (((x - ax) * dx + (y - ay) * dy) / (dx * dx + dy * dy)).clamp(0.0, 1.0);
+          (x - (ax + t * dx)).hypot(y - (ay + t * dy)) <= radius
+        })
+      })
+      .collect()
+  }
+
+  fn kinds(graph: &VesselGraph, kind: NodeKind) -> Vec<&GraphNode> {
+    graph.nodes
+      .iter()
+      .filter(|node| node.kind == kind)
+      .collect()
+  }
+
+  #[test]
+  fn graph_of_a_y() {
+    let mask = y_shape(2.5);
+    let skeleton = skeletonize(&mask, WIDTH, HEIGHT).unwrap();
+    let graph = vessel_graph(&skeleton, &mask, WIDTH, HEIGHT);
+
+    let junctions = kinds(&graph, NodeKind::Junction);
+    assert_eq!(junctions.len(), 1, "{:?}", graph.nodes);
+    assert_eq!(kinds(&graph, NodeKind::Endpoint).len(), 3, "{:?}", graph.nodes);
+    assert_eq!(graph.segments.len(), 3);
+    let junction = junctions[0];
+    assert_eq!(junction.degree, 3);
+    assert!((junction.x - 20.0).abs() <= 2.0 && (junction.y - 20.0).abs() <= 3.0);
+
+    for segment in &graph.segments {
+      assert!(segment.source == junction.id || segment.target == junction.id);
+      // Branches of a straight-limbed Y, thinned back from the rounded ends
+      assert!(segment.length > 12.0 && segment.length < 21.0, "{:?}", segment.length);
+      assert!(segment.tortuosity.is_some_and(|t| t < 1.2));
+      assert!((segment.mean_calibre - 5.0).abs() < 1.5, "{}", segment.mean_calibre);
+    }
+  }
+
+  #[test]
+  fn a_loop_is_anchored_on_one_node() {
+    let ring: Vec<bool> = (0..WIDTH * HEIGHT)
+      .map(|i| {
+        let (x, y) = ((i % WIDTH) as f32, (i / WIDTH) as f32);
+        let r = (x - 20.0).hypot(y - 22.0);
+        (9.0..=12.0).contains(&r)
+      })
+      .collect();
+    let skeleton = skeletonize(&ring, WIDTH, HEIGHT).unwrap();
+    let graph = vessel_graph(&skeleton, &ring, WIDTH, HEIGHT);
+    assert_eq!(graph.nodes.len(), 1);
+    assert_eq!(graph.segments.len(), 1);
+    let segment = &graph.segments[0];
+    assert_eq!(segment.source, segment.target);
+    assert!(segment.tortuosity.is_none());
+    // Circumference of the centre line, radius 10.5
+    assert!((segment.length - 66.0).abs() < 8.0, "{}", segment.length);
+  }
+
+  #[test]
+  fn graphml_lists_nodes_and_edges() {
+    let mask = y_shape(2.5);
+    let skeleton = skeletonize(&mask, WIDTH, HEIGHT).unwrap();
+    let graph = vessel_graph(&skeleton, &mask, WIDTH, HEIGHT);
+    let graphml = graph.to_graphml();
+    assert_eq!(graphml.matches("<node ").count(), graph.nodes.len());
+    assert_eq!(graphml.matches("<edge ").count(), graph.segments.len());
+    assert!(graphml.trim_end().ends_with("</graphml>"));
+  }
+}
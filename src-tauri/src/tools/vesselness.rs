@@ -0,0 +1,138 @@
+use rayon::prelude::*;
+use serde::{ Deserialize, Serialize };
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VesselFilter {
+  // Frangi et al. 1998: tube-likeness from the ratio and norm of the Hessian eigenvalues
+  Frangi,
+  // Sato et al. 1998: magnitude of the cross-section curvature
+  Sato,
+}
+
+// Frangi's sensitivity to blob-like structures
+const FRANGI_BETA: f32 = 0.5;
+
+// Gaussian and its first and second derivatives, sampled over +-3 sigma
+fn gaussian_kernels(sigma: f32) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+  let radius = (3.0 * sigma).ceil() as isize;
+  let s2 = sigma * sigma;
+  let g: Vec<f32> = (-radius..=radius).map(|x| (-((x * x) as f32) / (2.0 * s2)).exp()).collect();
+  let total: f32 = g.iter().sum();
+  let g: Vec<f32> = g.iter().map(|v| v / total).collect();
+  let first = (-radius..=radius)
+    .zip(g.iter())
+    .map(|(x, v)| (-(x as f32) / s2) * v)
+    .collect();
+  let second = (-radius..=radius)
+    .zip(g.iter())
+    .map(|(x, v)| (((x * x) as f32) / (s2 * s2) - 1.0 / s2) * v)
+    .collect();
+  (g, first, second)
+}
+
+// Separable convolution, `horizontal` along rows then `vertical` along columns, clamped borders
+fn convolve(values: &[f32], width: usize, height: usize, horizontal: &[f32], vertical: &[f32]) -> Vec<f32> {
+  let rh = (horizontal.len() / 2) as isize;
+  let rv = (vertical.len() / 2) as isize;
+  let mut rows = vec![0f32; values.len()];
+  rows
+    .par_chunks_mut(width)
+    .enumerate()
+    .for_each(|(y, row)| {
+      for (x, out) in row.iter_mut().enumerate() {
+        *out = horizontal
+          .iter()
+          .enumerate()
+          .map(|(k, w)| {
+            let xx = ((x as isize) + (k as isize) - rh).clamp(0, (width as isize) - 1) as usize;
+            w * values[y * width + xx]
+          })
+          .sum();
+      }
+    });
+  let mut out = vec![0f32; values.len()];
+  out
+    .par_chunks_mut(width)
+    .enumerate()
+    .for_each(|(y, row)| {
+      for (x, out) in row.iter_mut().enumerate() {
+        *out = vertical
+          .iter()
+          .enumerate()
+          .map(|(k, w)| {
+            let yy = ((y as isize) + (k as isize) - rv).clamp(0, (height as isize) - 1) as usize;
+            w * rows[yy * width + x]
+          })
+          .sum();
+      }
+    });
+  out
+}
+
+/// Scale-normalised Hessian eigenvalues (λ1, λ2) at `sigma`, with |λ1| <= |λ2|.
+fn hessian_eigenvalues(values: &[f32], width: usize, height: usize, sigma: f32) -> Vec<(f32, f32)> {
+  let (g, first, second) = gaussian_kernels(sigma);
+  let dxx = convolve(values, width, height, &second, &g);
+  let dyy = convolve(values, width, height, &g, &second);
+  let dxy = convolve(values, width, height, &first, &first);
+  let s2 = sigma * sigma;
+  (0..values.len())
+    .into_par_iter()
+    .map(|i| {
+      let (a, b, c) = (dxx[i] * s2, dxy[i] * s2, dyy[i] * s2);
+      let root = ((a - c).powi(2) + 4.0 * b * b).sqrt();
+      let (mu1, mu2) = ((a + c + root) / 2.0, (a + c - root) / 2.0);
+      if mu1.abs() <= mu2.abs() { (mu1, mu2) } else { (mu2, mu1) }
+    })
+    .collect()
+}
+
+/// Multi-scale vesselness of a grey image, the maximum response over `sigmas`.
+/// `dark_vessels` looks for ridges darker than their surroundings, as in fundus images.
+pub fn vesselness(
+  values: &[f32],
+  width: usize,
+  height: usize,
+  sigmas: &[f32],
+  filter: VesselFilter,
+  dark_vessels: bool
+) -> Vec<f32> {
+  let mut response = vec![0f32; values.len()];
+  for &sigma in sigmas {
+    let eigenvalues = hessian_eigenvalues(values, width, height, sigma.max(0.5));
+    // Dark tubes curve upwards across their axis, bright ones downwards
+    let across = |l2: f32| if dark_vessels { l2 } else { -l2 };
+    let scale: Vec<f32> = match filter {
+      VesselFilter::Frangi => {
+        // Structureness half-way point, half the largest Hessian norm at this scale
+        let c = eigenvalues
+          .iter()
+          .map(|&(l1, l2)| (l1 * l1 + l2 * l2).sqrt())
+          .fold(0f32, f32::max) / 2.0;
+        let c = c.max(1e-6);
+        eigenvalues
+          .par_iter()
+          .map(|&(l1, l2)| {
+            if across(l2) <= 0.0 {
+              return 0.0;
+            }
+            let blobness = l1 / l2;
+            let structureness = l1 * l1 + l2 * l2;
+            (-(blobness * blobness) / (2.0 * FRANGI_BETA * FRANGI_BETA)).exp() *
+              (1.0 - (-structureness / (2.0 * c * c)).exp())
+          })
+          .collect()
+      }
+      VesselFilter::Sato =>
+        eigenvalues
+          .par_iter()
+          .map(|&(_, l2)| across(l2).max(0.0))
+          .collect(),
+    };
+    for (r, s) in response.iter_mut().zip(scale.iter()) {
+      *r = r.max(*s);
+    }
+  }
+  response
+}
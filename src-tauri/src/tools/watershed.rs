@@ -0,0 +1,190 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use image::{ GrayImage, Luma };
+use imageproc::distance_transform::euclidean_squared_distance_transform;
+use imageproc::region_labelling::{ connected_components, Connectivity };
+
+const OFFSETS: [(isize, isize); 8] = [
+  (-1, -1), (0, -1), (1, -1),
+  (-1, 0), (1, 0),
+  (-1, 1), (0, 1), (1, 1),
+];
+
+fn neighbours(i: usize, width: usize, height: usize) -> impl Iterator<Item = usize> {
+  let (x, y) = ((i % width) as isize, (i / width) as isize);
+  OFFSETS.into_iter().filter_map(move |(dx, dy)| {
+    let (nx, ny) = (x + dx, y + dy);
+    if nx >= 0 && (nx as usize) < width && ny >= 0 && (ny as usize) < height {
+      Some((ny as usize) * width + (nx as usize))
+    } else {
+      None
+    }
+  })
+}
+
+/// Euclidean distance of every mask pixel to the background.
+pub fn distance_map(mask: &[bool], width: usize, height: usize) -> Vec<f32> {
+  let background = GrayImage::from_fn(width as u32, height as u32, |x, y| {
+    Luma([if mask[(y as usize) * width + (x as usize)] { 0 } else { 255 }])
+  });
+  euclidean_squared_distance_transform(&background)
+    .pixels()
+    .map(|d| d[0].sqrt() as f32)
+    .collect()
+}
+
+// Max over a (2r+1)² window, as a row pass then a column pass.
+fn max_filter(values: &[f32], width: usize, height: usize, radius: usize) -> Vec<f32> {
+  let mut rows = vec![0f32; values.len()];
+  for y in 0..height {
+    for x in 0..width {
+      let (x0, x1) = (x.saturating_sub(radius), (x + radius).min(width - 1));
+      rows[y * width + x] = values[y * width + x0..=y * width + x1]
+        .iter()
+        .copied()
+        .fold(f32::NEG_INFINITY, f32::max);
+    }
+  }
+  let mut out = vec![0f32; values.len()];
+  for y in 0..height {
+    let (y0, y1) = (y.saturating_sub(radius), (y + radius).min(height - 1));
+    for x in 0..width {
+      out[y * width + x] = (y0..=y1)
+        .map(|yy| rows[yy * width + x])
+        .fold(f32::NEG_INFINITY, f32::max);
+    }
+  }
+  out
+}
+
+/// One marker per plateau of local maxima of the distance map, at least `min_distance`
+/// pixels apart. Returns the marker label of each pixel (0 for none) and the marker count.
+pub fn distance_peaks(
+  distance: &[f32],
+  width: usize,
+  height: usize,
+  min_distance: usize
+) -> (Vec<u32>, u32) {
+  let maxima = max_filter(distance, width, height, min_distance.max(1));
+  let peaks = GrayImage::from_fn(width as u32, height as u32, |x, y| {
+    let i = (y as usize) * width + (x as usize);
+    Luma([if distance[i] > 0.0 && distance[i] >= maxima[i] { 255 } else { 0 }])
+  });
+  let components = connected_components(&peaks, Connectivity::Eight, Luma([0]));
+  let markers: Vec<u32> = components
+    .pixels()
+    .map(|p| p[0])
+    .collect();
+  let count = markers.iter().copied().max().unwrap_or(0);
+  (markers, count)
+}
+
+/// Marker-controlled watershed on the inverted distance map, flooding from the markers
+/// towards the mask border. Mask components without a marker become instances of their own.
+/// Returns the instance label of each pixel (0 for background).
+pub fn watershed(
+  mask: &[bool],
+  distance: &[f32],
+  markers: &[u32],
+  marker_count: u32,
+  width: usize,
+  height: usize
+) -> Vec<u32> {
+  let mut labels = vec![0u32; width * height];
+  let mut queued = vec![false; width * height];
+  // Deepest pixels first, then first come first served
+  let mut heap = BinaryHeap::new();
+  let mut counter = 0u64;
+  for i in 0..width * height {
+    if mask[i] && markers[i] > 0 {
+      labels[i] = markers[i];
+      queued[i] = true;
+      heap.push(((distance[i] * 256.0) as u32, Reverse(counter), i));
+      counter += 1;
+    }
+  }
+
+  while let Some((_, _, i)) = heap.pop() {
+    for j in neighbours(i, width, height) {
+      if mask[j] && !queued[j] {
+        queued[j] = true;
+        labels[j] = labels[i];
+        heap.push(((distance[j] * 256.0) as u32, Reverse(counter), j));
+        counter += 1;
+      }
+    }
+  }
+
+  // Pieces no marker reached
+  let mut next = marker_count;
+  for start in 0..width * height {
+    if !mask[start] || labels[start] != 0 {
+      continue;
+    }
+    next += 1;
+    labels[start] = next;
+    let mut stack = vec![start];
+    while let Some(i) = stack.pop() {
+      for j in neighbours(i, width, height) {
+        if mask[j] && labels[j] == 0 {
+          labels[j] = next;
+          stack.push(j);
+        }
+      }
+    }
+  }
+  labels
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const WIDTH: usize = 48;
+  const HEIGHT: usize = 32;
+
+  fn discs(centres: &[(f32, f32)], radius: f32) -> Vec<bool> {
+    (0..WIDTH * HEIGHT)
+      .map(|i| {
+        let (x, y) = ((i % WIDTH) as f32, (i / WIDTH) as f32);
+        centres.iter().any(|&(cx, cy)| (x - cx).hypot(y - cy) <= radius)
+      })
+      .collect()
+  }
+
+  #[test]
+  fn splits_two_touching_discs() {
+    let mask = discs(&[(14.0, 16.0), (32.0, 16.0)], 10.0);
+    let distance = distance_map(&mask, WIDTH, HEIGHT);
+    let (markers, count) = distance_peaks(&distance, WIDTH, HEIGHT, 7);
+    assert_eq!(count, 2);
+
+    let labels = watershed(&mask, &distance, &markers, count, WIDTH, HEIGHT);
+    let (left, right) = (labels[16 * WIDTH + 14], labels[16 * WIDTH + 32]);
+    assert!(left > 0 && right > 0 && left != right);
+    for (i, &label) in labels.iter().enumerate() {
+      assert_eq!(label == 0, !mask[i]);
+      // The cut runs along the neck between the centres
+      let x = i % WIDTH;
+      if mask[i] && x < 21 {
+        assert_eq!(label, left);
+      } else if mask[i] && x > 25 {
+        assert_eq!(label, right);
+      }
+    }
+    let sizes = [left, right].map(|l| labels.iter().filter(|&&label| label == l).count());
+    assert!(sizes[0].abs_diff(sizes[1]) < sizes[0] / 10);
+  }
+
+  #[test]
+  fn unreached_components_get_their_own_label() {
+    let mask = discs(&[(10.0, 16.0), (36.0, 16.0)], 6.0);
+    let distance = distance_map(&mask, WIDTH, HEIGHT);
+    let mut markers = vec![0u32; WIDTH * HEIGHT];
+    markers[16 * WIDTH + 10] = 1;
+    let labels = watershed(&mask, &distance, &markers, 1, WIDTH, HEIGHT);
+    assert_eq!(labels[16 * WIDTH + 10], 1);
+    assert_eq!(labels[16 * WIDTH + 36], 2);
+  }
+}